@@ -0,0 +1,102 @@
+// Compares the geometric sampling of bit errors with drawing every bit of a frame, first
+// checking that both flip as many bits on average, then timing them at a few error rates
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use std::hint::black_box;
+use stopandwait::packets::{
+    Packet,
+    frame::Frame,
+    sequence::{SequenceNumber, SequenceNumberWidth},
+};
+
+const PAYLOAD_LENGTH_IN_BYTES: usize = 5000;
+const BIT_ERROR_PROBABILITIES: [f64; 4] = [1e-9, 1e-6, 1e-4, 1e-2];
+
+/// How errors were injected before: one draw per bit.
+fn simulate_errors_bit_by_bit<R: Rng>(
+    frame: &Frame,
+    bit_error_probability: f64,
+    rng: &mut R,
+) -> Frame {
+    let bit_errors: Vec<usize> = (0..frame.length_in_bits())
+        .filter(|_| rng.random_bool(bit_error_probability))
+        .collect();
+    frame.with_flipped_bits(&bit_errors)
+}
+
+fn flipped_bits(transmitted: &Frame, received: &Frame) -> u32 {
+    transmitted
+        .content
+        .iter()
+        .zip(&received.content)
+        .map(|(transmitted_byte, received_byte)| (transmitted_byte ^ received_byte).count_ones())
+        .sum()
+}
+
+fn assert_equal_statistics(frame: &Frame, rng: &mut StdRng) {
+    let (bit_error_probability, trials) = (1e-3, 500);
+    let expected_mean = frame.length_in_bits() as f64 * bit_error_probability;
+    let mean_of = |errors: u32| errors as f64 / trials as f64;
+    let sampled = (0..trials)
+        .map(|_| {
+            flipped_bits(
+                frame,
+                &frame.simulate_errors_with_probability(bit_error_probability, rng),
+            )
+        })
+        .sum();
+    let bit_by_bit = (0..trials)
+        .map(|_| {
+            flipped_bits(
+                frame,
+                &simulate_errors_bit_by_bit(frame, bit_error_probability, rng),
+            )
+        })
+        .sum();
+    // Both means are within 5 standard deviations of 40 flipped bits
+    for mean in [mean_of(sampled), mean_of(bit_by_bit)] {
+        assert!(
+            (mean - expected_mean).abs() < 5.0 * (expected_mean / trials as f64).sqrt(),
+            "{} flipped bits on average instead of {}",
+            mean,
+            expected_mean
+        );
+    }
+}
+
+fn error_injection(criterion: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let frame = Frame::new(
+        &[0x55; PAYLOAD_LENGTH_IN_BYTES],
+        SequenceNumber::zero(SequenceNumberWidth::Bits32),
+    );
+    assert_equal_statistics(&frame, &mut rng);
+
+    let mut group = criterion.benchmark_group("error_injection");
+    for bit_error_probability in BIT_ERROR_PROBABILITIES {
+        group.bench_with_input(
+            BenchmarkId::new("geometric", bit_error_probability),
+            &bit_error_probability,
+            |bencher, &bit_error_probability| {
+                bencher.iter(|| {
+                    black_box(&frame)
+                        .simulate_errors_with_probability(bit_error_probability, &mut rng)
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("bit_by_bit", bit_error_probability),
+            &bit_error_probability,
+            |bencher, &bit_error_probability| {
+                bencher.iter(|| {
+                    simulate_errors_bit_by_bit(black_box(&frame), bit_error_probability, &mut rng)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, error_injection);
+criterion_main!(benches);
@@ -0,0 +1,182 @@
+// Times the hot paths of the protocol stack: building and checking frames, encoding and
+// decoding messages and acknowledgements, and whole transfers driven in lockstep over a noisy
+// line. Error injection on its own is timed by the error_injection benchmark.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rand::{SeedableRng, rngs::StdRng};
+use std::hint::black_box;
+use stopandwait::{
+    endpoint::{ReceiverStateMachine, TransmitterStateMachine},
+    message::{open_frame, prepare_message, reassemble_message},
+    packets::{
+        Packet,
+        acknowledgement::{GenericAcknowledgement, ack::ACK},
+        frame::Frame,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+    session::SessionParameters,
+};
+
+const PAYLOAD_LENGTHS_IN_BYTES: [usize; 3] = [64, 1024, 5000];
+const MESSAGE_LENGTHS_IN_BYTES: [usize; 3] = [4 * 1024, 64 * 1024, 1024 * 1024];
+const FULL_PAYLOAD_LENGTH_IN_BYTES: usize = 1024;
+const BIT_ERROR_PROBABILITIES: [f64; 2] = [0.0, 1e-5];
+const SEQUENCE_NUMBER_WIDTHS: [SequenceNumberWidth; 2] = [
+    SequenceNumberWidth::AlternatingBit,
+    SequenceNumberWidth::Bits32,
+];
+
+fn message(length_in_bytes: usize) -> Vec<u8> {
+    (0..length_in_bytes).map(|i| (i % 251) as u8).collect()
+}
+
+/// Sends `message` frame by frame, each one answered before the next attempt, and returns what
+/// the receiver reassembled.
+fn transfer_in_lockstep(
+    message: &[u8],
+    session_parameters: &SessionParameters,
+    bit_error_probability: f64,
+    rng: &mut StdRng,
+) -> Vec<u8> {
+    let frames = prepare_message(
+        message,
+        FULL_PAYLOAD_LENGTH_IN_BYTES,
+        session_parameters,
+        None,
+    )
+    .unwrap();
+    let mut transmitter = TransmitterStateMachine::new(frames);
+    let mut receiver = ReceiverStateMachine::new(None, session_parameters.sequence_number_width);
+    while let Some(frame) = transmitter.current_frame() {
+        let received_frame = frame.simulate_errors_with_probability(bit_error_probability, rng);
+        let (acknowledgement, _) = receiver
+            .handle_frame(&received_frame)
+            .expect("Seeded errors never slip through the session header checksum");
+        transmitter.handle_acknowledgement(
+            &acknowledgement.simulate_errors_with_probability(bit_error_probability, rng),
+        );
+    }
+    receiver.into_message().unwrap()
+}
+
+fn frames(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("frame");
+    for payload_length_in_bytes in PAYLOAD_LENGTHS_IN_BYTES {
+        let payload = message(payload_length_in_bytes);
+        group.throughput(Throughput::Bytes(payload_length_in_bytes as u64));
+        for sequence_number_width in SEQUENCE_NUMBER_WIDTHS {
+            let sequence_number = SequenceNumber::zero(sequence_number_width);
+            let frame = Frame::new(&payload, sequence_number);
+            let parameter = format!("{:?}/{}", sequence_number_width, payload_length_in_bytes);
+            group.bench_function(BenchmarkId::new("new", &parameter), |bencher| {
+                bencher.iter(|| Frame::new(black_box(&payload), sequence_number))
+            });
+            group.bench_function(BenchmarkId::new("is_valid", &parameter), |bencher| {
+                bencher.iter(|| black_box(&frame).is_valid())
+            });
+            group.bench_function(BenchmarkId::new("open", &parameter), |bencher| {
+                bencher.iter(|| open_frame(black_box(&frame), 0, None).unwrap())
+            });
+        }
+    }
+    group.finish();
+}
+
+fn encoding(criterion: &mut Criterion) {
+    let session_parameters = SessionParameters::default();
+    let mut group = criterion.benchmark_group("encoding");
+    for message_length_in_bytes in MESSAGE_LENGTHS_IN_BYTES {
+        let message = message(message_length_in_bytes);
+        let frames = prepare_message(
+            &message,
+            FULL_PAYLOAD_LENGTH_IN_BYTES,
+            &session_parameters,
+            None,
+        )
+        .unwrap();
+        let payloads: Vec<_> = frames
+            .iter()
+            .enumerate()
+            .map(|(frame_index, frame)| open_frame(frame, frame_index as u64, None).unwrap())
+            .collect();
+        group.throughput(Throughput::Bytes(message_length_in_bytes as u64));
+        group.bench_with_input(
+            BenchmarkId::new("prepare_message", message_length_in_bytes),
+            &message,
+            |bencher, message| {
+                bencher.iter(|| {
+                    prepare_message(
+                        message,
+                        FULL_PAYLOAD_LENGTH_IN_BYTES,
+                        &session_parameters,
+                        None,
+                    )
+                    .unwrap()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("reassemble_message", message_length_in_bytes),
+            &payloads,
+            |bencher, payloads| bencher.iter(|| reassemble_message(payloads).unwrap()),
+        );
+    }
+
+    group.throughput(Throughput::Elements(1));
+    for sequence_number_width in SEQUENCE_NUMBER_WIDTHS {
+        let acknowledgement =
+            GenericAcknowledgement::ACK(ACK::new(SequenceNumber::new(1, sequence_number_width)));
+        group.bench_function(
+            BenchmarkId::new("acknowledgement", format!("{:?}", sequence_number_width)),
+            |bencher| {
+                bencher.iter(|| {
+                    let bytes = black_box(&acknowledgement).to_bytes();
+                    GenericAcknowledgement::from_bytes(&bytes, sequence_number_width).unwrap()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn transfers(criterion: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut group = criterion.benchmark_group("transfer");
+    group.sample_size(10);
+    for message_length_in_bytes in MESSAGE_LENGTHS_IN_BYTES {
+        let message = message(message_length_in_bytes);
+        group.throughput(Throughput::Bytes(message_length_in_bytes as u64));
+        for sequence_number_width in SEQUENCE_NUMBER_WIDTHS {
+            let session_parameters = SessionParameters {
+                sequence_number_width,
+                ..SessionParameters::default()
+            };
+            for bit_error_probability in BIT_ERROR_PROBABILITIES {
+                group.bench_with_input(
+                    BenchmarkId::new(
+                        format!(
+                            "{:?}/BER {:.0e}",
+                            sequence_number_width, bit_error_probability
+                        ),
+                        message_length_in_bytes,
+                    ),
+                    &message,
+                    |bencher, message| {
+                        bencher.iter(|| {
+                            transfer_in_lockstep(
+                                message,
+                                &session_parameters,
+                                bit_error_probability,
+                                &mut rng,
+                            )
+                        })
+                    },
+                );
+            }
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, frames, encoding, transfers);
+criterion_main!(benches);
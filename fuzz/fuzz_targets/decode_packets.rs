@@ -0,0 +1,82 @@
+#![no_main]
+
+//! Feeds arbitrary bytes to every parser of bytes received from the line. The first byte
+//! picks the sequence number width, the rest is taken as a frame, an acknowledgement, a
+//! session header and an HDLC frame.
+
+use libfuzzer_sys::fuzz_target;
+use stopandwait::{
+    message::open_frame,
+    packets::{
+        Packet,
+        acknowledgement::GenericAcknowledgement,
+        frame::Frame,
+        hdlc::{HdlcCodec, HdlcFrame, Modulus},
+        sequence::SequenceNumberWidth,
+    },
+    session::SessionParameters,
+};
+
+const SEQUENCE_NUMBER_WIDTHS: [SequenceNumberWidth; 4] = [
+    SequenceNumberWidth::AlternatingBit,
+    SequenceNumberWidth::Bits8,
+    SequenceNumberWidth::Bits16,
+    SequenceNumberWidth::Bits32,
+];
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&width_index, bytes)) = data.split_first() else {
+        return;
+    };
+    let sequence_number_width =
+        SEQUENCE_NUMBER_WIDTHS[width_index as usize % SEQUENCE_NUMBER_WIDTHS.len()];
+
+    let frame = Frame {
+        content: bytes.to_vec().into(),
+        sequence_number_width,
+    };
+    let is_valid = frame.is_valid();
+    match frame.get_payload_and_checksum_and_sequence_number() {
+        Ok((payload, _, sequence_number)) => {
+            assert_eq!(sequence_number.width(), sequence_number_width);
+            assert_eq!(open_frame(&frame, 0, None).is_ok(), is_valid);
+            if is_valid {
+                // A frame passing its checksum is the one the sender built, except for the
+                // alternating sequence byte that is recovered by majority vote
+                let rebuilt_frame = Frame::new(&payload, sequence_number);
+                let covered_length = match sequence_number_width.is_alternating_bit() {
+                    true => bytes.len() - 1,
+                    false => bytes.len(),
+                };
+                assert_eq!(
+                    rebuilt_frame.content[..covered_length],
+                    bytes[..covered_length]
+                );
+            }
+        }
+        Err(_) => {
+            assert!(!is_valid);
+            assert!(bytes.len() < Frame::overhead_in_bytes(sequence_number_width));
+        }
+    }
+
+    if let Ok(acknowledgement) = GenericAcknowledgement::from_bytes(bytes, sequence_number_width) {
+        assert_eq!(acknowledgement.to_bytes(), bytes);
+        assert_eq!(
+            acknowledgement.sequence_number().width(),
+            sequence_number_width
+        );
+        let _ = acknowledgement.is_valid();
+    }
+
+    if let Ok(session_parameters) = SessionParameters::from_bytes(bytes) {
+        assert_eq!(session_parameters.to_bytes(), bytes);
+    }
+
+    for modulus in [Modulus::Modulo8, Modulus::Modulo128] {
+        if let Ok(hdlc_frame) = HdlcFrame::from_bytes(bytes, modulus) {
+            assert_eq!(hdlc_frame.to_bytes(modulus), bytes);
+        }
+        let _ = HdlcCodec::new(0x03, modulus, sequence_number_width).decode(bytes);
+    }
+});
@@ -0,0 +1,157 @@
+#![no_main]
+
+//! Drives the receiver with arbitrary sequences of what a stop-and-wait sender can put on a
+//! lossy line: the expected frame or a retransmission of the previous one, either intact,
+//! with a few flipped bits or truncated, mixed with arbitrary garbage. Every frame must get
+//! the outcome its content calls for, and the message must come out intact.
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use stopandwait::{
+    endpoint::{FrameOutcome, ReceiverStateMachine},
+    message::prepare_message,
+    packets::{
+        Packet,
+        acknowledgement::{GenericAcknowledgement, ack::ACK},
+        frame::{Frame, flip_bit_in_u8},
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+    session::SessionParameters,
+};
+
+const SEQUENCE_NUMBER_WIDTHS: [SequenceNumberWidth; 4] = [
+    SequenceNumberWidth::AlternatingBit,
+    SequenceNumberWidth::Bits8,
+    SequenceNumberWidth::Bits16,
+    SequenceNumberWidth::Bits32,
+];
+
+#[derive(Arbitrary, Debug)]
+enum Corruption {
+    None,
+    /// Up to three flipped bits, which CRC-32 always detects and majority vote always corrects.
+    FlippedBits([u16; 3]),
+    Truncated(u16),
+}
+
+#[derive(Arbitrary, Debug)]
+enum Event {
+    Expected(Corruption),
+    Previous(Corruption),
+    Garbage(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    message: Vec<u8>,
+    payload_blocks: u8,
+    width_index: u8,
+    events: Vec<Event>,
+}
+
+fn corrupt(frame: &Frame, corruption: &Corruption) -> Frame {
+    let mut content = frame.content.to_vec();
+    match corruption {
+        Corruption::None => {}
+        Corruption::FlippedBits(bit_indices) => {
+            let length_in_bits = content.len() * 8;
+            for &bit_index in bit_indices {
+                let bit_index = bit_index as usize % length_in_bits;
+                content[bit_index / 8] =
+                    flip_bit_in_u8(&content[bit_index / 8], (bit_index % 8) as u8);
+            }
+        }
+        Corruption::Truncated(length_in_bytes) => {
+            content.truncate(*length_in_bytes as usize % content.len());
+        }
+    }
+    Frame {
+        content: content.into(),
+        sequence_number_width: frame.sequence_number_width,
+    }
+}
+
+/// What the receiver acts on: the payload and the sequence number after majority vote.
+fn decoded_content(frame: &Frame) -> Option<(&[u8], SequenceNumber)> {
+    Some((frame.payload().ok()?, frame.sequence_number().ok()?))
+}
+
+fuzz_target!(|input: Input| {
+    let sequence_number_width =
+        SEQUENCE_NUMBER_WIDTHS[input.width_index as usize % SEQUENCE_NUMBER_WIDTHS.len()];
+    let session_parameters = SessionParameters {
+        sequence_number_width,
+        ..SessionParameters::default()
+    };
+    let full_payload_length_in_bytes = (input.payload_blocks as usize % 64 + 1) * 8;
+    let frames = prepare_message(
+        &input.message,
+        full_payload_length_in_bytes,
+        &session_parameters,
+        None,
+    )
+    .unwrap();
+    let mut receiver = ReceiverStateMachine::new(None, sequence_number_width);
+    // Garbage, or a message crafted so that a corrupted frame is another valid frame, can pass
+    // the checksum, after which anything can be delivered
+    let mut has_accepted_garbage = false;
+
+    for event in &input.events {
+        let received_frame_count = receiver.received_frame_count();
+        let (original_frame, corruption, intact_outcome) = match event {
+            Event::Expected(corruption) if received_frame_count < frames.len() => (
+                &frames[received_frame_count],
+                corruption,
+                FrameOutcome::Accepted,
+            ),
+            Event::Previous(corruption) if (1..=frames.len()).contains(&received_frame_count) => (
+                &frames[received_frame_count - 1],
+                corruption,
+                FrameOutcome::Duplicate,
+            ),
+            Event::Expected(_) | Event::Previous(_) => continue,
+            Event::Garbage(bytes) => {
+                let garbage = Frame {
+                    content: bytes.clone().into(),
+                    sequence_number_width,
+                };
+                if let Ok((_, FrameOutcome::Accepted)) = receiver.handle_frame(&garbage) {
+                    has_accepted_garbage = true;
+                }
+                continue;
+            }
+        };
+        let frame = corrupt(original_frame, corruption);
+
+        let (acknowledgement, outcome) = receiver
+            .handle_frame(&frame)
+            .expect("Frames of the transfer always carry a supported session header");
+        if has_accepted_garbage {
+            continue;
+        }
+        match frame.is_valid() {
+            true if decoded_content(&frame) != decoded_content(original_frame) => {
+                has_accepted_garbage |= outcome == FrameOutcome::Accepted;
+            }
+            true => {
+                assert_eq!(outcome, intact_outcome);
+                assert_eq!(
+                    acknowledgement,
+                    // Always the next expected frame, whether this one was new or not
+                    GenericAcknowledgement::ACK(ACK::new(SequenceNumber::for_frame_index(
+                        receiver.received_frame_count() as u64,
+                        sequence_number_width
+                    )))
+                );
+            }
+            false => {
+                assert_eq!(outcome, FrameOutcome::Rejected);
+                assert!(matches!(acknowledgement, GenericAcknowledgement::NACK(_)));
+            }
+        }
+    }
+
+    if receiver.is_finished() && !has_accepted_garbage {
+        assert_eq!(receiver.into_message().unwrap(), input.message);
+    }
+});
@@ -0,0 +1,263 @@
+use std::ops::RangeInclusive;
+
+use bytes::Bytes;
+
+use crate::{
+    analytic::optimal_payload_bytes,
+    compression::CompressionMode,
+    encryption::{SessionCipher, TAG_LENGTH_IN_BYTES},
+    error::{Error, Result},
+    packets::{frame::Frame, sequence::SequenceNumber},
+    session::SessionParameters,
+};
+
+// Frames cut from the message as the transfer goes, each one as long as the bit error rate
+// observed so far makes worth it. The rate is estimated from the tries that the transmitter
+// made, a try failing when the frame is rejected, its acknowledgement is unreadable or its
+// retransmission timer expires. Frames already sent keep their length, a retransmission being
+// the same frame, so the receiver only sees payloads of varying lengths, which it reassembles
+// like any others.
+
+/// Weight left to the past tries at every new one, so that the estimate follows a line whose
+/// quality changes within a few dozen frames.
+const DECAY: f64 = 0.95;
+/// Tries needed before the estimate is trusted.
+const MINIMUM_TRIES: f64 = 4.0;
+
+/// Bit error rate of the line seen through the outcome of the frames sent over it.
+#[derive(Debug, Clone, Default)]
+pub struct ErrorRateEstimator {
+    tries: f64,
+    failed_tries: f64,
+    transmitted_bits: f64,
+}
+
+impl ErrorRateEstimator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_try(&mut self, bits: usize, is_success: bool) {
+        self.tries = self.tries * DECAY + 1.0;
+        self.failed_tries = self.failed_tries * DECAY
+            + match is_success {
+                true => 0.0,
+                false => 1.0,
+            };
+        self.transmitted_bits = self.transmitted_bits * DECAY + bits as f64;
+    }
+
+    /// Bit error probability under which tries of the average length would fail as often as
+    /// they did, `None` until enough tries were made.
+    pub fn bit_error_rate(&self) -> Option<f64> {
+        if self.tries < MINIMUM_TRIES {
+            return None;
+        }
+        // A line that failed every try is only known to be worse than that
+        let success_ratio = ((self.tries - self.failed_tries) / self.tries).max(0.5 / self.tries);
+        let average_bits = self.transmitted_bits / self.tries;
+        Some(1.0 - success_ratio.powf(1.0 / average_bits))
+    }
+}
+
+/// Cuts the frames of one transfer out of a message, like [`prepare_message`] but one at a
+/// time, with payload lengths that follow the optimum of [`optimal_payload_bytes`] for the
+/// estimated bit error rate.
+///
+/// The payload length moves at most by a factor of 2 from one frame to the next and stays a
+/// multiple of 8 between the given bounds. It is measured on the uncompressed data in
+/// per-frame mode and on the compressed stream in per-stream mode.
+///
+/// [`prepare_message`]: crate::message::prepare_message
+#[derive(Clone)]
+pub struct AdaptiveFrameSource {
+    /// Data not cut into frames yet, compressed already in per-stream mode.
+    remaining_data: Bytes,
+    session_parameters: SessionParameters,
+    session_cipher: Option<SessionCipher>,
+    next_frame_index: u64,
+    is_exhausted: bool,
+    payload_length_in_bytes: usize,
+    minimum_payload_length_in_bytes: usize,
+    maximum_payload_length_in_bytes: usize,
+    error_rate_estimator: ErrorRateEstimator,
+}
+
+impl AdaptiveFrameSource {
+    /// Fails with [`Error::InvalidPayloadLength`] if a payload length is not a non-zero
+    /// multiple of 8 or if `initial_payload_length_in_bytes` is out of bounds.
+    pub fn new(
+        message: &[u8],
+        initial_payload_length_in_bytes: usize,
+        payload_length_bounds_in_bytes: RangeInclusive<usize>,
+        session_parameters: &SessionParameters,
+        session_cipher: Option<SessionCipher>,
+    ) -> Result<Self> {
+        let (minimum_payload_length_in_bytes, maximum_payload_length_in_bytes) =
+            payload_length_bounds_in_bytes.into_inner();
+        for payload_length_in_bytes in [
+            minimum_payload_length_in_bytes,
+            maximum_payload_length_in_bytes,
+        ] {
+            if payload_length_in_bytes == 0 || !payload_length_in_bytes.is_multiple_of(8) {
+                return Err(Error::InvalidPayloadLength(payload_length_in_bytes));
+            }
+        }
+        if !(minimum_payload_length_in_bytes..=maximum_payload_length_in_bytes)
+            .contains(&initial_payload_length_in_bytes)
+            || !initial_payload_length_in_bytes.is_multiple_of(8)
+        {
+            return Err(Error::InvalidPayloadLength(initial_payload_length_in_bytes));
+        }
+        let remaining_data = match session_parameters.compression_mode {
+            CompressionMode::PerFrame => Bytes::copy_from_slice(message),
+            CompressionMode::PerStream => session_parameters.compression.compress(message).into(),
+        };
+        Ok(Self {
+            remaining_data,
+            session_parameters: *session_parameters,
+            session_cipher,
+            next_frame_index: 0,
+            is_exhausted: false,
+            payload_length_in_bytes: initial_payload_length_in_bytes,
+            minimum_payload_length_in_bytes,
+            maximum_payload_length_in_bytes,
+            error_rate_estimator: ErrorRateEstimator::new(),
+        })
+    }
+
+    /// Length of the data cut into the next frame.
+    pub fn payload_length_in_bytes(&self) -> usize {
+        self.payload_length_in_bytes
+    }
+
+    pub fn bit_error_rate(&self) -> Option<f64> {
+        self.error_rate_estimator.bit_error_rate()
+    }
+
+    /// Frames left to cut if the payload length stays as it is.
+    pub fn remaining_frames(&self) -> usize {
+        if self.is_exhausted {
+            return 0;
+        }
+        let session_header_frames = usize::from(self.next_frame_index == 0);
+        session_header_frames
+            + self
+                .remaining_data
+                .len()
+                .div_ceil(self.payload_length_in_bytes)
+            + 1
+    }
+
+    /// Session header first, then the data, then the empty end-of-stream frame, then `None`.
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        if self.is_exhausted {
+            return None;
+        }
+        let sequence_number = SequenceNumber::for_frame_index(
+            self.next_frame_index,
+            self.session_parameters.sequence_number_width,
+        );
+        let frame = match self.next_frame_index {
+            0 => Frame::new(&self.session_parameters.to_bytes(), sequence_number),
+            frame_index => {
+                let chunk = self
+                    .remaining_data
+                    .split_to(self.payload_length_in_bytes.min(self.remaining_data.len()));
+                self.is_exhausted = chunk.is_empty();
+                let payload = match self.session_parameters.compression_mode {
+                    CompressionMode::PerFrame if !chunk.is_empty() => {
+                        self.session_parameters.compression.compress(&chunk).into()
+                    }
+                    _ => chunk,
+                };
+                match &self.session_cipher {
+                    Some(session_cipher) => {
+                        session_cipher.seal(frame_index, &payload, sequence_number)
+                    }
+                    None => Frame::new(&payload, sequence_number),
+                }
+            }
+        };
+        self.next_frame_index += 1;
+        Some(frame)
+    }
+
+    /// Feeds the outcome of one transmission of a frame of `frame_length_in_bytes` to the
+    /// estimate, and moves the payload length of the next frames toward the optimum.
+    pub fn record_try(&mut self, frame_length_in_bytes: usize, is_success: bool) {
+        self.error_rate_estimator
+            .record_try(frame_length_in_bytes * 8, is_success);
+        let Some(bit_error_rate) = self.error_rate_estimator.bit_error_rate() else {
+            return;
+        };
+        let overhead_in_bytes =
+            Frame::overhead_in_bytes(self.session_parameters.sequence_number_width)
+                + match self.session_cipher {
+                    Some(_) => TAG_LENGTH_IN_BYTES,
+                    None => 0,
+                };
+        let target_length_in_bytes = optimal_payload_bytes(overhead_in_bytes, bit_error_rate).clamp(
+            self.payload_length_in_bytes as f64 / 2.0,
+            self.payload_length_in_bytes as f64 * 2.0,
+        ) as usize;
+        let payload_length_in_bytes = (target_length_in_bytes / 8 * 8).clamp(
+            self.minimum_payload_length_in_bytes,
+            self.maximum_payload_length_in_bytes,
+        );
+        if payload_length_in_bytes != self.payload_length_in_bytes {
+            log::debug!(
+                "Estimated bit error rate {:.1e} - Payload length {} B",
+                bit_error_rate,
+                payload_length_in_bytes
+            );
+            self.payload_length_in_bytes = payload_length_in_bytes;
+        }
+    }
+}
+
+#[test]
+fn payload_length_settles_near_the_optimum_of_the_line() {
+    use crate::{
+        compression::Compression,
+        endpoint::{ReceiverStateMachine, TransmitterStateMachine},
+        packets::{Packet, sequence::SequenceNumberWidth},
+    };
+    use rand::SeedableRng;
+
+    let message: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    for compression_mode in [CompressionMode::PerFrame, CompressionMode::PerStream] {
+        let session_parameters = SessionParameters {
+            compression: Compression::None,
+            compression_mode,
+            sequence_number_width: SequenceNumberWidth::Bits16,
+            ..SessionParameters::default()
+        };
+        for (bit_error_probability, optimal_length_in_bytes) in [(0.0, 4096), (1e-4, 85)] {
+            let frame_source =
+                AdaptiveFrameSource::new(&message, 1024, 16..=4096, &session_parameters, None)
+                    .unwrap();
+            let mut transmitter = TransmitterStateMachine::adaptive(frame_source);
+            let mut receiver =
+                ReceiverStateMachine::new(None, session_parameters.sequence_number_width);
+            let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+            // Lossless acknowledgements, corrupted frames are rejected with a NACK
+            while let Some(frame) = transmitter.current_frame() {
+                let received_frame =
+                    frame.simulate_errors_with_probability(bit_error_probability, &mut rng);
+                let (acknowledgement, _) = receiver.handle_frame(&received_frame).unwrap();
+                transmitter.handle_acknowledgement(&acknowledgement);
+            }
+
+            let payload_length_in_bytes = transmitter.payload_length_in_bytes().unwrap();
+            assert!(
+                payload_length_in_bytes >= optimal_length_in_bytes / 2
+                    && payload_length_in_bytes <= optimal_length_in_bytes * 2,
+                "{} B instead of about {} B",
+                payload_length_in_bytes,
+                optimal_length_in_bytes
+            );
+            assert_eq!(receiver.into_message().unwrap(), message);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+// Closed-form stop-and-wait performance over a binary symmetric channel, ignoring the loss of
+// acknowledgements, to check measured sweeps against
+
+/// Probability that none of `bits` bits is flipped.
+pub fn transmission_success_probability(bit_error_probability: f64, bits: usize) -> f64 {
+    (1.0 - bit_error_probability).powf(bits as f64)
+}
+
+/// Expected number of transmissions until a frame of `frame_bytes` arrives intact.
+pub fn expected_tries(bit_error_probability: f64, frame_bytes: usize) -> f64 {
+    1.0 / transmission_success_probability(bit_error_probability, frame_bytes * 8)
+}
+
+/// Payload bits over transmitted bits, counting the overhead of every frame and every
+/// retransmission.
+pub fn efficiency(payload_bytes: usize, overhead_bytes: usize, bit_error_probability: f64) -> f64 {
+    let frame_bytes = payload_bytes + overhead_bytes;
+    payload_bytes as f64 / frame_bytes as f64
+        * transmission_success_probability(bit_error_probability, frame_bytes * 8)
+}
+
+/// Payload length that maximizes [`efficiency`], where the overhead saved by longer frames
+/// stops paying for their higher chance of being hit. Infinite on an error-free line.
+pub fn optimal_payload_bytes(overhead_bytes: usize, bit_error_probability: f64) -> f64 {
+    if bit_error_probability <= 0.0 {
+        return f64::INFINITY;
+    }
+    // Root of the derivative of the logarithm of the efficiency
+    let overhead_bytes = overhead_bytes as f64;
+    let error_exponent_per_byte = -8.0 * (1.0 - bit_error_probability).ln();
+    (-overhead_bytes
+        + (overhead_bytes.powi(2) + 4.0 * overhead_bytes / error_exponent_per_byte).sqrt())
+        / 2.0
+}
+
+/// Payload bytes per second when every try costs a round trip plus `per_frame_time`.
+pub fn goodput(
+    payload_bytes: usize,
+    overhead_bytes: usize,
+    bit_error_probability: f64,
+    round_trip_time: Duration,
+    per_frame_time: Duration,
+) -> f64 {
+    payload_bytes as f64
+        / expected_tries(bit_error_probability, payload_bytes + overhead_bytes)
+        / (round_trip_time + per_frame_time).as_secs_f64()
+}
+
+#[test]
+fn model_matches_hand_computed_values() {
+    assert_eq!(expected_tries(0.0, 1000), 1.0);
+    assert!((expected_tries(1e-4, 1250) - 1.0 / 0.9999f64.powi(10_000)).abs() < 1e-9);
+    assert!((efficiency(994, 6, 0.0) - 0.994).abs() < 1e-12);
+    assert!(efficiency(4000, 6, 1e-4) < efficiency(1000, 6, 1e-4));
+    assert_eq!(optimal_payload_bytes(6, 0.0), f64::INFINITY);
+    let optimal_bytes = optimal_payload_bytes(6, 1e-4).round() as usize;
+    assert_eq!(optimal_bytes, 84);
+    assert!(efficiency(optimal_bytes, 6, 1e-4) > efficiency(optimal_bytes - 8, 6, 1e-4));
+    assert!(efficiency(optimal_bytes, 6, 1e-4) > efficiency(optimal_bytes + 8, 6, 1e-4));
+    assert!(
+        (goodput(
+            1000,
+            6,
+            0.0,
+            Duration::from_millis(9),
+            Duration::from_millis(1)
+        ) - 100_000.0)
+            .abs()
+            < 1e-6
+    );
+}
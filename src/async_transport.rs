@@ -0,0 +1,404 @@
+use std::{io, path::Path};
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    time::{Instant, timeout_at},
+};
+
+use crate::{
+    encryption::PreSharedKey,
+    endpoint::{EndpointTimers, ReceiverStateMachine, TransmitterAction, TransmitterStateMachine},
+    error::{Error, Result},
+    message::{build_session_cipher, prepare_message},
+    packets::{
+        acknowledgement::GenericAcknowledgement, frame::Frame, sequence::SequenceNumberWidth,
+    },
+    session::SessionParameters,
+};
+
+// Runs one direction of a transfer on a tokio runtime: the sender only sends frames and
+// receives acknowledgements, the receiver the other way round, so acknowledgements are never
+// delayed nor piggybacked. Timers are those of the runtime.
+
+/// Largest packet accepted from a link, well above the largest frame.
+pub const MAX_PACKET_LENGTH_IN_BYTES: usize = 64 * 1024;
+
+/// Link carrying whole packets, each one sent and received at once.
+pub trait PacketLink {
+    fn send_packet(&mut self, packet: &[u8]) -> impl Future<Output = io::Result<()>> + Send;
+    fn recv_packet(&mut self) -> impl Future<Output = io::Result<Vec<u8>>> + Send;
+}
+
+/// One datagram per packet, on a socket connected to the peer.
+impl PacketLink for UdpSocket {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.send(packet).await.map(|_| ())
+    }
+
+    async fn recv_packet(&mut self) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![0; MAX_PACKET_LENGTH_IN_BYTES];
+        let length_in_bytes = self.recv(&mut buffer).await?;
+        buffer.truncate(length_in_bytes);
+        Ok(buffer)
+    }
+}
+
+/// Packets delimited by their length as a big-endian `u32` on a byte stream.
+///
+/// Bytes are kept in a buffer of the link until their packet is complete, so a receive
+/// cancelled by a timer halfway through a packet resumes where it stopped.
+#[derive(Debug)]
+pub struct StreamLink<S> {
+    stream: S,
+    read_buffer: BytesMut,
+}
+
+impl<S> StreamLink<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Bytes of a packet received only in part are lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PacketLink for StreamLink<S> {
+    async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_u32(packet.len() as u32).await?;
+        self.stream.write_all(packet).await?;
+        self.stream.flush().await
+    }
+
+    // Only `read_buf` is awaited, which is cancel safe, the bytes read before staying buffered
+    async fn recv_packet(&mut self) -> io::Result<Vec<u8>> {
+        loop {
+            if let Some(length_prefix) = self.read_buffer.first_chunk::<4>() {
+                let length_in_bytes = u32::from_be_bytes(*length_prefix) as usize;
+                if length_in_bytes > MAX_PACKET_LENGTH_IN_BYTES {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Packet of {} B is too long", length_in_bytes),
+                    ));
+                }
+                if self.read_buffer.len() >= 4 + length_in_bytes {
+                    self.read_buffer.advance(4);
+                    return Ok(self.read_buffer.split_to(length_in_bytes).to_vec());
+                }
+                self.read_buffer
+                    .reserve(4 + length_in_bytes - self.read_buffer.len());
+            } else {
+                self.read_buffer.reserve(4);
+            }
+            if self.stream.read_buf(&mut self.read_buffer).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+/// Closed streams are the peer going away, anything else is an I/O error.
+fn link_error(error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => Error::ChannelClosed,
+        _ => Error::Io(error),
+    }
+}
+
+/// Sends `message` over `link` and returns once its last frame is acknowledged.
+///
+/// A frame is sent again when its retransmission timer expires or when the acknowledgement
+/// asks for it, like [`run_endpoint`] does. Fails with [`Error::Timeout`] if nothing was heard
+/// from the peer for `timers.idle`.
+///
+/// [`run_endpoint`]: crate::endpoint::run_endpoint
+pub async fn send_message(
+    link: &mut impl PacketLink,
+    message: &[u8],
+    full_payload_length_in_bytes: usize,
+    session_parameters: &SessionParameters,
+    pre_shared_key: Option<&PreSharedKey>,
+    timers: EndpointTimers,
+) -> Result<()> {
+    let session_cipher = build_session_cipher(session_parameters, pre_shared_key)?;
+    let frames = prepare_message(
+        message,
+        full_payload_length_in_bytes,
+        session_parameters,
+        session_cipher.as_ref(),
+    )?;
+    let mut transmitter = TransmitterStateMachine::new(frames);
+    let mut idle_deadline = timers.idle.map(|idle| Instant::now() + idle);
+    let mut must_transmit = true;
+    let mut retransmission_deadline = Instant::now();
+
+    while let Some(frame) = transmitter.current_frame() {
+        if must_transmit {
+            let (frames_transmitted, total_number_of_frames_to_transmit) = transmitter.progress();
+            log::debug!(
+                "Sending frame {}/{}",
+                frames_transmitted + 1,
+                total_number_of_frames_to_transmit
+            );
+            link.send_packet(&frame.content).await.map_err(link_error)?;
+            retransmission_deadline = Instant::now() + timers.retransmission;
+        }
+
+        let deadline = match idle_deadline {
+            Some(idle_deadline) => retransmission_deadline.min(idle_deadline),
+            None => retransmission_deadline,
+        };
+        let Ok(packet) = timeout_at(deadline, link.recv_packet()).await else {
+            if idle_deadline.is_some_and(|idle_deadline| Instant::now() >= idle_deadline) {
+                return Err(Error::Timeout);
+            }
+            log::debug!("Retransmission timer expired - Retrying same packet");
+            must_transmit = true;
+            continue;
+        };
+        let packet = packet.map_err(link_error)?;
+        idle_deadline = timers.idle.map(|idle| Instant::now() + idle);
+
+        // A packet of the wrong length is a corrupted acknowledgement, like an invalid one
+        let action = match GenericAcknowledgement::from_bytes(&packet, frame.sequence_number_width)
+        {
+            Ok(acknowledgement) => transmitter.handle_acknowledgement(&acknowledgement),
+            Err(_) => TransmitterAction::RetransmitFrame,
+        };
+        must_transmit = matches!(
+            action,
+            TransmitterAction::SendNextFrame | TransmitterAction::RetransmitFrame
+        );
+    }
+    log::info!("Message acknowledged");
+    Ok(())
+}
+
+/// Receives a message sent with [`send_message`] and returns it.
+///
+/// Every frame is acknowledged as soon as it is received. Once the message is complete the
+/// receiver keeps answering retransmissions until the peer has been quiet for `timers.linger`,
+/// as its last acknowledgement may have been lost, or until it closes the link. Fails with
+/// [`Error::Timeout`] if nothing was heard from the peer for `timers.idle` before that.
+pub async fn recv_message(
+    link: &mut impl PacketLink,
+    sequence_number_width: SequenceNumberWidth,
+    pre_shared_key: Option<PreSharedKey>,
+    timers: EndpointTimers,
+) -> Result<Vec<u8>> {
+    let mut receiver = ReceiverStateMachine::new(pre_shared_key, sequence_number_width);
+
+    loop {
+        let deadline = match receiver.is_finished() {
+            true => Some(Instant::now() + timers.linger),
+            false => timers.idle.map(|idle| Instant::now() + idle),
+        };
+        let packet = match deadline {
+            Some(deadline) => match timeout_at(deadline, link.recv_packet()).await {
+                Ok(packet) => packet,
+                Err(_) if receiver.is_finished() => break,
+                Err(_) => return Err(Error::Timeout),
+            },
+            None => link.recv_packet().await,
+        };
+        let packet = match packet.map_err(link_error) {
+            Ok(packet) => packet,
+            // The peer only leaves once it has received every acknowledgement
+            Err(Error::ChannelClosed) if receiver.is_finished() => break,
+            Err(error) => return Err(error),
+        };
+
+        let frame = Frame {
+            content: Bytes::from(packet),
+            sequence_number_width,
+        };
+        let (acknowledgement, outcome) = receiver.handle_frame(&frame)?;
+        log::debug!("Received frame - {:?}", outcome);
+        link.send_packet(&acknowledgement.to_bytes())
+            .await
+            .map_err(link_error)?;
+    }
+    log::info!("Message received");
+    receiver.into_message()
+}
+
+/// Sends the file at `path`, see [`send_message`].
+pub async fn send_file(
+    link: &mut impl PacketLink,
+    path: impl AsRef<Path>,
+    full_payload_length_in_bytes: usize,
+    session_parameters: &SessionParameters,
+    pre_shared_key: Option<&PreSharedKey>,
+    timers: EndpointTimers,
+) -> Result<()> {
+    let content = tokio::fs::read(path).await?;
+    send_message(
+        link,
+        &content,
+        full_payload_length_in_bytes,
+        session_parameters,
+        pre_shared_key,
+        timers,
+    )
+    .await
+}
+
+/// Receives a file into `path`, see [`recv_message`].
+pub async fn recv_file(
+    link: &mut impl PacketLink,
+    path: impl AsRef<Path>,
+    sequence_number_width: SequenceNumberWidth,
+    pre_shared_key: Option<PreSharedKey>,
+    timers: EndpointTimers,
+) -> Result<()> {
+    let content = recv_message(link, sequence_number_width, pre_shared_key, timers).await?;
+    Ok(tokio::fs::write(path, content).await?)
+}
+
+#[test]
+fn messages_get_through_lossy_links() {
+    use std::time::Duration;
+
+    /// Drops every third packet it is asked to send.
+    struct LossyLink<L> {
+        link: L,
+        sent_packets: usize,
+    }
+
+    impl<L: PacketLink + Send> PacketLink for LossyLink<L> {
+        async fn send_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+            self.sent_packets += 1;
+            match self.sent_packets % 3 {
+                0 => Ok(()),
+                _ => self.link.send_packet(packet).await,
+            }
+        }
+
+        async fn recv_packet(&mut self) -> io::Result<Vec<u8>> {
+            self.link.recv_packet().await
+        }
+    }
+
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(20),
+        linger: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(5)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters {
+        sequence_number_width: SequenceNumberWidth::Bits16,
+        ..SessionParameters::default()
+    };
+    let message: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (stream_a, stream_b) = tokio::io::duplex(4096);
+        let mut sender = LossyLink {
+            link: StreamLink::new(stream_a),
+            sent_packets: 0,
+        };
+        let mut receiver = LossyLink {
+            link: StreamLink::new(stream_b),
+            sent_packets: 0,
+        };
+        let (sent, received) = tokio::join!(
+            send_message(
+                &mut sender,
+                &message,
+                512,
+                &session_parameters,
+                None,
+                timers
+            ),
+            recv_message(&mut receiver, SequenceNumberWidth::Bits16, None, timers),
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), message);
+
+        let socket_a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let socket_b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket_a
+            .connect(socket_b.local_addr().unwrap())
+            .await
+            .unwrap();
+        socket_b
+            .connect(socket_a.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut sender = LossyLink {
+            link: socket_a,
+            sent_packets: 0,
+        };
+        let mut receiver = LossyLink {
+            link: socket_b,
+            sent_packets: 0,
+        };
+        let (sent, received) = tokio::join!(
+            send_message(
+                &mut sender,
+                &message,
+                512,
+                &session_parameters,
+                None,
+                timers
+            ),
+            recv_message(&mut receiver, SequenceNumberWidth::Bits16, None, timers),
+        );
+        sent.unwrap();
+        assert_eq!(received.unwrap(), message);
+    });
+}
+
+#[test]
+fn packets_split_across_retransmission_timeouts_keep_the_stream_in_step() {
+    use std::time::Duration;
+
+    let retransmission = Duration::from_millis(20);
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    runtime.block_on(async {
+        let (mut stream_a, stream_b) = tokio::io::duplex(4096);
+        let mut link = StreamLink::new(stream_b);
+        let packets: [Vec<u8>; 2] = [(0..100).collect(), (100..150).collect()];
+        let mut bytes = Vec::new();
+        for packet in &packets {
+            bytes.extend_from_slice(&(packet.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(packet);
+        }
+
+        // The length prefix and half of the first packet, then nothing for longer than the
+        // retransmission timer of the reading side
+        stream_a.write_all(&bytes[..54]).await.unwrap();
+        let started = Instant::now();
+        assert!(
+            timeout_at(started + retransmission, link.recv_packet())
+                .await
+                .is_err()
+        );
+        tokio::time::sleep(retransmission).await;
+        stream_a.write_all(&bytes[54..]).await.unwrap();
+
+        let received_packets = timeout_at(started + Duration::from_secs(1), async {
+            [
+                link.recv_packet().await.unwrap(),
+                link.recv_packet().await.unwrap(),
+            ]
+        })
+        .await
+        .expect("Stream out of step");
+        assert_eq!(received_packets, packets);
+    });
+}
@@ -0,0 +1,155 @@
+use std::io::{self, Read, Write};
+
+use bytes::Bytes;
+use flate2::{Compression as DeflateLevel, read::DeflateDecoder, write::DeflateEncoder};
+
+use crate::error::{DecodeError, Result};
+
+/// Algorithm applied to payloads before they are split into frames.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Compression {
+    None,
+    Deflate,
+    Lz4,
+}
+
+/// Whether each frame is compressed on its own or the whole stream is compressed
+/// once and then split into frames.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CompressionMode {
+    PerFrame,
+    PerStream,
+}
+
+pub const SUPPORTED_COMPRESSIONS: [Compression; 3] =
+    [Compression::Lz4, Compression::Deflate, Compression::None];
+
+impl Compression {
+    pub const fn id(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+            Compression::Lz4 => 2,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Deflate),
+            2 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Compression::None => data.to_vec(),
+            Compression::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), DeflateLevel::default());
+                encoder
+                    .write_all(data)
+                    .expect("Writing to a Vec should not fail");
+                encoder.finish().expect("Writing to a Vec should not fail")
+            }
+            Compression::Lz4 => lz4_flex::compress_prepend_size(data),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Deflate => {
+                let mut decompressed = Vec::new();
+                DeflateDecoder::new(data)
+                    .read_to_end(&mut decompressed)
+                    .map_err(DecodeError::Decompression)?;
+                Ok(decompressed)
+            }
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data).map_err(|error| {
+                DecodeError::Decompression(io::Error::new(io::ErrorKind::InvalidData, error)).into()
+            }),
+        }
+    }
+}
+
+impl CompressionMode {
+    pub const fn id(&self) -> u8 {
+        match self {
+            CompressionMode::PerFrame => 0,
+            CompressionMode::PerStream => 1,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(CompressionMode::PerFrame),
+            1 => Some(CompressionMode::PerStream),
+            _ => None,
+        }
+    }
+
+    /// Splits `data` into the payloads carried by consecutive frames.
+    ///
+    /// `chunk_length_in_bytes` is measured on the uncompressed data in per-frame mode and on
+    /// the compressed stream in per-stream mode.
+    pub fn compress_into_payloads(
+        &self,
+        compression: Compression,
+        data: &[u8],
+        chunk_length_in_bytes: usize,
+    ) -> Vec<Bytes> {
+        match self {
+            CompressionMode::PerFrame => data
+                .chunks(chunk_length_in_bytes)
+                .map(|chunk| compression.compress(chunk).into())
+                .collect(),
+            // The payloads share the compressed stream
+            CompressionMode::PerStream => {
+                let stream = Bytes::from(compression.compress(data));
+                (0..stream.len())
+                    .step_by(chunk_length_in_bytes)
+                    .map(|start| {
+                        stream.slice(start..stream.len().min(start + chunk_length_in_bytes))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Inverse of [`CompressionMode::compress_into_payloads`].
+    pub fn decompress_payloads<'a>(
+        &self,
+        compression: Compression,
+        payloads: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<u8>> {
+        match self {
+            CompressionMode::PerFrame => {
+                let mut data = Vec::new();
+                for payload in payloads {
+                    data.append(&mut compression.decompress(payload)?);
+                }
+                Ok(data)
+            }
+            CompressionMode::PerStream => {
+                let stream: Vec<u8> = payloads.into_iter().flatten().copied().collect();
+                compression.decompress(&stream)
+            }
+        }
+    }
+}
+
+#[test]
+fn payloads_round_trip_for_every_compression_and_mode() {
+    let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+
+    for compression in SUPPORTED_COMPRESSIONS {
+        for mode in [CompressionMode::PerFrame, CompressionMode::PerStream] {
+            let payloads = mode.compress_into_payloads(compression, &data, 4096);
+            let decompressed = mode
+                .decompress_payloads(compression, payloads.iter().map(|payload| &payload[..]))
+                .unwrap();
+            assert_eq!(decompressed, data, "{:?} {:?}", compression, mode);
+        }
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ratatui::{
+    Frame,
+    crossterm::event::{self, Event, KeyCode},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Bar, BarChart, BarGroup, Block, Gauge, Paragraph, Sparkline},
+};
+
+use crate::{
+    error::Result,
+    trace::{TraceAction, TraceEvent, TraceSource},
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+const GOODPUT_WINDOW_US: u64 = 1_000_000;
+const GOODPUT_HISTORY_LENGTH: usize = 200;
+/// Upper bounds of the RTT histogram buckets, the last bucket collects everything above.
+pub const RTT_BUCKET_BOUNDS_US: [u64; 9] =
+    [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000];
+
+#[derive(Debug, Clone)]
+pub struct EndpointStatistics {
+    pub name: String,
+    pub total_frames: usize,
+    pub acknowledged_frames: usize,
+    pub current_sequence_number: Option<u32>,
+    pub sent_frames: usize,
+    pub retransmitted_frames: usize,
+    pub received_bytes: usize,
+    /// Valid frames discarded because they were already accepted or numbered ahead.
+    pub discarded_frames: usize,
+    pub stale_acknowledgements: usize,
+    // Time the current frame was last sent, and whether it was sent more than once
+    outstanding_frame: Option<(u64, bool)>,
+    received_window: VecDeque<(u64, usize)>,
+    goodput_history: VecDeque<u64>,
+}
+
+impl EndpointStatistics {
+    fn new(name: &str, total_frames: usize) -> Self {
+        Self {
+            name: name.to_owned(),
+            total_frames,
+            acknowledged_frames: 0,
+            current_sequence_number: None,
+            sent_frames: 0,
+            retransmitted_frames: 0,
+            received_bytes: 0,
+            discarded_frames: 0,
+            stale_acknowledgements: 0,
+            outstanding_frame: None,
+            received_window: VecDeque::new(),
+            goodput_history: VecDeque::with_capacity(GOODPUT_HISTORY_LENGTH),
+        }
+    }
+
+    pub fn retransmission_rate(&self) -> f64 {
+        match self.sent_frames {
+            0 => 0.0,
+            sent_frames => self.retransmitted_frames as f64 / sent_frames as f64,
+        }
+    }
+
+    /// Payload bytes of accepted frames received during the last second.
+    pub fn goodput(&self) -> u64 {
+        self.received_window
+            .iter()
+            .map(|&(_, bytes)| bytes as u64)
+            .sum()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ChannelStatistics {
+    pub name: String,
+    pub transmitted_bits: u64,
+    pub corrupted_bits: u64,
+    pub duplicated_packets: usize,
+    pub reordered_packets: usize,
+    pub lost_packets: usize,
+}
+
+impl ChannelStatistics {
+    pub fn observed_bit_error_rate(&self) -> f64 {
+        match self.transmitted_bits {
+            0 => 0.0,
+            transmitted_bits => self.corrupted_bits as f64 / transmitted_bits as f64,
+        }
+    }
+}
+
+/// Live statistics of a transfer, built from the events recorded by its [`Tracer`].
+///
+/// [`Tracer`]: crate::trace::Tracer
+#[derive(Debug, Clone)]
+pub struct DashboardStatistics {
+    pub endpoints: Vec<EndpointStatistics>,
+    pub channels: Vec<ChannelStatistics>,
+    pub configured_bit_error_probability: f64,
+    /// Round trip times of frames sent once, as Karn's algorithm ignores retransmitted ones.
+    pub rtt_histogram: [u64; RTT_BUCKET_BOUNDS_US.len() + 1],
+    first_event_us: Option<u64>,
+    last_event_us: u64,
+}
+
+impl DashboardStatistics {
+    /// `endpoints` pairs the name of every endpoint with the number of frames it sends.
+    pub fn new(endpoints: &[(&str, usize)], configured_bit_error_probability: f64) -> Self {
+        Self {
+            endpoints: endpoints
+                .iter()
+                .map(|&(name, total_frames)| EndpointStatistics::new(name, total_frames))
+                .collect(),
+            channels: Vec::new(),
+            configured_bit_error_probability,
+            rtt_histogram: [0; RTT_BUCKET_BOUNDS_US.len() + 1],
+            first_event_us: None,
+            last_event_us: 0,
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_micros(
+            self.last_event_us - self.first_event_us.unwrap_or(self.last_event_us),
+        )
+    }
+
+    pub fn update(&mut self, event: &TraceEvent) {
+        self.first_event_us.get_or_insert(event.timestamp_us);
+        self.last_event_us = self.last_event_us.max(event.timestamp_us);
+
+        if event.source == TraceSource::Channel {
+            let channel = match self
+                .channels
+                .iter()
+                .position(|channel| channel.name == event.node)
+            {
+                Some(index) => &mut self.channels[index],
+                None => {
+                    self.channels.push(ChannelStatistics {
+                        name: event.node.clone(),
+                        ..ChannelStatistics::default()
+                    });
+                    self.channels.last_mut().expect("Channel was just added")
+                }
+            };
+            match event.action {
+                TraceAction::Duplicate => channel.duplicated_packets += 1,
+                TraceAction::Reorder => channel.reordered_packets += 1,
+                // Lost packets never reach the far end, so none of their bits can be corrupted
+                TraceAction::Lose => {
+                    channel.lost_packets += 1;
+                    return;
+                }
+                _ => {}
+            }
+            channel.transmitted_bits += event.length_in_bytes as u64 * 8;
+            channel.corrupted_bits += event.corrupted_bits as u64;
+            return;
+        }
+
+        let Some(endpoint) = self
+            .endpoints
+            .iter_mut()
+            .find(|endpoint| endpoint.name == event.node)
+        else {
+            return;
+        };
+        match event.action {
+            TraceAction::SendFrame => {
+                endpoint.sent_frames += 1;
+                endpoint.current_sequence_number = event.sequence_number;
+                endpoint.outstanding_frame = Some((event.timestamp_us, false));
+            }
+            TraceAction::RetransmitFrame => {
+                endpoint.sent_frames += 1;
+                endpoint.retransmitted_frames += 1;
+                endpoint.outstanding_frame = Some((event.timestamp_us, true));
+            }
+            TraceAction::AcknowledgementAdvance
+            | TraceAction::AcknowledgementPause
+            | TraceAction::AcknowledgementFinish => {
+                if let Some((sent_us, is_retransmitted)) = endpoint.outstanding_frame.take() {
+                    endpoint.acknowledged_frames += 1;
+                    if !is_retransmitted {
+                        let rtt_us = event.timestamp_us.saturating_sub(sent_us);
+                        let bucket = RTT_BUCKET_BOUNDS_US.partition_point(|&bound| bound < rtt_us);
+                        self.rtt_histogram[bucket] += 1;
+                    }
+                }
+            }
+            TraceAction::AcceptFrame => {
+                let payload_length_in_bytes = event.payload_length_in_bytes();
+                endpoint.received_bytes += payload_length_in_bytes;
+                endpoint
+                    .received_window
+                    .push_back((event.timestamp_us, payload_length_in_bytes));
+            }
+            TraceAction::DuplicateFrame | TraceAction::OutOfOrderFrame => {
+                endpoint.discarded_frames += 1
+            }
+            TraceAction::AcknowledgementStale => endpoint.stale_acknowledgements += 1,
+            _ => {}
+        }
+    }
+
+    /// Slides the goodput windows to `now_us` and appends the current goodput to their history.
+    pub fn sample_goodput(&mut self, now_us: u64) {
+        for endpoint in &mut self.endpoints {
+            while let Some(&(received_us, _)) = endpoint.received_window.front()
+                && received_us + GOODPUT_WINDOW_US < now_us
+            {
+                endpoint.received_window.pop_front();
+            }
+            if endpoint.goodput_history.len() == GOODPUT_HISTORY_LENGTH {
+                endpoint.goodput_history.pop_front();
+            }
+            let goodput = endpoint.goodput();
+            endpoint.goodput_history.push_back(goodput);
+        }
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Shows `statistics` in the terminal, updated with `events` until every tracer is dropped.
+///
+/// Events are drained between two refreshes, so the threads recording them never wait for the
+/// terminal. Pressing `q` hides the dashboard while the events keep being consumed. Returns the
+/// consumed events if `keep_events` is set, so they can still be exported.
+pub fn run_dashboard(
+    events: mpsc::Receiver<TraceEvent>,
+    mut statistics: DashboardStatistics,
+    keep_events: bool,
+) -> Result<Vec<TraceEvent>> {
+    let mut kept_events = Vec::new();
+    let mut terminal = Some(ratatui::init());
+    loop {
+        let refresh_deadline = Instant::now() + REFRESH_INTERVAL;
+        let mut is_disconnected = false;
+        while Instant::now() < refresh_deadline {
+            match events.recv_timeout(refresh_deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => {
+                    statistics.update(&event);
+                    if keep_events {
+                        kept_events.push(event);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    is_disconnected = true;
+                    break;
+                }
+            }
+        }
+        statistics.sample_goodput(now_us());
+
+        if let Some(visible_terminal) = &mut terminal {
+            visible_terminal.draw(|frame| draw(frame, &statistics))?;
+            while event::poll(Duration::ZERO)? {
+                if let Event::Key(key) = event::read()?
+                    && key.code == KeyCode::Char('q')
+                {
+                    ratatui::restore();
+                    terminal = None;
+                    break;
+                }
+            }
+        }
+        if is_disconnected {
+            break;
+        }
+    }
+    if terminal.is_some() {
+        ratatui::restore();
+    }
+    Ok(kept_events)
+}
+
+fn draw(frame: &mut Frame, statistics: &DashboardStatistics) {
+    let [progress_area, goodput_area, bottom_area] = Layout::vertical([
+        Constraint::Length(3 * statistics.endpoints.len() as u16),
+        Constraint::Min(6),
+        Constraint::Length(12),
+    ])
+    .areas(frame.area());
+    let [rtt_area, channel_area] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .areas(bottom_area);
+
+    let endpoint_areas =
+        Layout::vertical(statistics.endpoints.iter().map(|_| Constraint::Length(3)))
+            .split(progress_area);
+    for (endpoint, &area) in statistics.endpoints.iter().zip(endpoint_areas.iter()) {
+        let ratio = match endpoint.total_frames {
+            0 => 1.0,
+            total_frames => endpoint.acknowledged_frames as f64 / total_frames as f64,
+        };
+        let title = format!(
+            " {} sending - sequence {} - retransmissions {:.1}% ",
+            endpoint.name,
+            endpoint
+                .current_sequence_number
+                .map_or("-".to_owned(), |sequence_number| sequence_number
+                    .to_string()),
+            endpoint.retransmission_rate() * 100.0
+        );
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(title))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio.min(1.0))
+                .label(format!(
+                    "{}/{} frames",
+                    endpoint.acknowledged_frames, endpoint.total_frames
+                )),
+            area,
+        );
+    }
+
+    draw_goodput(frame, statistics, goodput_area);
+    draw_rtt_histogram(frame, statistics, rtt_area);
+
+    let mut channel_lines = vec![
+        Line::from(format!(
+            "Configured BER: {:.2e}",
+            statistics.configured_bit_error_probability
+        )),
+        Line::from(format!("Elapsed: {:.1?}", statistics.elapsed())),
+    ];
+    for channel in &statistics.channels {
+        channel_lines.push(Line::from(format!(
+            "{}: observed BER {:.2e} ({} of {} bits)",
+            channel.name,
+            channel.observed_bit_error_rate(),
+            channel.corrupted_bits,
+            channel.transmitted_bits
+        )));
+        if channel.duplicated_packets + channel.reordered_packets + channel.lost_packets > 0 {
+            channel_lines.push(Line::from(format!(
+                "  duplicated {}, reordered {}, lost {} packets",
+                channel.duplicated_packets, channel.reordered_packets, channel.lost_packets
+            )));
+        }
+    }
+    for endpoint in &statistics.endpoints {
+        channel_lines.push(Line::from(format!(
+            "{}: discarded {} frames, ignored {} stale acknowledgements",
+            endpoint.name, endpoint.discarded_frames, endpoint.stale_acknowledgements
+        )));
+    }
+    channel_lines.push(Line::from("Press q to hide the dashboard"));
+    frame.render_widget(
+        Paragraph::new(channel_lines).block(Block::bordered().title(" Channel ")),
+        channel_area,
+    );
+}
+
+fn draw_goodput(frame: &mut Frame, statistics: &DashboardStatistics, area: Rect) {
+    let areas = Layout::horizontal(
+        statistics
+            .endpoints
+            .iter()
+            .map(|_| Constraint::Ratio(1, statistics.endpoints.len().max(1) as u32)),
+    )
+    .split(area);
+    for (endpoint, &area) in statistics.endpoints.iter().zip(areas.iter()) {
+        let history: Vec<u64> = endpoint.goodput_history.iter().copied().collect();
+        // Only the most recent samples fit
+        let visible_samples = (area.width.saturating_sub(2) as usize).min(history.len());
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title(format!(
+                    " {} goodput {:.1} kB/s - {} B received ",
+                    endpoint.name,
+                    endpoint.goodput() as f64 / 1000.0,
+                    endpoint.received_bytes
+                )))
+                .style(Style::default().fg(Color::Cyan))
+                .data(&history[history.len() - visible_samples..]),
+            area,
+        );
+    }
+}
+
+fn draw_rtt_histogram(frame: &mut Frame, statistics: &DashboardStatistics, area: Rect) {
+    let labels = RTT_BUCKET_BOUNDS_US
+        .iter()
+        .map(|bound| match bound {
+            ..1_000 => format!("<{}us", bound),
+            _ => format!("<{}ms", bound / 1_000),
+        })
+        .chain([format!(
+            ">{}ms",
+            RTT_BUCKET_BOUNDS_US[RTT_BUCKET_BOUNDS_US.len() - 1] / 1_000
+        )]);
+    let bars: Vec<Bar> = labels
+        .zip(statistics.rtt_histogram)
+        .map(|(label, count)| Bar::default().label(Line::from(label)).value(count))
+        .collect();
+    frame.render_widget(
+        BarChart::default()
+            .block(Block::bordered().title(" RTT of frames sent once "))
+            .bar_width(6)
+            .bar_gap(1)
+            .bar_style(Style::default().fg(Color::Yellow))
+            .data(BarGroup::default().bars(&bars)),
+        area,
+    );
+}
+
+#[test]
+fn statistics_follow_frames_acknowledgements_and_corruptions() {
+    use crate::{
+        packets::{
+            frame::Frame,
+            sequence::{SequenceNumber, SequenceNumberWidth},
+        },
+        trace::PacketType,
+    };
+
+    let event = |timestamp_us: u64, node: &str, action: TraceAction| TraceEvent {
+        timestamp_us,
+        node: node.to_owned(),
+        source: action.source(),
+        direction: action.direction(),
+        action,
+        packet_type: Some(PacketType::Frame),
+        sequence_number: Some(1),
+        acknowledgement_number: None,
+        length_in_bytes: 100,
+        corrupted_bits: 0,
+        is_valid: true,
+        sequence_number_width_id: 16,
+        frame_bytes: Vec::new(),
+        acknowledgement_bytes: Vec::new(),
+    };
+    let mut statistics = DashboardStatistics::new(&[("A", 2), ("B", 2)], 1e-3);
+    statistics.update(&event(0, "A", TraceAction::SendFrame));
+    statistics.update(&TraceEvent {
+        corrupted_bits: 8,
+        ..event(100, "A->B", TraceAction::Corrupt)
+    });
+    statistics.update(&event(200, "A", TraceAction::RetransmitFrame));
+    statistics.update(&event(300, "A->B", TraceAction::Deliver));
+    statistics.update(&event(350, "A->B", TraceAction::Duplicate));
+    statistics.update(&event(380, "B->A", TraceAction::Lose));
+    // Only the payload counts toward the goodput, not the trailer of the frame
+    let frame = Frame::new(
+        &[0; 90],
+        SequenceNumber::for_frame_index(1, SequenceNumberWidth::Bits16),
+    );
+    statistics.update(&TraceEvent {
+        frame_bytes: frame.content.to_vec(),
+        ..event(400, "B", TraceAction::AcceptFrame)
+    });
+    statistics.update(&event(450, "B", TraceAction::DuplicateFrame));
+    statistics.update(&event(1_000, "A", TraceAction::AcknowledgementAdvance));
+    statistics.update(&event(2_000, "A", TraceAction::SendFrame));
+    // The receiver had no room for it yet, the frame is still outstanding
+    statistics.update(&event(2_100, "A", TraceAction::AcknowledgementRefused));
+    statistics.update(&event(2_300, "A", TraceAction::AcknowledgementFinish));
+    statistics.sample_goodput(2_300);
+
+    let endpoint_a = &statistics.endpoints[0];
+    assert_eq!(endpoint_a.acknowledged_frames, 2);
+    assert_eq!(endpoint_a.retransmission_rate(), 1.0 / 3.0);
+    assert_eq!(statistics.endpoints[1].goodput(), 90);
+    assert_eq!(statistics.channels[0].name, "A->B");
+    assert_eq!(
+        statistics.channels[0].observed_bit_error_rate(),
+        8.0 / 2400.0
+    );
+    assert_eq!(statistics.channels[1].transmitted_bits, 0);
+    assert_eq!(statistics.channels[0].duplicated_packets, 1);
+    assert_eq!(statistics.channels[1].lost_packets, 1);
+    assert_eq!(statistics.endpoints[1].discarded_frames, 1);
+    // Only the frame sent once counts, its 300us round trip falls in the 500us bucket
+    assert_eq!(statistics.rtt_histogram.iter().sum::<u64>(), 1);
+    assert_eq!(statistics.rtt_histogram[2], 1);
+    assert_eq!(statistics.elapsed(), Duration::from_micros(2_300));
+}
@@ -0,0 +1,548 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+};
+
+use crate::trace::{PacketType, TraceAction, TraceDirection, TraceEvent, TraceSource};
+
+const LANE_SPACING: usize = 400;
+const MARGIN: usize = 160;
+const HEADER_HEIGHT: usize = 60;
+const ROW_HEIGHT: usize = 26;
+const ARROW_COLORS: [&str; 4] = ["black", "green", "darkorange", "red"];
+
+/// A packet travelling from one lane to the other.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Message {
+    pub from: usize,
+    pub to: usize,
+    pub sent_us: u64,
+    /// `None` if the packet never arrived.
+    pub received_us: Option<u64>,
+    pub packet_type: Option<PacketType>,
+    pub sequence_number: Option<u32>,
+    pub acknowledgement_number: Option<u32>,
+    pub is_retransmission: bool,
+    /// Extra copy made by the channel.
+    pub is_duplicate: bool,
+    /// Overtaken in the channel by packets sent after it.
+    pub is_reordered: bool,
+    pub corrupted_bits: u32,
+    /// What the receiving side did with each part of the packet.
+    pub outcomes: Vec<TraceAction>,
+    // Parts of the packet still expected to be handled by the receiving side
+    pending_parts: usize,
+}
+
+impl Message {
+    pub fn label(&self) -> String {
+        let mut label = match (self.sequence_number, self.acknowledgement_number) {
+            (Some(sequence_number), None) => format!("Frame {}", sequence_number),
+            (Some(sequence_number), Some(acknowledgement_number)) => format!(
+                "Frame {} + {} {}",
+                sequence_number,
+                self.acknowledgement_name(),
+                acknowledgement_number
+            ),
+            (None, Some(acknowledgement_number)) => {
+                format!("{} {}", self.acknowledgement_name(), acknowledgement_number)
+            }
+            (None, None) => "Packet".to_owned(),
+        };
+        if self.is_retransmission {
+            label.push_str(" (retransmission)");
+        }
+        if self.is_duplicate {
+            label.push_str(" (duplicate)");
+        }
+        if self.is_reordered {
+            label.push_str(" (reordered)");
+        }
+        if self.corrupted_bits > 0 {
+            let _ = write!(
+                label,
+                " [{} {} corrupted]",
+                self.corrupted_bits,
+                match self.corrupted_bits {
+                    1 => "bit",
+                    _ => "bits",
+                }
+            );
+        }
+        label
+    }
+
+    fn acknowledgement_name(&self) -> &'static str {
+        match self.packet_type {
+            Some(PacketType::Nack | PacketType::FrameWithNack) => "NACK",
+            _ => "ACK",
+        }
+    }
+
+    fn is_acknowledgement_only(&self) -> bool {
+        matches!(self.packet_type, Some(PacketType::Ack | PacketType::Nack))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Step {
+    Message(Message),
+    Note {
+        lane: usize,
+        at_us: u64,
+        text: String,
+    },
+}
+
+/// Time-sequence ladder diagram between the two endpoints of a recorded transfer.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SequenceDiagram {
+    pub lanes: Vec<String>,
+    pub steps: Vec<Step>,
+}
+
+/// Note shown on the receiving lane for the actions worth pointing out.
+fn outcome_note(action: TraceAction) -> Option<&'static str> {
+    match action {
+        TraceAction::DuplicateFrame => Some("duplicate frame discarded"),
+        TraceAction::OutOfOrderFrame => Some("out-of-order frame discarded"),
+        TraceAction::RejectFrame => Some("frame rejected"),
+        TraceAction::RefuseFrame => Some("frame refused, receiver not ready"),
+        TraceAction::AcknowledgementRetransmit => Some("acknowledgement asks for retransmission"),
+        TraceAction::AcknowledgementStale => Some("stale acknowledgement ignored"),
+        TraceAction::AcknowledgementPause => Some("receiver not ready, next frame held back"),
+        TraceAction::AcknowledgementRefused => Some("receiver still not ready for the frame"),
+        _ => None,
+    }
+}
+
+impl SequenceDiagram {
+    /// Pairs every packet sent by an endpoint with its passage through the channel, when the
+    /// trace contains it, and with its handling by the other endpoint.
+    ///
+    /// The channel records packets as they leave it, so a channel event is matched with the
+    /// oldest packet in the channel that carries the same numbers, and the endpoints handle
+    /// packets in the order they left the channel. Copies made by the channel get their own
+    /// message.
+    pub fn from_events(events: &[TraceEvent]) -> Self {
+        let mut events: Vec<&TraceEvent> = events.iter().collect();
+        events.sort_by_key(|event| event.timestamp_us);
+
+        let mut lanes: Vec<String> = Vec::new();
+        for event in &events {
+            if event.source != TraceSource::Channel && !lanes.contains(&event.node) {
+                lanes.push(event.node.clone());
+            }
+        }
+        lanes.truncate(2);
+        lanes.sort();
+        let has_channel = events
+            .iter()
+            .any(|event| event.source == TraceSource::Channel);
+
+        let mut steps: Vec<Step> = Vec::new();
+        // Indices into `steps` of the messages waiting for the channel and for their receiver
+        let mut in_channel: HashMap<usize, VecDeque<usize>> = HashMap::new();
+        let mut in_flight: HashMap<usize, VecDeque<usize>> = HashMap::new();
+
+        for event in events {
+            let lane = match event.source {
+                TraceSource::Channel => event
+                    .node
+                    .split("->")
+                    .next()
+                    .and_then(|sender| lanes.iter().position(|lane| lane == sender)),
+                _ => lanes.iter().position(|lane| *lane == event.node),
+            };
+            let Some(lane) = lane else {
+                continue;
+            };
+            let other_lane = (lane + 1) % lanes.len().max(1);
+
+            match event.direction {
+                TraceDirection::Outgoing => {
+                    let message = Message {
+                        from: lane,
+                        to: other_lane,
+                        sent_us: event.timestamp_us,
+                        received_us: None,
+                        packet_type: event.packet_type,
+                        sequence_number: event.sequence_number,
+                        acknowledgement_number: event.acknowledgement_number,
+                        is_retransmission: event.action == TraceAction::RetransmitFrame,
+                        is_duplicate: false,
+                        is_reordered: false,
+                        corrupted_bits: 0,
+                        outcomes: Vec::new(),
+                        pending_parts: event.sequence_number.is_some() as usize
+                            + event.acknowledgement_number.is_some() as usize,
+                    };
+                    match has_channel {
+                        true => in_channel.entry(lane).or_default(),
+                        false => in_flight.entry(other_lane).or_default(),
+                    }
+                    .push_back(steps.len());
+                    steps.push(Step::Message(message));
+                }
+                TraceDirection::InTransit if event.action == TraceAction::Duplicate => {
+                    in_flight
+                        .entry(other_lane)
+                        .or_default()
+                        .push_back(steps.len());
+                    steps.push(Step::Message(Message {
+                        from: lane,
+                        to: other_lane,
+                        sent_us: event.timestamp_us,
+                        received_us: None,
+                        packet_type: event.packet_type,
+                        sequence_number: event.sequence_number,
+                        acknowledgement_number: event.acknowledgement_number,
+                        is_retransmission: false,
+                        is_duplicate: true,
+                        is_reordered: false,
+                        corrupted_bits: event.corrupted_bits,
+                        outcomes: Vec::new(),
+                        pending_parts: event.sequence_number.is_some() as usize
+                            + event.acknowledgement_number.is_some() as usize,
+                    }));
+                }
+                TraceDirection::InTransit => {
+                    let queue = in_channel.entry(lane).or_default();
+                    let position = queue
+                        .iter()
+                        .position(|&index| {
+                            matches!(&steps[index], Step::Message(message)
+                                if message.sequence_number == event.sequence_number
+                                    && message.acknowledgement_number
+                                        == event.acknowledgement_number)
+                        })
+                        .unwrap_or(0);
+                    let Some(index) = queue.remove(position) else {
+                        continue;
+                    };
+                    // A lost message is never received, and drawn as such
+                    if event.action == TraceAction::Lose {
+                        continue;
+                    }
+                    if let Step::Message(message) = &mut steps[index] {
+                        message.corrupted_bits = event.corrupted_bits;
+                        message.is_reordered = event.action == TraceAction::Reorder;
+                        in_flight.entry(message.to).or_default().push_back(index);
+                    }
+                }
+                TraceDirection::Incoming => {
+                    let queue = in_flight.entry(lane).or_default();
+                    let Some(&index) = queue.front() else {
+                        continue;
+                    };
+                    let Step::Message(message) = &mut steps[index] else {
+                        continue;
+                    };
+                    message.received_us.get_or_insert(event.timestamp_us);
+                    message.outcomes.push(event.action);
+                    message.pending_parts = message.pending_parts.saturating_sub(1);
+                    if message.pending_parts == 0 {
+                        queue.pop_front();
+                    }
+                    if let Some(note) = outcome_note(event.action) {
+                        steps.push(Step::Note {
+                            lane,
+                            at_us: event.timestamp_us,
+                            text: note.to_owned(),
+                        });
+                    }
+                }
+                TraceDirection::Local => steps.push(Step::Note {
+                    lane,
+                    at_us: event.timestamp_us,
+                    text: "retransmission timeout".to_owned(),
+                }),
+            }
+        }
+
+        Self { lanes, steps }
+    }
+
+    fn lane_name(&self, lane: usize) -> &str {
+        self.lanes.get(lane).map(String::as_str).unwrap_or("?")
+    }
+
+    pub fn to_mermaid(&self) -> String {
+        let mut mermaid = String::from("sequenceDiagram\n");
+        for lane in &self.lanes {
+            let _ = writeln!(mermaid, "    participant {}", lane);
+        }
+        for step in &self.steps {
+            let _ = match step {
+                Step::Message(message) => {
+                    let arrow = match (message.received_us, message.corrupted_bits) {
+                        (None, _) | (_, 1..) => "-x",
+                        _ if message.is_acknowledgement_only() => "-->>",
+                        _ => "->>",
+                    };
+                    writeln!(
+                        mermaid,
+                        "    {}{}{}: {}",
+                        self.lane_name(message.from),
+                        arrow,
+                        self.lane_name(message.to),
+                        message.label()
+                    )
+                }
+                Step::Note { lane, text, .. } => {
+                    writeln!(mermaid, "    Note over {}: {}", self.lane_name(*lane), text)
+                }
+            };
+        }
+        mermaid
+    }
+
+    pub fn to_plantuml(&self) -> String {
+        let mut plantuml = String::from("@startuml\n");
+        for lane in &self.lanes {
+            let _ = writeln!(plantuml, "participant {}", lane);
+        }
+        for step in &self.steps {
+            let _ = match step {
+                Step::Message(message) => {
+                    let arrow = match (message.received_us, message.corrupted_bits) {
+                        (None, _) => "->x",
+                        (_, 1..) => "-[#red]>",
+                        _ if message.is_acknowledgement_only() => "-->",
+                        _ => "->",
+                    };
+                    writeln!(
+                        plantuml,
+                        "{} {} {} : {}",
+                        self.lane_name(message.from),
+                        arrow,
+                        self.lane_name(message.to),
+                        message.label()
+                    )
+                }
+                Step::Note { lane, text, .. } => {
+                    writeln!(plantuml, "note over {} : {}", self.lane_name(*lane), text)
+                }
+            };
+        }
+        plantuml.push_str("@enduml\n");
+        plantuml
+    }
+
+    /// Draws the lanes vertically with time flowing downwards. Rows follow the order of the
+    /// events rather than their exact timing, so that fast exchanges stay readable.
+    pub fn to_svg(&self) -> String {
+        let mut instants: Vec<u64> = self
+            .steps
+            .iter()
+            .flat_map(|step| match step {
+                Step::Message(message) => vec![Some(message.sent_us), message.received_us],
+                Step::Note { at_us, .. } => vec![Some(*at_us)],
+            })
+            .flatten()
+            .collect();
+        instants.sort_unstable();
+        instants.dedup();
+        let row_of = |at_us: u64| {
+            HEADER_HEIGHT + ROW_HEIGHT * (instants.partition_point(|&instant| instant < at_us) + 1)
+        };
+        let lane_x = |lane: usize| MARGIN + lane * LANE_SPACING;
+        let width = 2 * MARGIN + LANE_SPACING * self.lanes.len().saturating_sub(1);
+        let height = HEADER_HEIGHT + ROW_HEIGHT * (instants.len() + 2);
+
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" font-family="monospace" font-size="12">"#
+        );
+        svg.push_str("<defs>\n");
+        for color in ARROW_COLORS {
+            let _ = writeln!(
+                svg,
+                r#"<marker id="arrow-{color}" viewBox="0 0 10 10" refX="10" refY="5" markerWidth="8" markerHeight="8" orient="auto"><path d="M 0 0 L 10 5 L 0 10 z" fill="{color}"/></marker>"#
+            );
+        }
+        svg.push_str("</defs>\n");
+        let _ = writeln!(
+            svg,
+            r#"<rect width="{width}" height="{height}" fill="white"/>"#
+        );
+        for (lane, name) in self.lanes.iter().enumerate() {
+            let x = lane_x(lane);
+            let _ = writeln!(
+                svg,
+                r#"<text x="{x}" y="{}" text-anchor="middle" font-size="16" font-weight="bold">{}</text>"#,
+                HEADER_HEIGHT - 20,
+                escape_xml(name)
+            );
+            let _ = writeln!(
+                svg,
+                r#"<line x1="{x}" y1="{}" x2="{x}" y2="{}" stroke="gray" stroke-width="2"/>"#,
+                HEADER_HEIGHT - 10,
+                height - ROW_HEIGHT
+            );
+        }
+
+        for step in &self.steps {
+            match step {
+                Step::Message(message) => {
+                    let (x1, y1) = (lane_x(message.from), row_of(message.sent_us));
+                    let color = match message.packet_type {
+                        _ if message.corrupted_bits > 0 || message.received_us.is_none() => "red",
+                        Some(PacketType::Nack) => "darkorange",
+                        Some(PacketType::Ack) => "green",
+                        _ => "black",
+                    };
+                    let dash = match message.is_acknowledgement_only() {
+                        true => r#" stroke-dasharray="6 3""#,
+                        false => "",
+                    };
+                    let (x2, y2) = match message.received_us {
+                        Some(received_us) => (lane_x(message.to), row_of(received_us)),
+                        // Lost packets stop half way
+                        None => ((x1 + lane_x(message.to)) / 2, y1 + ROW_HEIGHT / 2),
+                    };
+                    let _ = writeln!(
+                        svg,
+                        r#"<line x1="{x1}" y1="{y1}" x2="{x2}" y2="{y2}" stroke="{color}" stroke-width="1.5"{dash} marker-end="url(#arrow-{color})"/>"#
+                    );
+                    let _ = writeln!(
+                        svg,
+                        r#"<text x="{}" y="{}" text-anchor="middle" fill="{color}">{}</text>"#,
+                        (x1 + x2) / 2,
+                        (y1 + y2) / 2 - 4,
+                        escape_xml(&message.label())
+                    );
+                }
+                Step::Note { lane, at_us, text } => {
+                    let (x, anchor) = match lane {
+                        0 => (lane_x(*lane) - 8, "end"),
+                        _ => (lane_x(*lane) + 8, "start"),
+                    };
+                    let _ = writeln!(
+                        svg,
+                        r#"<text x="{x}" y="{}" text-anchor="{anchor}" fill="dimgray" font-style="italic">{}</text>"#,
+                        row_of(*at_us) + 4,
+                        escape_xml(text)
+                    );
+                }
+            }
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[test]
+fn corrupted_frame_is_drawn_with_its_rejection_and_retransmission() {
+    use crate::{
+        packets::{
+            GenericPacket, Packet,
+            acknowledgement::{GenericAcknowledgement, ack::ACK, nack::NACK},
+            frame::Frame,
+            sequence::{SequenceNumber, SequenceNumberWidth},
+        },
+        trace::Tracer,
+    };
+
+    let width = SequenceNumberWidth::Bits8;
+    let frame = Frame::new(b"data", SequenceNumber::new(1, width));
+    let corrupted_frame = frame.with_flipped_bits(&[0]);
+    let nack = GenericAcknowledgement::NACK(NACK::new(SequenceNumber::new(1, width)));
+    let ack = GenericAcknowledgement::ACK(ACK::new(SequenceNumber::new(2, width)));
+
+    let (tracer, events) = Tracer::new();
+    tracer.record("A", TraceAction::SendFrame, Some(&frame), None, true);
+    tracer.record_transit(
+        "A->B",
+        &GenericPacket::Frame(frame.clone()),
+        &GenericPacket::Frame(corrupted_frame.clone()),
+    );
+    tracer.record(
+        "B",
+        TraceAction::RejectFrame,
+        Some(&corrupted_frame),
+        None,
+        false,
+    );
+    tracer.record(
+        "B",
+        TraceAction::SendAcknowledgement,
+        None,
+        Some(&nack),
+        true,
+    );
+    tracer.record_transit(
+        "B->A",
+        &GenericPacket::Acknowledgement(nack),
+        &GenericPacket::Acknowledgement(nack),
+    );
+    tracer.record(
+        "A",
+        TraceAction::AcknowledgementRetransmit,
+        None,
+        Some(&nack),
+        true,
+    );
+    tracer.record("A", TraceAction::RetransmitFrame, Some(&frame), None, true);
+    tracer.record_transit(
+        "A->B",
+        &GenericPacket::Frame(frame.clone()),
+        &GenericPacket::Frame(frame.clone()),
+    );
+    tracer.record("B", TraceAction::AcceptFrame, Some(&frame), None, true);
+    tracer.record(
+        "B",
+        TraceAction::SendAcknowledgement,
+        None,
+        Some(&ack),
+        true,
+    );
+    tracer.record(
+        "A",
+        TraceAction::RetransmissionTimeout,
+        Some(&frame),
+        None,
+        true,
+    );
+    drop(tracer);
+    let events: Vec<TraceEvent> = events.iter().collect();
+
+    let diagram = SequenceDiagram::from_events(&events);
+    assert_eq!(diagram.lanes, ["A", "B"]);
+    let Step::Message(first_frame) = &diagram.steps[0] else {
+        panic!("First step is the frame sent by A");
+    };
+    assert_eq!(first_frame.corrupted_bits, 1);
+    assert_eq!(first_frame.outcomes, [TraceAction::RejectFrame]);
+
+    assert_eq!(
+        diagram.to_mermaid(),
+        "sequenceDiagram
+    participant A
+    participant B
+    A-xB: Frame 1 [1 bit corrupted]
+    Note over B: frame rejected
+    B-->>A: NACK 1
+    Note over A: acknowledgement asks for retransmission
+    A->>B: Frame 1 (retransmission)
+    B-xA: ACK 2
+    Note over A: retransmission timeout
+"
+    );
+    assert!(
+        diagram
+            .to_plantuml()
+            .contains("A -[#red]> B : Frame 1 [1 bit corrupted]")
+    );
+    let svg = diagram.to_svg();
+    assert!(svg.starts_with("<svg") && svg.ends_with("</svg>\n"));
+    assert_eq!(svg.matches("<line").count(), 2 + 4);
+}
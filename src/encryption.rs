@@ -0,0 +1,177 @@
+use chacha20poly1305::{
+    KeyInit, XChaCha20Poly1305, XNonce,
+    aead::{Aead, Payload},
+};
+use rand::Rng;
+
+use crate::{
+    error::{DecodeError, Error, Result},
+    packets::{frame::Frame, sequence::SequenceNumber},
+};
+
+pub const SALT_LENGTH_IN_BYTES: usize = 16;
+pub const TAG_LENGTH_IN_BYTES: usize = 16;
+pub const KEY_LENGTH_IN_BYTES: usize = 32;
+
+/// Protection applied to the payload of every data frame.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Encryption {
+    None,
+    /// XChaCha20-Poly1305 keyed with a pre-shared key. The salt is chosen randomly by the
+    /// sender for every session so that nonces are never reused under the same key.
+    XChaCha20Poly1305 {
+        salt: [u8; SALT_LENGTH_IN_BYTES],
+    },
+}
+
+impl Encryption {
+    pub fn random_xchacha20poly1305() -> Self {
+        let mut salt = [0u8; SALT_LENGTH_IN_BYTES];
+        rand::rng().fill(&mut salt);
+        Encryption::XChaCha20Poly1305 { salt }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey([u8; KEY_LENGTH_IN_BYTES]);
+
+impl PreSharedKey {
+    pub const fn new(key: [u8; KEY_LENGTH_IN_BYTES]) -> Self {
+        Self(key)
+    }
+
+    /// Parses a key written as 64 hexadecimal characters.
+    pub fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.trim();
+        if hex.len() != KEY_LENGTH_IN_BYTES * 2 || !hex.is_ascii() {
+            return None;
+        }
+        let mut key = [0u8; KEY_LENGTH_IN_BYTES];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+        }
+        Some(Self(key))
+    }
+}
+
+/// Seals and opens data frames of one session.
+///
+/// A sealed frame is laid out as `ciphertext | tag | sequence number`: the Poly1305 tag replaces
+/// the CRC, and the session header together with the sequence number are authenticated as
+/// associated data. The nonce is the session salt followed by the index of the frame in the
+/// transfer, which both sides derive from the sequence number.
+#[derive(Clone)]
+pub struct SessionCipher {
+    cipher: XChaCha20Poly1305,
+    salt: [u8; SALT_LENGTH_IN_BYTES],
+    session_header: Vec<u8>,
+}
+
+impl SessionCipher {
+    /// Returns `None` when the session does not use encryption.
+    pub fn new(key: &PreSharedKey, encryption: Encryption, session_header: &[u8]) -> Option<Self> {
+        match encryption {
+            Encryption::None => None,
+            Encryption::XChaCha20Poly1305 { salt } => Some(Self {
+                cipher: XChaCha20Poly1305::new(&key.0.into()),
+                salt,
+                session_header: session_header.to_vec(),
+            }),
+        }
+    }
+
+    fn nonce(&self, frame_index: u64) -> XNonce {
+        let mut nonce = [0u8; 24];
+        nonce[..SALT_LENGTH_IN_BYTES].copy_from_slice(&self.salt);
+        nonce[SALT_LENGTH_IN_BYTES..].copy_from_slice(&frame_index.to_be_bytes());
+        nonce.into()
+    }
+
+    fn associated_data(&self, sequence_number_bytes: &[u8]) -> Vec<u8> {
+        let mut associated_data =
+            Vec::with_capacity(self.session_header.len() + sequence_number_bytes.len());
+        associated_data.extend_from_slice(&self.session_header);
+        associated_data.extend_from_slice(sequence_number_bytes);
+        associated_data
+    }
+
+    pub fn seal(&self, frame_index: u64, payload: &[u8], sequence_number: SequenceNumber) -> Frame {
+        let sequence_number_bytes = sequence_number.to_bytes();
+        let mut content = self
+            .cipher
+            .encrypt(
+                &self.nonce(frame_index),
+                Payload {
+                    msg: payload,
+                    aad: &self.associated_data(&sequence_number_bytes),
+                },
+            )
+            .expect("Encrypting into a Vec should not fail");
+        content.extend_from_slice(&sequence_number_bytes);
+        Frame {
+            content: content.into(),
+            sequence_number_width: sequence_number.width(),
+        }
+    }
+
+    /// Returns the decrypted payload if the tag matches, [`Error::ChecksumMismatch`] if the
+    /// frame was corrupted or forged.
+    pub fn open(&self, frame_index: u64, frame: &Frame) -> Result<Vec<u8>> {
+        let sequence_number_length = frame.sequence_number_width.length_in_bytes();
+        let minimum_length_in_bytes = TAG_LENGTH_IN_BYTES + sequence_number_length;
+        if frame.content.len() < minimum_length_in_bytes {
+            return Err(Error::Decode(DecodeError::TruncatedFrame {
+                length_in_bytes: frame.content.len(),
+                minimum_length_in_bytes,
+            }));
+        }
+        let (sealed_payload, sequence_number_bytes) = frame
+            .content
+            .split_at(frame.content.len() - sequence_number_length);
+        self.cipher
+            .decrypt(
+                &self.nonce(frame_index),
+                Payload {
+                    msg: sealed_payload,
+                    aad: &self.associated_data(sequence_number_bytes),
+                },
+            )
+            .map_err(|_| Error::ChecksumMismatch)
+    }
+}
+
+#[test]
+fn sealed_frames_only_open_with_matching_key_index_and_content() {
+    use crate::packets::{Packet, sequence::SequenceNumberWidth};
+
+    let key = PreSharedKey::new([7; KEY_LENGTH_IN_BYTES]);
+    let encryption = Encryption::random_xchacha20poly1305();
+    let cipher = SessionCipher::new(&key, encryption, b"header").unwrap();
+    let frame = cipher.seal(
+        3,
+        b"secret payload",
+        SequenceNumber::for_frame_index(3, SequenceNumberWidth::Bits16),
+    );
+
+    assert_eq!(
+        cipher.open(3, &frame).ok(),
+        Some(b"secret payload".to_vec())
+    );
+    assert!(matches!(
+        cipher.open(4, &frame),
+        Err(Error::ChecksumMismatch)
+    ));
+
+    let tampered_frame = frame.with_flipped_bits(&[(frame.content.len() - 1) * 8]);
+    assert!(matches!(
+        cipher.open(3, &tampered_frame),
+        Err(Error::ChecksumMismatch)
+    ));
+
+    let other_key = PreSharedKey::new([8; KEY_LENGTH_IN_BYTES]);
+    let other_cipher = SessionCipher::new(&other_key, encryption, b"header").unwrap();
+    assert!(matches!(
+        other_cipher.open(3, &frame),
+        Err(Error::ChecksumMismatch)
+    ));
+}
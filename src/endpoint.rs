@@ -0,0 +1,1002 @@
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    sync::mpsc::{self, RecvTimeoutError, TrySendError},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    adaptive::AdaptiveFrameSource,
+    encryption::{PreSharedKey, SessionCipher},
+    error::{DecodeError, Error, Result},
+    message::{build_session_cipher, open_frame, reassemble_message},
+    packets::{
+        GenericPacket, Packet,
+        acknowledgement::{GenericAcknowledgement, ack::ACK, nack::NACK},
+        frame::Frame,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+    session::SessionParameters,
+    trace::{TraceAction, Tracer},
+};
+
+/// What the transmitter has to do after inspecting an acknowledgement.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TransmitterAction {
+    SendNextFrame,
+    /// Send the current frame again, or for the first time if the receiver held it back.
+    RetransmitFrame,
+    /// The acknowledgement is stale, keep waiting for the one of the current frame.
+    WaitForAcknowledgement,
+    /// The current frame is acknowledged but the receiver is not ready for the next one, hold
+    /// it back until an acknowledgement tells otherwise.
+    HoldNextFrame,
+    /// The receiver is still not ready for the current frame. The retransmission timer still
+    /// sends it to probe the receiver.
+    WaitForReceiver,
+    Finished,
+}
+
+/// What the receiver did with a frame.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FrameOutcome {
+    Accepted,
+    Duplicate,
+    OutOfOrder,
+    /// The frame failed its checksum or authentication and is rejected with a NACK.
+    Rejected,
+    /// The frame is intact but the receiver buffers too much to take it, it is answered with a
+    /// not-ready ACK.
+    Refused,
+}
+
+/// Sending half of the alternating-bit protocol.
+#[derive(Clone)]
+pub struct TransmitterStateMachine {
+    frames_to_transmit: VecDeque<Frame>,
+    expected_sequence_number: SequenceNumber,
+    frames_transmitted: usize,
+    total_number_of_frames_to_transmit: usize,
+    stale_acknowledgements: usize,
+    is_paused: bool,
+    /// Cuts the next frame once the current one is acknowledged, in adaptive mode.
+    frame_source: Option<AdaptiveFrameSource>,
+}
+
+impl TransmitterStateMachine {
+    pub fn new(frames_to_transmit: VecDeque<Frame>) -> Self {
+        let sequence_number_width = frames_to_transmit
+            .front()
+            .map(|frame| frame.sequence_number_width)
+            .unwrap_or(SequenceNumberWidth::AlternatingBit);
+        Self {
+            total_number_of_frames_to_transmit: frames_to_transmit.len(),
+            frames_to_transmit,
+            expected_sequence_number: SequenceNumber::zero(sequence_number_width).next(),
+            frames_transmitted: 0,
+            stale_acknowledgements: 0,
+            is_paused: false,
+            frame_source: None,
+        }
+    }
+
+    /// Transmitter of the frames cut one at a time by `frame_source`, with payload lengths
+    /// adapted to the outcome of the previous tries.
+    pub fn adaptive(mut frame_source: AdaptiveFrameSource) -> Self {
+        let mut transmitter = Self::new(frame_source.next_frame().into_iter().collect());
+        transmitter.total_number_of_frames_to_transmit += frame_source.remaining_frames();
+        transmitter.frame_source = Some(frame_source);
+        transmitter
+    }
+
+    pub fn current_frame(&self) -> Option<&Frame> {
+        self.frames_to_transmit.front()
+    }
+
+    /// Appends a frame numbered after the last one, for senders that produce the message as
+    /// it goes.
+    pub fn push_frame(&mut self, frame: Frame) {
+        self.frames_to_transmit.push_back(frame);
+        self.total_number_of_frames_to_transmit += 1;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frames_to_transmit.is_empty()
+    }
+
+    /// Number of acknowledged frames and total number of frames, only an estimate of the
+    /// latter in adaptive mode.
+    pub fn progress(&self) -> (usize, usize) {
+        (
+            self.frames_transmitted,
+            self.total_number_of_frames_to_transmit,
+        )
+    }
+
+    /// Valid acknowledgements that did not refer to the current frame and were ignored.
+    pub fn stale_acknowledgements(&self) -> usize {
+        self.stale_acknowledgements
+    }
+
+    /// Payload length of the next frames in adaptive mode.
+    pub fn payload_length_in_bytes(&self) -> Option<usize> {
+        self.frame_source
+            .as_ref()
+            .map(AdaptiveFrameSource::payload_length_in_bytes)
+    }
+
+    /// Counts the expiry of the retransmission timer as a failed try, unless the frame is held
+    /// back and the timer only probes the receiver.
+    pub fn handle_retransmission_timeout(&mut self) {
+        if !self.is_paused {
+            self.record_try(false);
+        }
+    }
+
+    fn record_try(&mut self, is_success: bool) {
+        if let Some(frame_source) = &mut self.frame_source
+            && let Some(frame) = self.frames_to_transmit.front()
+        {
+            frame_source.record_try(frame.content.len(), is_success);
+        }
+    }
+
+    /// Whether the receiver asked to hold the current frame back.
+    pub fn is_paused(&self) -> bool {
+        self.is_paused
+    }
+
+    pub fn handle_acknowledgement(
+        &mut self,
+        acknowledgement: &GenericAcknowledgement,
+    ) -> TransmitterAction {
+        if self.is_finished() {
+            log::debug!("Transmission already finished, discarding acknowledgement silently...");
+            return TransmitterAction::Finished;
+        }
+        if !acknowledgement.is_valid() {
+            log::debug!("Acknowledgement packet is invalid - Retrying same packet");
+            self.record_try(false);
+            return TransmitterAction::RetransmitFrame;
+        }
+        // An acknowledgement that does not refer to the current frame answers a duplicate or
+        // a late retransmission. Acting on it would answer every duplicate with another one.
+        let current_sequence_number = self.expected_sequence_number.previous();
+        match acknowledgement {
+            GenericAcknowledgement::ACK(ack) => {
+                let acknowledged_sequence_number = ack.get_ack_and_sequence_number().1;
+                if acknowledged_sequence_number == self.expected_sequence_number {
+                    log::debug!(
+                        "Packet is a valid ACK for frame {} - Moving on to next packet",
+                        current_sequence_number
+                    );
+                    self.frames_transmitted += 1;
+                    self.record_try(true);
+                    self.frames_to_transmit.pop_front();
+                    if let Some(frame_source) = &mut self.frame_source {
+                        self.frames_to_transmit.extend(frame_source.next_frame());
+                        self.total_number_of_frames_to_transmit = self.frames_transmitted
+                            + self.frames_to_transmit.len()
+                            + frame_source.remaining_frames();
+                    }
+                    self.expected_sequence_number = self.expected_sequence_number.next();
+                    self.is_paused = !ack.is_ready();
+                    match (self.is_finished(), self.is_paused) {
+                        (true, _) => TransmitterAction::Finished,
+                        (false, true) => {
+                            log::debug!("Receiver is not ready - Holding the next packet back");
+                            TransmitterAction::HoldNextFrame
+                        }
+                        (false, false) => TransmitterAction::SendNextFrame,
+                    }
+                } else if acknowledged_sequence_number == current_sequence_number
+                    && (self.is_paused || !ack.is_ready())
+                {
+                    // The receiver still expects the current frame, and tells whether it takes
+                    // it now, which makes the acknowledgement anything but stale
+                    let was_paused = std::mem::replace(&mut self.is_paused, !ack.is_ready());
+                    match (was_paused, self.is_paused) {
+                        (true, false) => {
+                            log::debug!("Receiver is ready again - Sending packet");
+                            TransmitterAction::RetransmitFrame
+                        }
+                        _ => {
+                            log::debug!(
+                                "Receiver is not ready for frame {} - Holding it back",
+                                current_sequence_number
+                            );
+                            TransmitterAction::WaitForReceiver
+                        }
+                    }
+                } else {
+                    log::debug!(
+                        "Received stale ACK {} while expecting {}, discarding it silently...",
+                        acknowledged_sequence_number,
+                        self.expected_sequence_number
+                    );
+                    self.stale_acknowledgements += 1;
+                    TransmitterAction::WaitForAcknowledgement
+                }
+            }
+            GenericAcknowledgement::NACK(nack) => {
+                let rejected_sequence_number = nack.get_ack_and_sequence_number().1;
+                if rejected_sequence_number == current_sequence_number {
+                    log::debug!(
+                        "Packet is a valid NACK for frame {} - Retrying same packet",
+                        current_sequence_number
+                    );
+                    self.record_try(false);
+                    TransmitterAction::RetransmitFrame
+                } else {
+                    log::debug!(
+                        "Received stale NACK {} while sending {}, discarding it silently...",
+                        rejected_sequence_number,
+                        current_sequence_number
+                    );
+                    self.stale_acknowledgements += 1;
+                    TransmitterAction::WaitForAcknowledgement
+                }
+            }
+        }
+    }
+}
+
+/// Receiving half of the alternating-bit protocol.
+///
+/// The first accepted frame carries the session parameters, an accepted frame with an empty
+/// payload marks the end of the stream.
+///
+/// With a buffer limit, the receiver stops taking frames once the payloads not taken yet reach
+/// it, and tells the transmitter to hold on with not-ready ACKs until they are taken, by the
+/// consumer or by [`ReceiverStateMachine::deliver_payloads`].
+#[derive(Clone)]
+pub struct ReceiverStateMachine {
+    pre_shared_key: Option<PreSharedKey>,
+    session_cipher: Option<SessionCipher>,
+    current_expected_package: SequenceNumber,
+    received_frame_count: usize,
+    received_payloads: Vec<Bytes>,
+    buffered_length_in_bytes: usize,
+    buffer_limit_in_bytes: Option<NonZeroUsize>,
+    payload_sink: Option<mpsc::SyncSender<Bytes>>,
+    duplicate_frames: usize,
+    out_of_order_frames: usize,
+    is_finished: bool,
+}
+
+impl ReceiverStateMachine {
+    pub fn new(
+        pre_shared_key: Option<PreSharedKey>,
+        sequence_number_width: SequenceNumberWidth,
+    ) -> Self {
+        Self {
+            pre_shared_key,
+            session_cipher: None,
+            current_expected_package: SequenceNumber::zero(sequence_number_width),
+            received_frame_count: 0,
+            received_payloads: Vec::new(),
+            buffered_length_in_bytes: 0,
+            buffer_limit_in_bytes: None,
+            payload_sink: None,
+            duplicate_frames: 0,
+            out_of_order_frames: 0,
+            is_finished: false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.is_finished
+    }
+
+    /// Refuses frames while the payloads not taken with [`ReceiverStateMachine::take_payloads`]
+    /// add up to `buffer_limit_in_bytes`, which must then be taken for the transfer to go on.
+    /// `None` takes every frame.
+    pub fn set_buffer_limit(&mut self, buffer_limit_in_bytes: Option<NonZeroUsize>) {
+        self.buffer_limit_in_bytes = buffer_limit_in_bytes;
+    }
+
+    /// Whether the next frame would be taken.
+    pub fn is_ready(&self) -> bool {
+        self.buffer_limit_in_bytes
+            .is_none_or(|limit| self.buffered_length_in_bytes < limit.get())
+    }
+
+    /// Length of the payloads not taken yet.
+    pub fn buffered_length_in_bytes(&self) -> usize {
+        self.buffered_length_in_bytes
+    }
+
+    pub fn has_payloads(&self) -> bool {
+        !self.received_payloads.is_empty()
+    }
+
+    /// Hands the payloads over to `payload_sink` with
+    /// [`ReceiverStateMachine::deliver_payloads`], the session header first, for a consumer
+    /// such as a disk writer running on its own thread. The bound of the channel and the buffer
+    /// limit then cap what is held in memory.
+    pub fn set_payload_sink(&mut self, payload_sink: mpsc::SyncSender<Bytes>) {
+        self.payload_sink = Some(payload_sink);
+    }
+
+    /// Whether payloads wait for room in the payload sink.
+    pub fn has_undelivered_payloads(&self) -> bool {
+        self.payload_sink.is_some() && self.has_payloads()
+    }
+
+    /// Moves the payloads that fit into the payload sink, if any.
+    ///
+    /// Fails with [`Error::ChannelClosed`] if the consumer has gone away.
+    pub fn deliver_payloads(&mut self) -> Result<()> {
+        let Some(payload_sink) = &self.payload_sink else {
+            return Ok(());
+        };
+        let mut delivered_payloads = 0;
+        for payload in &self.received_payloads {
+            match payload_sink.try_send(payload.clone()) {
+                Ok(()) => delivered_payloads += 1,
+                Err(TrySendError::Full(_)) => break,
+                Err(TrySendError::Disconnected(_)) => return Err(Error::ChannelClosed),
+            }
+        }
+        for payload in self.received_payloads.drain(..delivered_payloads) {
+            self.buffered_length_in_bytes -= payload.len();
+        }
+        Ok(())
+    }
+
+    /// Waits for the payload sink to take the remaining payloads, then closes it so that the
+    /// consumer sees the end of the message.
+    pub fn finish_delivery(&mut self) -> Result<()> {
+        let Some(payload_sink) = self.payload_sink.take() else {
+            return Ok(());
+        };
+        for payload in self.take_payloads() {
+            payload_sink
+                .send(payload)
+                .map_err(|_| Error::ChannelClosed)?;
+        }
+        Ok(())
+    }
+
+    /// ACK of the next expected frame, not ready if the receiver is not.
+    pub fn acknowledgement(&self) -> GenericAcknowledgement {
+        GenericAcknowledgement::ACK(match self.is_ready() {
+            true => ACK::new(self.current_expected_package),
+            false => ACK::not_ready(self.current_expected_package),
+        })
+    }
+
+    pub fn received_frame_count(&self) -> usize {
+        self.received_frame_count
+    }
+
+    /// Valid frames that were already accepted before.
+    pub fn duplicate_frames(&self) -> usize {
+        self.duplicate_frames
+    }
+
+    /// Valid frames numbered ahead of the expected one, only detectable with numbered
+    /// sequences.
+    pub fn out_of_order_frames(&self) -> usize {
+        self.out_of_order_frames
+    }
+
+    /// Validates `received_frame` and returns the acknowledgement to send back.
+    ///
+    /// Corrupted frames are answered with a NACK. Only an intact session header that cannot be
+    /// honoured is an error, as no retransmission can fix it.
+    pub fn handle_frame(
+        &mut self,
+        received_frame: &Frame,
+    ) -> Result<(GenericAcknowledgement, FrameOutcome)> {
+        let start_processing_time = Instant::now();
+        // A truncated frame has no sequence number, it is rejected as the expected one
+        let received_sequence_number = received_frame
+            .get_payload_and_checksum_and_sequence_number()
+            .map_or(self.current_expected_package, |(_, _, sequence_number)| {
+                sequence_number
+            });
+        let is_expected_frame = received_sequence_number == self.current_expected_package;
+        // A duplicate can only be the previous frame, which decides the nonce to open it with
+        let frame_index = match is_expected_frame {
+            true => self.received_frame_count,
+            false => self.received_frame_count.saturating_sub(1),
+        } as u64;
+        let received_payload =
+            open_frame(received_frame, frame_index, self.session_cipher.as_ref());
+        log::debug!(
+            "Received frame - finished processing, took {:?} - Valid {}",
+            start_processing_time.elapsed(),
+            received_payload.is_ok()
+        );
+
+        let Ok(received_payload) = received_payload else {
+            // A numbered sequence number is covered by the failed checksum, so it cannot be
+            // trusted and the expected one is rejected instead
+            let rejected_sequence_number = match received_sequence_number.width() {
+                SequenceNumberWidth::AlternatingBit => received_sequence_number,
+                _ => self.current_expected_package,
+            };
+            log::debug!("Sending NACK for frame {}", rejected_sequence_number);
+            return Ok((
+                GenericAcknowledgement::NACK(NACK::new(rejected_sequence_number)),
+                FrameOutcome::Rejected,
+            ));
+        };
+
+        let outcome = if is_expected_frame && !self.is_ready() {
+            log::debug!(
+                "Buffering {} B, refusing frame {}",
+                self.buffered_length_in_bytes,
+                received_sequence_number
+            );
+            FrameOutcome::Refused
+        } else if is_expected_frame {
+            if self.received_frame_count == 0 {
+                let session_parameters = SessionParameters::from_bytes(&received_payload)?;
+                if session_parameters.sequence_number_width != self.current_expected_package.width()
+                {
+                    return Err(Error::Decode(DecodeError::SequenceNumberWidthMismatch {
+                        announced: session_parameters.sequence_number_width,
+                        expected: self.current_expected_package.width(),
+                    }));
+                }
+                log::info!("Accepted session {:?}", session_parameters);
+                self.session_cipher =
+                    build_session_cipher(&session_parameters, self.pre_shared_key.as_ref())?;
+            } else if received_payload.is_empty() {
+                log::info!("Received end of stream");
+                self.is_finished = true;
+            }
+            self.current_expected_package = self.current_expected_package.next();
+            self.received_frame_count += 1;
+            self.buffered_length_in_bytes += received_payload.len();
+            self.received_payloads.push(received_payload);
+            FrameOutcome::Accepted
+        } else if received_sequence_number.distance_from(self.current_expected_package) > 0 {
+            log::warn!(
+                "Received out-of-order frame {} while expecting {}, discarding it...",
+                received_sequence_number,
+                self.current_expected_package
+            );
+            self.out_of_order_frames += 1;
+            FrameOutcome::OutOfOrder
+        } else {
+            log::debug!(
+                "Received duplicate frame {}, discarding it silently...",
+                received_sequence_number
+            );
+            self.duplicate_frames += 1;
+            FrameOutcome::Duplicate
+        };
+        log::debug!(
+            "Sending ACK {} for packet {}",
+            self.current_expected_package,
+            self.received_frame_count
+        );
+        Ok((self.acknowledgement(), outcome))
+    }
+
+    /// Hands over the payloads accepted since the last call, the session header first, for
+    /// consumers that deliver the message as it arrives. Taken payloads are left out of
+    /// [`ReceiverStateMachine::into_message`].
+    pub fn take_payloads(&mut self) -> Vec<Bytes> {
+        self.buffered_length_in_bytes = 0;
+        std::mem::take(&mut self.received_payloads)
+    }
+
+    /// Reassembles the received payloads into the original message.
+    pub fn into_message(self) -> Result<Vec<u8>> {
+        reassemble_message(&self.received_payloads)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EndpointTimers {
+    /// How long an acknowledgement waits for an outgoing frame to ride on.
+    pub delayed_acknowledgement: Duration,
+    /// How long the transmitter waits for an acknowledgement before sending the frame again.
+    pub retransmission: Duration,
+    /// How long an endpoint keeps answering retransmissions once both directions are complete.
+    pub linger: Duration,
+    /// How long the transfer may go without hearing from the peer, `None` waits forever.
+    pub idle: Option<Duration>,
+}
+
+impl Default for EndpointTimers {
+    fn default() -> Self {
+        Self {
+            delayed_acknowledgement: Duration::from_millis(10),
+            retransmission: Duration::from_millis(500),
+            linger: Duration::from_millis(200),
+            idle: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+pub type TimestampedPacket = (GenericPacket, Instant);
+
+/// Runs one side of a full-duplex transfer, sending the frames of `transmitter` while feeding
+/// incoming frames to `receiver`.
+///
+/// Acknowledgements are piggybacked on the next outgoing frame when one is sent within the
+/// delayed acknowledgement timer, and sent on their own otherwise. Returns the receiver once
+/// both directions are complete, or once the peer has gone away after the incoming message was
+/// complete: the peer only leaves once it has received everything, so only the final
+/// acknowledgement can be missing then. Every packet sent or handled is recorded to `tracer`.
+///
+/// Payloads are handed over to the payload sink of `receiver`, if it has one, as soon as the
+/// sink has room for them, and the sink is closed once the incoming message is complete.
+///
+/// Fails with [`Error::ChannelClosed`] if the peer or the consumer of the payloads goes away
+/// before the incoming message is complete and with [`Error::Timeout`] if nothing was heard from it for `timers.idle`.
+pub fn run_endpoint(
+    name: &str,
+    mut transmitter: TransmitterStateMachine,
+    mut receiver: ReceiverStateMachine,
+    outgoing: mpsc::Sender<TimestampedPacket>,
+    incoming: mpsc::Receiver<TimestampedPacket>,
+    timers: EndpointTimers,
+    tracer: Tracer,
+) -> Result<ReceiverStateMachine> {
+    let mut idle_deadline = timers.idle.map(|idle| Instant::now() + idle);
+    let mut pending_acknowledgement: Option<(GenericAcknowledgement, Instant)> = None;
+    let mut must_transmit = true;
+    let mut retransmission_deadline: Option<Instant> = None;
+    let mut linger_deadline: Option<Instant> = None;
+    let mut last_transmitted_frame: Option<usize> = None;
+
+    log::info!("{}: Starting transmission", name);
+    loop {
+        let now = Instant::now();
+        // Payloads handed over to the consumer may make room for the frame held back, which the
+        // transmitter learns right away rather than on its next retransmission
+        let was_ready = receiver.is_ready();
+        receiver.deliver_payloads()?;
+        if !was_ready && receiver.is_ready() {
+            log::debug!("{}: Receiver is ready again", name);
+            pending_acknowledgement = Some((receiver.acknowledgement(), now));
+        }
+
+        if must_transmit && let Some(frame) = transmitter.current_frame() {
+            let (frames_transmitted, total_number_of_frames_to_transmit) = transmitter.progress();
+            log::info!(
+                "{}: Sending frame {}/{}",
+                name,
+                frames_transmitted + 1,
+                total_number_of_frames_to_transmit
+            );
+            let action = match last_transmitted_frame.replace(frames_transmitted) {
+                Some(last_frame) if last_frame == frames_transmitted => {
+                    TraceAction::RetransmitFrame
+                }
+                _ => TraceAction::SendFrame,
+            };
+            tracer.record(
+                name,
+                action,
+                Some(frame),
+                pending_acknowledgement
+                    .as_ref()
+                    .map(|(acknowledgement, _)| acknowledgement),
+                true,
+            );
+            let packet = match pending_acknowledgement.take() {
+                Some((acknowledgement, _)) => {
+                    GenericPacket::FrameWithAcknowledgement(frame.clone(), acknowledgement)
+                }
+                None => GenericPacket::Frame(frame.clone()),
+            };
+            if outgoing.send((packet, now)).is_err() {
+                break;
+            }
+            retransmission_deadline = Some(now + timers.retransmission);
+        }
+        must_transmit = false;
+
+        // Nothing left to piggyback on, so there is no point in delaying the acknowledgement
+        if let Some((acknowledgement, deadline)) = pending_acknowledgement
+            && (transmitter.is_finished() || now >= deadline)
+        {
+            log::debug!("{}: Sending standalone acknowledgement", name);
+            tracer.record(
+                name,
+                TraceAction::SendAcknowledgement,
+                None,
+                Some(&acknowledgement),
+                true,
+            );
+            pending_acknowledgement = None;
+            if outgoing
+                .send((GenericPacket::Acknowledgement(acknowledgement), now))
+                .is_err()
+            {
+                log::info!("{}: Peer has closed the channel", name);
+                break;
+            }
+        }
+
+        if transmitter.is_finished() && receiver.is_finished() {
+            let deadline = *linger_deadline.get_or_insert(now + timers.linger);
+            if now >= deadline {
+                log::info!("{}: Finished transmission and reception", name);
+                break;
+            }
+        }
+
+        if idle_deadline.is_some_and(|deadline| now >= deadline) {
+            log::warn!("{}: Nothing heard from the peer, giving up", name);
+            return Err(Error::Timeout);
+        }
+
+        let next_deadline = [
+            pending_acknowledgement.map(|(_, deadline)| deadline),
+            // Polls the payload sink until it has room
+            receiver
+                .has_undelivered_payloads()
+                .then(|| now + timers.delayed_acknowledgement),
+            retransmission_deadline.filter(|_| !transmitter.is_finished()),
+            linger_deadline,
+            idle_deadline,
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        let received = match next_deadline {
+            Some(deadline) => incoming.recv_timeout(deadline.saturating_duration_since(now)),
+            None => incoming.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        match received {
+            Ok((packet, send_instant)) => {
+                log::debug!(
+                    "{}: Received packet in {:?} - Starting processing",
+                    name,
+                    send_instant.elapsed()
+                );
+                linger_deadline = None;
+                idle_deadline = timers.idle.map(|idle| Instant::now() + idle);
+                let (frame, acknowledgement) = match packet {
+                    GenericPacket::Frame(frame) => (Some(frame), None),
+                    GenericPacket::Acknowledgement(acknowledgement) => {
+                        (None, Some(acknowledgement))
+                    }
+                    GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                        (Some(frame), Some(acknowledgement))
+                    }
+                };
+                // The frame goes first, so that its acknowledgement can ride on the frame that
+                // the piggybacked acknowledgement is about to release
+                if let Some(frame) = frame {
+                    let deadline = pending_acknowledgement
+                        .map(|(_, deadline)| deadline)
+                        .unwrap_or_else(|| Instant::now() + timers.delayed_acknowledgement);
+                    let (response, outcome) = receiver.handle_frame(&frame)?;
+                    tracer.record(
+                        name,
+                        outcome.into(),
+                        Some(&frame),
+                        None,
+                        outcome != FrameOutcome::Rejected,
+                    );
+                    pending_acknowledgement = Some((response, deadline));
+                }
+                if let Some(acknowledgement) = acknowledgement {
+                    let action = transmitter.handle_acknowledgement(&acknowledgement);
+                    tracer.record(
+                        name,
+                        action.into(),
+                        None,
+                        Some(&acknowledgement),
+                        acknowledgement.is_valid(),
+                    );
+                    match action {
+                        TransmitterAction::Finished => retransmission_deadline = None,
+                        TransmitterAction::WaitForAcknowledgement
+                        | TransmitterAction::HoldNextFrame
+                        | TransmitterAction::WaitForReceiver => {}
+                        TransmitterAction::SendNextFrame | TransmitterAction::RetransmitFrame => {
+                            must_transmit = true
+                        }
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if let Some(deadline) = retransmission_deadline
+                    && !transmitter.is_finished()
+                    && Instant::now() >= deadline
+                {
+                    log::debug!(
+                        "{}: Retransmission timer expired - Retrying same packet",
+                        name
+                    );
+                    tracer.record(
+                        name,
+                        TraceAction::RetransmissionTimeout,
+                        transmitter.current_frame(),
+                        None,
+                        true,
+                    );
+                    transmitter.handle_retransmission_timeout();
+                    must_transmit = true;
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    log::info!(
+        "{}: Discarded {} duplicate and {} out-of-order frames, ignored {} stale acknowledgements",
+        name,
+        receiver.duplicate_frames(),
+        receiver.out_of_order_frames(),
+        transmitter.stale_acknowledgements()
+    );
+    if let Some(payload_length_in_bytes) = transmitter.payload_length_in_bytes() {
+        log::info!(
+            "{}: Payload length adapted to {} B",
+            name,
+            payload_length_in_bytes
+        );
+    }
+    if !receiver.is_finished() {
+        log::warn!(
+            "{}: Peer has closed the channel before the end of the transfer",
+            name
+        );
+        return Err(Error::ChannelClosed);
+    }
+    if !transmitter.is_finished() {
+        log::info!(
+            "{}: Peer has left before acknowledging the last frame",
+            name
+        );
+    }
+    receiver.finish_delivery()?;
+    Ok(receiver)
+}
+
+#[test]
+fn full_duplex_transfer_delivers_both_messages() {
+    use crate::message::prepare_message;
+    use std::thread;
+
+    let message_a: Vec<u8> = (0..50_000u32).map(|i| (i % 241) as u8).collect();
+    let message_b: Vec<u8> = (0..30_000u32).map(|i| (i % 13) as u8).collect();
+
+    for sequence_number_width in [
+        SequenceNumberWidth::AlternatingBit,
+        SequenceNumberWidth::Bits16,
+    ] {
+        let (tx_a_to_b, rx_a_to_b) = mpsc::channel();
+        let (tx_b_to_a, rx_b_to_a) = mpsc::channel();
+
+        let session_parameters = SessionParameters {
+            sequence_number_width,
+            ..SessionParameters::default()
+        };
+        let frames_a = prepare_message(&message_a, 4000, &session_parameters, None).unwrap();
+        let frames_b = prepare_message(&message_b, 4000, &session_parameters, None).unwrap();
+
+        let endpoint_b = thread::spawn(move || {
+            run_endpoint(
+                "B",
+                TransmitterStateMachine::new(frames_b),
+                ReceiverStateMachine::new(None, sequence_number_width),
+                tx_b_to_a,
+                rx_a_to_b,
+                EndpointTimers::default(),
+                Tracer::disabled(),
+            )
+            .unwrap()
+        });
+        let received_by_a = run_endpoint(
+            "A",
+            TransmitterStateMachine::new(frames_a),
+            ReceiverStateMachine::new(None, sequence_number_width),
+            tx_a_to_b,
+            rx_b_to_a,
+            EndpointTimers::default(),
+            Tracer::disabled(),
+        )
+        .unwrap();
+        let received_by_b = endpoint_b.join().unwrap();
+
+        assert_eq!(received_by_a.into_message().unwrap(), message_b);
+        assert_eq!(received_by_b.into_message().unwrap(), message_a);
+    }
+
+    // A peer that hangs up before sending anything leaves the incoming message incomplete
+    let (tx_a_to_b, rx_a_to_b) = mpsc::channel();
+    let (tx_b_to_a, rx_b_to_a) = mpsc::channel::<TimestampedPacket>();
+    drop((rx_a_to_b, tx_b_to_a));
+    let frames_a = prepare_message(&message_a, 4000, &SessionParameters::default(), None).unwrap();
+    assert!(matches!(
+        run_endpoint(
+            "A",
+            TransmitterStateMachine::new(frames_a),
+            ReceiverStateMachine::new(None, SequenceNumberWidth::AlternatingBit),
+            tx_a_to_b,
+            rx_b_to_a,
+            EndpointTimers::default(),
+            Tracer::disabled(),
+        ),
+        Err(Error::ChannelClosed)
+    ));
+}
+
+#[test]
+fn bounded_receivers_pause_the_transmitter_until_payloads_are_written() {
+    use crate::message::{prepare_message, write_message};
+    use std::thread;
+
+    let message: Vec<u8> = (0..50_000u32).map(|i| (i % 241) as u8).collect();
+    let (tx_a_to_b, rx_a_to_b) = mpsc::channel();
+    let (tx_b_to_a, rx_b_to_a) = mpsc::channel();
+    let frames_a = prepare_message(&message, 4000, &SessionParameters::default(), None).unwrap();
+    let frames_b = prepare_message(&[], 4000, &SessionParameters::default(), None).unwrap();
+
+    // A writer slower than the line, behind a queue of a single payload
+    let (payload_sink, payloads) = mpsc::sync_channel(1);
+    let writer = thread::spawn(move || {
+        let mut written_message = Vec::new();
+        let slow_payloads = payloads
+            .into_iter()
+            .inspect(|_| thread::sleep(Duration::from_millis(5)));
+        write_message(slow_payloads, &mut written_message).unwrap();
+        written_message
+    });
+    let mut receiver = ReceiverStateMachine::new(None, SequenceNumberWidth::AlternatingBit);
+    receiver.set_buffer_limit(NonZeroUsize::new(4000));
+    receiver.set_payload_sink(payload_sink);
+
+    let endpoint_b = thread::spawn(move || {
+        run_endpoint(
+            "B",
+            TransmitterStateMachine::new(frames_b),
+            receiver,
+            tx_b_to_a,
+            rx_a_to_b,
+            EndpointTimers::default(),
+            Tracer::disabled(),
+        )
+        .unwrap()
+    });
+    let (tracer, events) = Tracer::new();
+    run_endpoint(
+        "A",
+        TransmitterStateMachine::new(frames_a),
+        ReceiverStateMachine::new(None, SequenceNumberWidth::AlternatingBit),
+        tx_a_to_b,
+        rx_b_to_a,
+        EndpointTimers::default(),
+        tracer,
+    )
+    .unwrap();
+    endpoint_b.join().unwrap();
+
+    assert!(
+        events
+            .try_iter()
+            .any(|event| event.action == TraceAction::AcknowledgementPause)
+    );
+    assert_eq!(writer.join().unwrap(), message);
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+    // Both halves run in lockstep over a seeded line: every round carries the current frame
+    // and brings its acknowledgement back, a lost acknowledgement stands for the timeout
+    #[test]
+    fn lockstep_transfer_delivers_the_message_unless_an_error_goes_undetected(
+        message in proptest::collection::vec(proptest::num::u8::ANY, 0..4096),
+        payload_blocks in 1usize..64,
+        seed in proptest::num::u64::ANY,
+        bit_error_probability in proptest::prop_oneof![
+            proptest::strategy::Just(0.0),
+            0.0..1e-3,
+            0.0..0.05,
+            proptest::strategy::Just(0.5),
+        ],
+        sequence_number_width in proptest::sample::select(vec![
+            SequenceNumberWidth::AlternatingBit,
+            SequenceNumberWidth::Bits8,
+            SequenceNumberWidth::Bits16,
+            SequenceNumberWidth::Bits32,
+        ]),
+    ) {
+        use crate::message::prepare_message;
+        use rand::SeedableRng;
+
+        let session_parameters = SessionParameters {
+            sequence_number_width,
+            ..SessionParameters::default()
+        };
+        let frames =
+            prepare_message(&message, payload_blocks * 8, &session_parameters, None).unwrap();
+        let total_number_of_frames = frames.len();
+        let mut transmitter = TransmitterStateMachine::new(frames);
+        let mut receiver = ReceiverStateMachine::new(None, sequence_number_width);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut undetected_errors = 0;
+
+        // Likely enough to get through that every frame is delivered well within the bound
+        let bits_per_round =
+            (payload_blocks * 8 + Frame::overhead_in_bytes(sequence_number_width) + 6) * 8;
+        let is_live = (1.0f64 - bit_error_probability).powi(bits_per_round as i32) >= 0.5;
+        let rounds = match is_live {
+            true => total_number_of_frames * 64,
+            false => 256,
+        };
+        let meaning = |acknowledgement: &GenericAcknowledgement| {
+            (
+                matches!(acknowledgement, GenericAcknowledgement::ACK(_)),
+                acknowledgement.sequence_number(),
+            )
+        };
+
+        for _ in 0..rounds {
+            let Some(frame) = transmitter.current_frame().cloned() else {
+                break;
+            };
+            let (frames_transmitted, _) = transmitter.progress();
+            let (_, _, sequence_number) =
+                frame.get_payload_and_checksum_and_sequence_number().unwrap();
+            proptest::prop_assert_eq!(
+                sequence_number,
+                SequenceNumber::for_frame_index(frames_transmitted as u64, sequence_number_width)
+            );
+            if sequence_number_width.is_alternating_bit() {
+                proptest::prop_assert_eq!(sequence_number.value() as usize, frames_transmitted % 2);
+            }
+
+            let received_frame =
+                frame.simulate_errors_with_probability(bit_error_probability, &mut rng);
+            let Ok((acknowledgement, outcome)) = receiver.handle_frame(&received_frame) else {
+                // Only a session header corrupted beyond detection cannot be honoured
+                proptest::prop_assert!(received_frame != frame);
+                undetected_errors += 1;
+                break;
+            };
+            if outcome != FrameOutcome::Rejected
+                && received_frame.get_payload_and_checksum_and_sequence_number().ok()
+                    .map(|(payload, _, sequence_number)| (payload, sequence_number))
+                    != frame.get_payload_and_checksum_and_sequence_number().ok()
+                        .map(|(payload, _, sequence_number)| (payload, sequence_number))
+            {
+                undetected_errors += 1;
+            }
+
+            let received_acknowledgement =
+                acknowledgement.simulate_errors_with_probability(bit_error_probability, &mut rng);
+            if received_acknowledgement.is_valid()
+                && meaning(&received_acknowledgement) != meaning(&acknowledgement)
+            {
+                undetected_errors += 1;
+            }
+            let action = transmitter.handle_acknowledgement(&received_acknowledgement);
+            proptest::prop_assert_eq!(
+                action == TransmitterAction::Finished,
+                transmitter.is_finished()
+            );
+            if undetected_errors == 0 {
+                // The transmitter can only be ahead of the receiver by the frame in flight
+                proptest::prop_assert!(
+                    receiver.received_frame_count() - transmitter.progress().0 <= 1
+                );
+                proptest::prop_assert!(!transmitter.is_finished() || receiver.is_finished());
+            }
+        }
+
+        if undetected_errors == 0 {
+            proptest::prop_assert!(!is_live || transmitter.is_finished());
+            if transmitter.is_finished() {
+                proptest::prop_assert_eq!(receiver.into_message().unwrap(), message);
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fmt, io};
+
+use crate::{packets::sequence::SequenceNumberWidth, trace::PacketType};
+
+/// Everything that can go wrong while preparing, transferring or decoding a message.
+#[derive(Debug)]
+pub enum Error {
+    /// Bytes that do not decode into what they should carry.
+    Decode(DecodeError),
+    /// A frame whose checksum or authentication tag does not match its content.
+    ChecksumMismatch,
+    /// A packet of a type that makes no sense where it was received.
+    UnexpectedPacketType(PacketType),
+    /// The peer went away before the transfer was complete.
+    ChannelClosed,
+    /// Nothing was heard from the peer for longer than the idle timer.
+    Timeout,
+    /// Frames are split on whole 8 byte blocks, so the payload length must be a non-zero
+    /// multiple of 8.
+    InvalidPayloadLength(usize),
+    /// The session is encrypted but no pre-shared key was provided.
+    MissingPreSharedKey,
+    Io(io::Error),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// A frame too short to hold its checksum and sequence number.
+    TruncatedFrame {
+        length_in_bytes: usize,
+        minimum_length_in_bytes: usize,
+    },
+    /// Acknowledgements have a fixed length for every sequence number width.
+    InvalidAcknowledgementLength {
+        length_in_bytes: usize,
+        expected_length_in_bytes: usize,
+    },
+    /// The first payload is missing or announces session parameters this side does not support.
+    InvalidSessionHeader,
+    /// The session announces another sequence number width than the receiver was set up with.
+    SequenceNumberWidthMismatch {
+        announced: SequenceNumberWidth,
+        expected: SequenceNumberWidth,
+    },
+    /// The reassembled payloads are not a valid compressed stream.
+    Decompression(io::Error),
+    /// A line of an error trace that is not an event, or an event past the declared length.
+    InvalidErrorTrace { line_number: usize },
+    /// An HDLC control field of no known frame type.
+    InvalidHdlcControl { control: u8 },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Decode(error) => write!(f, "{}", error),
+            Error::ChecksumMismatch => write!(f, "Frame does not match its checksum"),
+            Error::UnexpectedPacketType(packet_type) => {
+                write!(f, "Unexpected {:?} packet", packet_type)
+            }
+            Error::ChannelClosed => write!(f, "Peer has closed the channel before the end"),
+            Error::Timeout => write!(f, "Timed out waiting for the peer"),
+            Error::InvalidPayloadLength(length_in_bytes) => write!(
+                f,
+                "Payload length of {} B is not a non-zero multiple of 8",
+                length_in_bytes
+            ),
+            Error::MissingPreSharedKey => {
+                write!(f, "Session is encrypted but no pre-shared key was provided")
+            }
+            Error::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TruncatedFrame {
+                length_in_bytes,
+                minimum_length_in_bytes,
+            } => write!(
+                f,
+                "Frame of {} B is shorter than the {} B of its trailer",
+                length_in_bytes, minimum_length_in_bytes
+            ),
+            DecodeError::InvalidAcknowledgementLength {
+                length_in_bytes,
+                expected_length_in_bytes,
+            } => write!(
+                f,
+                "Acknowledgement of {} B instead of {} B",
+                length_in_bytes, expected_length_in_bytes
+            ),
+            DecodeError::InvalidSessionHeader => write!(f, "Missing or unsupported session header"),
+            DecodeError::SequenceNumberWidthMismatch {
+                announced,
+                expected,
+            } => write!(
+                f,
+                "Session uses {:?} sequence numbers instead of {:?}",
+                announced, expected
+            ),
+            DecodeError::Decompression(error) => write!(f, "Failed to decompress: {}", error),
+            DecodeError::InvalidErrorTrace { line_number } => {
+                write!(f, "Invalid line {} in error trace", line_number)
+            }
+            DecodeError::InvalidHdlcControl { control } => {
+                write!(f, "Unknown HDLC control field {:#04x}", control)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Decode(DecodeError::Decompression(error)) | Error::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// For [`std::io`] traits implemented on top of a transfer.
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Io(error) => error,
+            Error::ChannelClosed => io::Error::new(io::ErrorKind::ConnectionAborted, error),
+            Error::Timeout => io::Error::new(io::ErrorKind::TimedOut, error),
+            Error::InvalidPayloadLength(_) | Error::MissingPreSharedKey => {
+                io::Error::new(io::ErrorKind::InvalidInput, error)
+            }
+            _ => io::Error::new(io::ErrorKind::InvalidData, error),
+        }
+    }
+}
+
+impl From<DecodeError> for Error {
+    fn from(error: DecodeError) -> Self {
+        Error::Decode(error)
+    }
+}
@@ -0,0 +1,203 @@
+use std::io::BufRead;
+
+use crate::{
+    error::{DecodeError, Result},
+    packets::Packet,
+};
+
+// Replays the bit errors and losses captured on a real link in place of random bit flips
+
+/// Bit errors and losses captured on a link.
+///
+/// Read from lines of `<position> error` or `<position> loss`, positions counting the bits sent
+/// on the link since the capture started, so that they measure time at the line rate. A
+/// `length <bits>` line gives the duration of the capture, which otherwise ends with its last
+/// event. Lines starting with `#` are comments.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorTrace {
+    length_in_bits: u64,
+    /// Sorted positions of the flipped bits.
+    bit_errors: Vec<u64>,
+    /// Sorted positions of the losses, each one dropping the packet sent over it.
+    losses: Vec<u64>,
+}
+
+impl ErrorTrace {
+    /// Fails with [`DecodeError::InvalidErrorTrace`] on the first line that is not an event, or
+    /// on an event past the declared length.
+    pub fn read(reader: impl BufRead) -> Result<Self> {
+        let mut declared_length_in_bits = None;
+        // Line number and position of the last event, which ends an undeclared capture
+        let mut last_event: Option<(usize, u64)> = None;
+        let mut bit_errors = Vec::new();
+        let mut losses = Vec::new();
+
+        for (line_index, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || DecodeError::InvalidErrorTrace {
+                line_number: line_index + 1,
+            };
+            let mut fields = line.split_whitespace();
+            let (Some(first_field), Some(second_field), None) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid().into());
+            };
+            if first_field == "length" {
+                let length_in_bits = second_field
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&length_in_bits| length_in_bits > 0)
+                    .ok_or_else(invalid)?;
+                declared_length_in_bits = Some(length_in_bits);
+                continue;
+            }
+            let position: u64 = first_field.parse().map_err(|_| invalid())?;
+            match second_field {
+                "error" => bit_errors.push(position),
+                "loss" => losses.push(position),
+                _ => return Err(invalid().into()),
+            }
+            if last_event.is_none_or(|(_, last_position)| position >= last_position) {
+                last_event = Some((line_index + 1, position));
+            }
+        }
+
+        let length_in_bits = match (declared_length_in_bits, last_event) {
+            (Some(length_in_bits), Some((line_number, last_position)))
+                if last_position >= length_in_bits =>
+            {
+                return Err(DecodeError::InvalidErrorTrace { line_number }.into());
+            }
+            (Some(length_in_bits), _) => length_in_bits,
+            (None, Some((_, last_position))) => last_position + 1,
+            (None, None) => 1,
+        };
+        bit_errors.sort_unstable();
+        losses.sort_unstable();
+        Ok(Self {
+            length_in_bits,
+            bit_errors,
+            losses,
+        })
+    }
+
+    /// Fraction of the captured bits that were flipped.
+    pub fn bit_error_rate(&self) -> f64 {
+        self.bit_errors.len() as f64 / self.length_in_bits as f64
+    }
+
+    pub fn replay(self) -> ErrorTraceReplay {
+        ErrorTraceReplay {
+            trace: self,
+            position_in_bits: 0,
+        }
+    }
+}
+
+/// Plays an [`ErrorTrace`] against the packets sent on a line, one after the other, starting
+/// over once the whole capture has been played.
+#[derive(Debug, Clone)]
+pub struct ErrorTraceReplay {
+    trace: ErrorTrace,
+    position_in_bits: u64,
+}
+
+impl ErrorTraceReplay {
+    pub fn bit_error_rate(&self) -> f64 {
+        self.trace.bit_error_rate()
+    }
+
+    /// Sends `packet` over the next bits of the capture. Returns `None` if a loss falls within
+    /// them, and the packet with the bit errors that fall within them flipped otherwise.
+    pub fn transmit<P: Packet>(&mut self, packet: &P) -> Option<P> {
+        self.transmit_bits(packet.length_in_bits())
+            .map(|flipped_bit_indices| packet.with_flipped_bits(&flipped_bit_indices))
+    }
+
+    /// Plays the next `length_in_bits` bits of the capture like [`ErrorTraceReplay::transmit`],
+    /// returning the offsets of the flipped bits rather than a packet with them flipped.
+    pub fn transmit_bits(&mut self, length_in_bits: usize) -> Option<Vec<usize>> {
+        let length_in_bits = length_in_bits as u64;
+        let is_lost = !self
+            .offsets_within(&self.trace.losses, length_in_bits)
+            .is_empty();
+        let flipped_bit_indices = self.offsets_within(&self.trace.bit_errors, length_in_bits);
+        self.position_in_bits += length_in_bits;
+        match is_lost {
+            true => None,
+            false => Some(flipped_bit_indices),
+        }
+    }
+
+    /// Offsets from the current position of the `events` within the next `length_in_bits`.
+    fn offsets_within(&self, events: &[u64], length_in_bits: u64) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        while offset < length_in_bits {
+            let start = (self.position_in_bits + offset) % self.trace.length_in_bits;
+            let end = (start + length_in_bits - offset).min(self.trace.length_in_bits);
+            let first_event = events.partition_point(|&position| position < start);
+            let end_event = events.partition_point(|&position| position < end);
+            offsets.extend(
+                events[first_event..end_event]
+                    .iter()
+                    .map(|&position| (position - start + offset) as usize),
+            );
+            offset += end - start;
+        }
+        offsets
+    }
+}
+
+#[test]
+fn replay_flips_and_drops_packets_where_the_trace_says() {
+    use crate::{
+        error::Error,
+        packets::{
+            frame::Frame,
+            sequence::{SequenceNumber, SequenceNumberWidth},
+        },
+    };
+
+    let trace = ErrorTrace::read(
+        "# Two bursts and a loss\nlength 400\n3 error\n4 error\n\n130 loss\n250 error\n".as_bytes(),
+    )
+    .unwrap();
+    assert_eq!(trace.bit_error_rate(), 3.0 / 400.0);
+
+    // 13 bytes: 8 of payload, 4 of checksum and the sequence byte
+    let frame = Frame::new(
+        &[0; 8],
+        SequenceNumber::zero(SequenceNumberWidth::AlternatingBit),
+    );
+    assert_eq!(frame.length_in_bits(), 104);
+    let mut replay = trace.clone().replay();
+    let first = replay.transmit(&frame).unwrap();
+    assert_eq!(first.content[0], 0b0001_1000);
+    assert_eq!(first.content[1..], frame.content[1..]);
+    // Bits 104 to 207 hold the loss
+    assert_eq!(replay.transmit(&frame), None);
+    // Bit 250 is bit 42 of the third frame
+    let third = replay.transmit(&frame).unwrap();
+    assert_eq!(third.content[5], 0b0000_0100);
+    // The fourth frame spans bits 312 to 415, wrapping onto the burst at bits 3 and 4
+    let fourth = replay.transmit(&frame).unwrap();
+    assert_eq!(fourth.content[11] ^ frame.content[11], 0b0001_1000);
+
+    for (text, line_number) in [
+        ("1 error\n2 flip\n", 2),
+        ("length 10\n\n10 loss\n", 3),
+        ("# comment\nlength\n", 2),
+    ] {
+        assert!(matches!(
+            ErrorTrace::read(text.as_bytes()),
+            Err(Error::Decode(DecodeError::InvalidErrorTrace { line_number: line }))
+                if line == line_number
+        ));
+    }
+}
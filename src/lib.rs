@@ -1,2 +1,22 @@
-
+pub mod adaptive;
+pub mod analytic;
+#[cfg(feature = "tokio")]
+pub mod async_transport;
+pub mod compression;
+pub mod dashboard;
+pub mod diagram;
+pub mod encryption;
+pub mod endpoint;
+pub mod error;
+pub mod error_trace;
+pub mod message;
+pub mod model_check;
 pub mod packets;
+pub mod plots;
+pub mod proxy;
+pub mod reliable_stream;
+pub mod serial;
+pub mod session;
+pub mod trace;
+
+pub use error::{DecodeError, Error, Result};
@@ -1,23 +1,102 @@
+use bytes::Bytes;
 use log;
+use rand::Rng;
 use rfd::FileDialog;
 use std::{
     collections::{HashMap, VecDeque},
     fmt::Display,
-    fs::{self},
-    ops::Range,
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    num::NonZeroUsize,
+    ops::{Range, RangeInclusive},
     path::{Path, PathBuf},
-    sync::mpsc::{self},
+    sync::mpsc::{self, RecvTimeoutError},
     thread,
-    time::{self, Duration, Instant},
+    time::{self, Duration},
 };
-use stopandwait::packets::{
-    self, GenericPacket, Packet, SEQUENCE_ONE, SEQUENCE_ZERO,
-    acknowledgement::{GenericAcknowledgement, ack::ACK, nack::NACK},
-    flip_sequence_byte,
-    frame::Frame,
+use stopandwait::{
+    adaptive::AdaptiveFrameSource,
+    analytic,
+    compression::{Compression, CompressionMode, SUPPORTED_COMPRESSIONS},
+    dashboard::{DashboardStatistics, run_dashboard},
+    diagram::SequenceDiagram,
+    encryption::{Encryption, PreSharedKey},
+    endpoint::{
+        EndpointTimers, ReceiverStateMachine, TimestampedPacket, TransmitterStateMachine,
+        run_endpoint,
+    },
+    error_trace::{ErrorTrace, ErrorTraceReplay},
+    message::{
+        build_session_cipher, open_frame, prepare_message, reassemble_message, write_message,
+    },
+    model_check::{self, FaultBounds},
+    packets::{GenericPacket, Packet, frame::Frame, sequence::SequenceNumberWidth},
+    plots::{Chart, Series},
+    proxy::{BitErrors, GilbertElliott, Impairments, Proxy},
+    session::SessionParameters,
+    trace::{self, TraceAction, TraceEvent, Tracer},
 };
 const FOLDER_PREFIX: &str = "assets/";
 const FULL_PAYLOAD_LENGTH_IN_BYTES: usize = 5000;
+const SESSION_PARAMETERS: SessionParameters = SessionParameters {
+    compression: Compression::Lz4,
+    compression_mode: CompressionMode::PerFrame,
+    encryption: Encryption::None,
+    sequence_number_width: SequenceNumberWidth::Bits16,
+};
+// Hex encoded 32 byte key, enables authenticated encryption of the payloads when set
+const PRE_SHARED_KEY_VARIABLE: &str = "STOPANDWAIT_PSK";
+// Transfer drawn by `diagram --simulate`
+const SIMULATED_MESSAGE_LENGTH_IN_BYTES: usize = 96;
+const SIMULATED_PAYLOAD_LENGTH_IN_BYTES: usize = 32;
+const SIMULATED_BIT_ERROR_PROBABILITY: f64 = 1e-3;
+// Sweeps run by `sweep`
+const SWEEP_MESSAGE_LENGTH_IN_BYTES: usize = 64 * 1024;
+const SWEEP_FRAME_SIZES_IN_BYTES: [usize; 9] = [64, 128, 256, 512, 1024, 1536, 2048, 3072, 4096];
+const SWEEP_BIT_ERROR_PROBABILITIES: [f64; 3] = [1e-5, 3e-5, 1e-4];
+const SWEEP_FIXED_FRAME_SIZE_IN_BYTES: usize = 1024;
+const SWEEP_RTT_MESSAGE_LENGTH_IN_BYTES: usize = 32 * 1024;
+const SWEEP_ROUND_TRIP_TIMES_IN_MS: [u64; 6] = [0, 1, 2, 5, 10, 20];
+const SWEEP_RTT_BIT_ERROR_PROBABILITY: f64 = 1e-5;
+// Simulated transfers of `compression`
+const COMPRESSION_USAGE: &str = "Usage: stopandwait compression <file> [bit error probability]";
+const COMPARED_BIT_ERROR_PROBABILITY: f64 = 1e-5;
+// Transfer explored by `check`
+const CHECKED_MESSAGE_LENGTH_IN_BYTES: usize = 12;
+const CHECKED_PAYLOAD_LENGTH_IN_BYTES: usize = 8;
+const CHECKED_FAULT_BOUNDS: FaultBounds = FaultBounds {
+    losses: 2,
+    corruptions: 1,
+    duplications: 1,
+    flipped_bits_per_byte: 3,
+};
+// Path prefix of the event trace, written as <prefix>.jsonl and <prefix>.pcap when set
+const TRACE_VARIABLE: &str = "STOPANDWAIT_TRACE";
+// Shows a live dashboard in the terminal instead of the progress logs when set
+const DASHBOARD_VARIABLE: &str = "STOPANDWAIT_DASHBOARD";
+// Adapts the payload length of the transferred files to the observed bit error rate when set,
+// starting from FULL_PAYLOAD_LENGTH_IN_BYTES
+const ADAPTIVE_VARIABLE: &str = "STOPANDWAIT_ADAPTIVE";
+// Received payloads waiting for the disk, beyond which the sending endpoint is held back
+const RECEIVE_BUFFER_LIMIT_IN_BYTES: NonZeroUsize =
+    NonZeroUsize::new(4 * FULL_PAYLOAD_LENGTH_IN_BYTES).unwrap();
+const FILE_WRITER_QUEUE_LENGTH: usize = 4;
+const ADAPTIVE_PAYLOAD_LENGTH_BOUNDS_IN_BYTES: RangeInclusive<usize> = 64..=16 * 1024;
+// Probabilities that the simulated lines duplicate or reorder a packet, 0 when unset
+const DUPLICATION_VARIABLE: &str = "STOPANDWAIT_DUPLICATION";
+const REORDERING_VARIABLE: &str = "STOPANDWAIT_REORDERING";
+const DEFAULT_REORDERING_WINDOW: usize = 3;
+// Longest wait for the packets that should overtake a held one, well below the retransmission
+// timer since stop-and-wait rarely has another packet on the way
+const HELD_PACKET_TIMEOUT: Duration = Duration::from_millis(20);
+// Path of a captured error trace replayed on the simulated lines instead of random bit errors
+const ERROR_TRACE_VARIABLE: &str = "STOPANDWAIT_ERROR_TRACE";
+// Impaired UDP forwarding run by `proxy`
+const PROXY_USAGE: &str = "Usage: stopandwait proxy <listen address> <forward address> \
+    [ber=<p>] [burst=<p good to bad>,<p bad to good>,<p bit error when bad>] \
+    [trace=<error trace>] [loss=<p>] [delay=<ms>] [duplication=<p>] [reordering=<p>] \
+    [reordering_delay=<ms>] [rate=<bit/s>] [seed=<n>]";
+const DEFAULT_PROXY_REORDERING_DELAY_IN_MS: u64 = 10;
 #[derive(Debug)]
 #[allow(dead_code)]
 struct TransferResults {
@@ -29,6 +108,9 @@ struct TransferResults {
     average_rtt: f64,     // in ms
     average_tries: f64,
     incorrect_packets: usize,
+    transmitted_payload_bytes: usize,
+    compression_ratio: f64, // original bytes / transmitted bytes
+    line_efficiency: f64,   // original bytes / bytes on the line, retransmissions included
 }
 
 impl Display for TransferResults {
@@ -44,6 +126,11 @@ impl Display for TransferResults {
             "Average tries per frame: {:.2}\n",
             self.average_tries
         ));
+        result.push_str(&format!(
+            "Compression ratio: {:.2} ({} B on the wire)\n",
+            self.compression_ratio, self.transmitted_payload_bytes
+        ));
+        result.push_str(&format!("Line efficiency: {:.3}\n", self.line_efficiency));
         result.push_str(&format!(
             "Incorrect packets accepted: {}",
             self.incorrect_packets
@@ -53,175 +140,107 @@ impl Display for TransferResults {
     }
 }
 
-fn prepare_message(
-    payload_to_transfer: &Vec<u8>,
-    full_payload_length_in_bytes: usize,
-) -> VecDeque<Frame> {
-    assert!(full_payload_length_in_bytes % 8 == 0);
-
-    let total_payload_length_in_bytes = payload_to_transfer.len();
-
-    let n_full_frames =
-        f64::floor(total_payload_length_in_bytes as f64 / full_payload_length_in_bytes as f64)
-            as usize;
-
-    // Either 0 or 1 small frame at the end
-    let small_payload_length = total_payload_length_in_bytes % full_payload_length_in_bytes;
-    let n_small_frames: usize;
-    match small_payload_length {
-        0 => n_small_frames = 0,
-        _ => n_small_frames = 1,
-    }
-
-    // Separate into bytes to be sent with full frames and the last bytes to be sent with small frames
-    let (full_frames_bytes, small_frames_bytes) =
-        payload_to_transfer.split_at(n_full_frames * full_payload_length_in_bytes);
+fn read_pre_shared_key() -> Option<PreSharedKey> {
+    let hex = std::env::var(PRE_SHARED_KEY_VARIABLE)
+        .ok()
+        .filter(|hex| !hex.is_empty())?;
+    Some(PreSharedKey::from_hex(&hex).expect("Pre-shared key must be 64 hex characters"))
+}
 
-    assert_eq!(
-        full_frames_bytes.len() + small_frames_bytes.len(),
-        total_payload_length_in_bytes
-    );
+fn read_trace_prefix() -> Option<String> {
+    std::env::var(TRACE_VARIABLE)
+        .ok()
+        .filter(|prefix| !prefix.is_empty())
+}
 
-    let mut frames_to_be_transmitted: VecDeque<Frame> =
-        VecDeque::with_capacity(n_full_frames + n_small_frames);
-    let mut current_sequence_byte = packets::SEQUENCE_ZERO;
-    for full_payload in full_frames_bytes.chunks(full_payload_length_in_bytes) {
-        frames_to_be_transmitted.push_back(Frame::new(full_payload, current_sequence_byte));
-        current_sequence_byte = flip_sequence_byte(current_sequence_byte);
-    }
-    for small_payload in small_frames_bytes.chunks(small_payload_length) {
-        frames_to_be_transmitted.push_back(Frame::new(small_payload, current_sequence_byte));
-        current_sequence_byte = flip_sequence_byte(current_sequence_byte);
-    }
+fn is_dashboard_enabled() -> bool {
+    std::env::var(DASHBOARD_VARIABLE).is_ok_and(|value| !value.is_empty() && value != "0")
+}
 
-    let mut even_frames = frames_to_be_transmitted.iter().step_by(2);
-    let mut odd_frames = frames_to_be_transmitted.iter().skip(1).step_by(2);
-    assert!(
-        even_frames
-            .all(|frame| frame.get_payload_and_checksum_and_sequence_byte().2 == SEQUENCE_ZERO)
-    );
-    assert!(
-        odd_frames
-            .all(|frame| frame.get_payload_and_checksum_and_sequence_byte().2 == SEQUENCE_ONE)
-    );
+fn is_adaptive_enabled() -> bool {
+    std::env::var(ADAPTIVE_VARIABLE).is_ok_and(|value| !value.is_empty() && value != "0")
+}
 
-    frames_to_be_transmitted
+fn read_error_trace() -> Option<ErrorTrace> {
+    let path = std::env::var(ERROR_TRACE_VARIABLE)
+        .ok()
+        .filter(|path| !path.is_empty())?;
+    let file = File::open(path).expect("Unable to open error trace");
+    Some(ErrorTrace::read(BufReader::new(file)).expect("Unable to read error trace"))
+}
 
-    /* eprintln!(
-        "Sending {n_frames} frames with a full payload of {} bytes",
-        full_payload_length_in_bytes
-    ); */
+fn write_trace(trace_prefix: &str, events: &[TraceEvent]) -> stopandwait::Result<()> {
+    trace::write_json_lines(
+        events,
+        BufWriter::new(File::create(trace_prefix.to_owned() + ".jsonl")?),
+    )?;
+    trace::write_pcap(
+        events,
+        BufWriter::new(File::create(trace_prefix.to_owned() + ".pcap")?),
+    )
 }
-fn _simulate_transfer(
-    payload_to_transfer: &Vec<u8>,
+
+fn simulate_transfer(
+    payload_to_transfer: &[u8],
     full_payload_length_in_bytes: usize,
-    bit_error_probability: f64,
+    mut line_errors: LineErrors,
+    session_parameters: &SessionParameters,
+    pre_shared_key: Option<&PreSharedKey>,
 ) -> TransferResults {
     let total_payload_length_in_bytes = payload_to_transfer.len();
-    let mut frames_to_be_transmitted =
-        prepare_message(payload_to_transfer, full_payload_length_in_bytes);
+    let session_cipher = build_session_cipher(session_parameters, pre_shared_key)
+        .expect("Unable to build session cipher");
+    let mut frames_to_be_transmitted = prepare_message(
+        payload_to_transfer,
+        full_payload_length_in_bytes,
+        session_parameters,
+        session_cipher.as_ref(),
+    )
+    .expect("Unable to split message into frames");
     let n_frames = frames_to_be_transmitted.len();
+    let transmitted_payload_bytes = transmitted_payload_length_in_bytes(&frames_to_be_transmitted);
     let mut rng = rand::rng();
     let mut total_tries: usize = 0;
+    let mut transmitted_line_bytes: usize = 0;
     let mut total_time = Duration::ZERO;
-    let mut received_frames: Vec<Frame> = Vec::with_capacity(n_frames); // Just for performance, in reality the RX does not know n_frames
+    let mut received_payloads: Vec<Bytes> = Vec::with_capacity(n_frames); // Just for performance, in reality the RX does not know n_frames
 
     let mut wrong_received_packets: usize = 0;
     while frames_to_be_transmitted.len() > 0 {
         let transfer_start_time = time::Instant::now();
         let mut sent_counter: usize = 1;
-        /* eprintln!(
-            "Transmitting frame {}/{}",
-            n_frames - frames_to_be_transmitted.len() + 1,
-            n_frames
-        );*/
         let transmitted_frame = frames_to_be_transmitted
             .get(0)
             .expect("Condition checked in while loop, len > 0");
 
-        // For testing purposes
-        assert!(transmitted_frame.is_valid());
-        // Simulate transmission line that can mutate the frame
-        let mut received_frame =
-            transmitted_frame.simulate_errors_with_probability(bit_error_probability, &mut rng);
+        let frame_index = received_payloads.len() as u64;
 
-        // Simulate receiver validating the frame
-        while !received_frame.is_valid() {
-            received_frame =
-                transmitted_frame.simulate_errors_with_probability(bit_error_probability, &mut rng);
+        // Simulate transmission line that can mutate or lose the frame, and receiver
+        // validating it
+        let (received_frame, received_payload) = loop {
+            if let Some(received_frame) = line_errors.transmit(transmitted_frame, &mut rng)
+                && let Ok(received_payload) =
+                    open_frame(&received_frame, frame_index, session_cipher.as_ref())
+            {
+                break (received_frame, received_payload);
+            }
             sent_counter += 1;
-        }
-        /*
-        let transmitted_ack = ACK::new();
-
-        // Simulate transmission line that can mutate the ACK
-        let received_ack =
-            transmitted_ack.simulate_errors_with_probability(bit_error_probability, &mut rng);
-
-        if !received_ack.is_valid() {
-            eprintln!("TX received Invalid ACK!");
-            continue;
-        }
-
-        */
-
-        // Just for knowledge purposes
-
+        };
+        transmitted_line_bytes += transmitted_frame.content.len() * sent_counter;
         if *transmitted_frame != received_frame {
-            /* eprintln!(
-                "ERROR: {}/{} TX frame != RX frame",
-                n_frames - frames_to_be_transmitted.len() + 1,
-                n_frames
-            ); */
             wrong_received_packets += 1;
         }
-        received_frames.push(received_frame);
+        received_payloads.push(received_payload);
         frames_to_be_transmitted.pop_front();
 
         let transfer_duration = transfer_start_time.elapsed();
 
         total_tries += sent_counter;
         total_time += transfer_duration;
-        // println!("Sent in {} tries\n", sent_counter);
     }
 
-    /*
-    print!("\n");
-
-    println!(
-        "Frames transferred: {} ({} B)",
-        n_frames, total_payload_length_in_bytes
-    );
-    println!(
-        "Total transfer time: {} ms",
-        total_time.as_secs_f64() * 1000.0
-    );
-
-    println!(
-        "Effective transfer speed: {} kB/s",
-        total_payload_length_in_bytes as f64 / total_time.as_secs_f64() as f64 / 1000.0
-    );
-    println!(
-        "Average RTT per frame: {} ms",
-        total_time.as_secs_f64() as f64 / n_frames as f64 * 1000.0
-    );
-    println!(
-        "Average tries per frame: {:.2}",
-        total_tries as f64 / n_frames as f64
-    );
-
-    println!("Incorrect packets accepted: {}", wrong_received_packets);
-
-    */
-    let mut received_bytes_vec =
-        Vec::with_capacity(received_frames.len() * full_payload_length_in_bytes);
-    for received_frame in received_frames {
-        received_bytes_vec.append(
-            &mut received_frame
-                .get_payload_and_checksum_and_sequence_byte()
-                .0,
-        );
-    }
+    // Undetected errors can make the received stream undecodable, in that case report it empty
+    let received_bytes_vec = reassemble_message(&received_payloads).unwrap_or_default();
     TransferResults {
         received_bytes: received_bytes_vec,
         transferred_frames: n_frames,
@@ -232,6 +251,9 @@ fn _simulate_transfer(
         average_rtt: total_time.as_secs_f64() as f64 / n_frames as f64 * 1000.0,
         average_tries: total_tries as f64 / n_frames as f64,
         incorrect_packets: wrong_received_packets,
+        transmitted_payload_bytes,
+        compression_ratio: total_payload_length_in_bytes as f64 / transmitted_payload_bytes as f64,
+        line_efficiency: total_payload_length_in_bytes as f64 / transmitted_line_bytes as f64,
     }
 }
 
@@ -245,10 +267,12 @@ fn _benchmark_payload_lengths(
     for full_payload_length_in_bytes in payload_range.step_by(byte_step) {
         results_map.insert(
             full_payload_length_in_bytes,
-            _simulate_transfer(
+            simulate_transfer(
                 &payload_to_transfer,
                 full_payload_length_in_bytes,
-                bit_error_probability,
+                LineErrors::Random(bit_error_probability),
+                &SESSION_PARAMETERS,
+                None,
             ),
         );
     }
@@ -268,6 +292,16 @@ fn _benchmark_payload_lengths(
         best_speed.0, best_speed.1.effective_speed
     );
 }
+/// Bytes the payloads take on the line, leaving out the session header frame.
+fn transmitted_payload_length_in_bytes(frames: &VecDeque<Frame>) -> usize {
+    frames
+        .iter()
+        .skip(1)
+        .filter_map(|frame| frame.get_payload_and_checksum_and_sequence_number().ok())
+        .map(|(payload, _, _)| payload.len())
+        .sum()
+}
+
 #[derive(Clone)]
 struct FileToTransfer {
     path: PathBuf,
@@ -281,13 +315,14 @@ impl FileToTransfer {
             path: file_path,
         })
     }
-    fn extension(&self) -> String {
-        self.path
-            .extension()
-            .expect("File has no extensions")
-            .to_str()
-            .unwrap()
-            .to_string()
+    /// Where the received copy is written, keeping the extension of the original file if it
+    /// has one.
+    fn output_path(&self, file_stem: &str) -> PathBuf {
+        let output_path = Path::new(FOLDER_PREFIX).join(file_stem);
+        match self.path.extension() {
+            Some(extension) => output_path.with_extension(extension),
+            None => output_path,
+        }
     }
 }
 
@@ -300,267 +335,1031 @@ fn ask_for_input_file_and_return_it() -> std::io::Result<FileToTransfer> {
     FileToTransfer::new(input_file_path)
 }
 
-fn main() {
-    // let (tx_a_to_b, rx_a_to_b) = mpsc::channel();
-    // let (tx_b_to_a, rx_b_to_a): (Sender<PacketType>, Receiver<PacketType>) = mpsc::channel();
+fn ask_for_optional_file_to_send_back() -> std::io::Result<Option<FileToTransfer>> {
+    match FileDialog::new()
+        .set_title("File to send back from B (cancel for a one-way transfer)")
+        .set_directory("~/Downloads")
+        .pick_file()
+    {
+        Some(path) => FileToTransfer::new(path).map(Some),
+        None => Ok(None),
+    }
+}
 
-    const ACK_ZERO: ACK = ACK::new(SEQUENCE_ZERO);
-    const ACK_ONE: ACK = ACK::new(SEQUENCE_ONE);
-    const NACK_ZERO: NACK = NACK::new(SEQUENCE_ZERO);
-    const NACK_ONE: NACK = NACK::new(SEQUENCE_ONE);
+/// Impairments of a simulated line besides bit errors, all disabled by default.
+#[derive(Debug, Clone, Copy, Default)]
+struct LineImpairments {
+    /// Probability that a packet is delivered twice in a row.
+    duplication_probability: f64,
+    /// Probability that a packet is held back until 1 to `reordering_window` packets sent
+    /// after it have been delivered.
+    reordering_probability: f64,
+    reordering_window: usize,
+}
 
-    let (tx_a_to_tl, rx_a_to_tl) = mpsc::channel();
-    let (tx_tl_to_a, rx_tl_to_a) = mpsc::channel();
-    let (tx_b_to_tl, rx_b_to_tl) = mpsc::channel();
-    let (tx_tl_to_b, rx_tl_to_b) = mpsc::channel();
+impl LineImpairments {
+    fn from_environment() -> Self {
+        let read_probability = |variable: &str| {
+            std::env::var(variable)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .map_or(0.0, |value| {
+                    value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|probability| (0.0..=1.0).contains(probability))
+                        .unwrap_or_else(|| panic!("{} must be a probability", variable))
+                })
+        };
+        Self {
+            duplication_probability: read_probability(DUPLICATION_VARIABLE),
+            reordering_probability: read_probability(REORDERING_VARIABLE),
+            reordering_window: DEFAULT_REORDERING_WINDOW,
+        }
+    }
+}
 
-    env_logger::init();
+/// Bit errors and losses of a simulated line, drawn at random or replayed from a capture.
+#[derive(Debug, Clone)]
+enum LineErrors {
+    /// Every bit flipped independently with this probability, and no packet lost.
+    Random(f64),
+    Replayed(ErrorTraceReplay),
+}
 
-    log::info!("Waiting for file input");
+impl LineErrors {
+    /// Replays the error trace named in the environment if any, and flips bits with
+    /// `bit_error_probability` otherwise.
+    fn from_environment(bit_error_probability: f64) -> Self {
+        match read_error_trace() {
+            Some(error_trace) => LineErrors::Replayed(error_trace.replay()),
+            None => LineErrors::Random(bit_error_probability),
+        }
+    }
 
-    // Ask for input file
-    let file_to_transfer = ask_for_input_file_and_return_it().expect("Unable to read input file");
-    let cloned_content = file_to_transfer.content.clone();
-    // Read file extension
-    let file_extension = file_to_transfer.extension();
-    let not_passed_file_extension = file_extension.clone();
-    // TX thread
-    let transmitter_thread = thread::spawn(move || {
-        let mut frames_to_transmit = prepare_message(
-            &file_to_transfer.clone().content,
-            FULL_PAYLOAD_LENGTH_IN_BYTES,
-        );
+    fn bit_error_rate(&self) -> f64 {
+        match self {
+            LineErrors::Random(bit_error_probability) => *bit_error_probability,
+            LineErrors::Replayed(replay) => replay.bit_error_rate(),
+        }
+    }
 
-        let mut expected_sequence_byte = SEQUENCE_ONE;
-
-        let mut frames_transmitted: usize = 1;
-        let total_number_of_frames_to_transmit = frames_to_transmit.len();
-        log::info!("Starting transmission");
-        while frames_to_transmit.len() > 0 {
-            let current_frame: Frame = frames_to_transmit
-                .front()
-                .expect("Already checked that the deque is not empty in the for loop")
-                .clone();
-            log::info!(
-                "Sending frame {}/{}",
-                frames_transmitted,
-                total_number_of_frames_to_transmit
-            );
-            tx_a_to_tl
-                .send((current_frame, tx_tl_to_a.clone(), Instant::now()))
-                .expect("Channel TX to TL should not be closed");
-
-            let (acknowledgement, sent_time): (GenericPacket, Instant) = rx_tl_to_a
-                .recv()
-                .expect("Should get an acknowledgment for every sent frame");
-            log::debug!(
-                "Received acknowledgement packet in {:?}- Starting inspection",
-                sent_time.elapsed()
-            );
+    /// What comes out of the line for `packet`, `None` when it is lost.
+    fn transmit<P: Packet, R: Rng + ?Sized>(&mut self, packet: &P, rng: &mut R) -> Option<P> {
+        match self {
+            LineErrors::Random(bit_error_probability) => {
+                Some(packet.simulate_errors_with_probability(*bit_error_probability, rng))
+            }
+            LineErrors::Replayed(replay) => replay.transmit(packet),
+        }
+    }
+}
 
-            if acknowledgement.is_valid() {
-                match acknowledgement {
-                    GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(ack)) => {
-                        if ack.get_ack_and_sequence_byte().1 == expected_sequence_byte {
-                            log::debug!("Packet is a valid ACK - Moving on to next packet");
-                            frames_transmitted += 1;
-                            frames_to_transmit.pop_front();
-                            expected_sequence_byte = flip_sequence_byte(expected_sequence_byte);
-                        } else {
-                            log::debug!("Received duplicate ACK, discarding it silently...");
-                            continue;
-                        }
+fn spawn_transmission_line(
+    direction: &'static str,
+    incoming: mpsc::Receiver<TimestampedPacket>,
+    outgoing: mpsc::Sender<TimestampedPacket>,
+    mut line_errors: LineErrors,
+    one_way_delay: Duration,
+    impairments: LineImpairments,
+    tracer: Tracer,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut rng = rand::rng();
+        let mut corrupted_packets_counter: usize = 0;
+        let mut duplicated_packets_counter: usize = 0;
+        let mut reordered_packets_counter: usize = 0;
+        let mut lost_packets_counter: usize = 0;
+        // Held back packet with the number of packets that still have to overtake it, out of the
+        // number drawn for it
+        type HeldPacket = (usize, usize, GenericPacket, TimestampedPacket);
+        let mut held_packets: Vec<HeldPacket> = Vec::new();
+        // A held packet that was overtaken at all is reordered, otherwise only late
+        let release_held_packet =
+            |held_packet: HeldPacket, reordered_packets_counter: &mut usize| {
+                let (
+                    overtakes,
+                    drawn_overtakes,
+                    transmitted_packet,
+                    (corrupted_packet, send_instant),
+                ) = held_packet;
+                match overtakes < drawn_overtakes {
+                    true => {
+                        *reordered_packets_counter += 1;
+                        tracer.record_impairment(
+                            direction,
+                            TraceAction::Reorder,
+                            &transmitted_packet,
+                            &corrupted_packet,
+                        );
                     }
-                    GenericPacket::Acknowledgement(GenericAcknowledgement::NACK(nack)) => {
-                        if nack.get_ack_and_sequence_byte().1 == expected_sequence_byte {
-                            log::debug!("Received duplicate NACK, discarding it silently...");
+                    false => {
+                        tracer.record_transit(direction, &transmitted_packet, &corrupted_packet)
+                    }
+                }
+                outgoing.send((corrupted_packet, send_instant)).is_ok()
+            };
+        // Ends when the sending endpoint hangs up, which in turn hangs up on the receiving one
+        'line: loop {
+            let received = match held_packets.is_empty() {
+                true => incoming.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                false => incoming.recv_timeout(HELD_PACKET_TIMEOUT),
+            };
+            let (transmitted_packet, send_instant) = match received {
+                Ok(timestamped_packet) => timestamped_packet,
+                Err(RecvTimeoutError::Timeout) => {
+                    for held_packet in held_packets.drain(..) {
+                        if !release_held_packet(held_packet, &mut reordered_packets_counter) {
+                            break 'line;
                         }
-                        log::debug!("Packet is a valid NACK - Retrying same packet");
-                        continue;
                     }
-
-                    _ => panic!("Should not be a Frame here"),
+                    continue;
                 }
-            } else {
-                log::debug!("Acknowledgement packet is invalid - Retrying same packet");
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            // Packets are forwarded in order, so holding each one back delays all of them equally
+            thread::sleep(
+                (send_instant + one_way_delay).saturating_duration_since(time::Instant::now()),
+            );
+            let Some(corrupted_packet) = line_errors.transmit(&transmitted_packet, &mut rng) else {
+                lost_packets_counter += 1;
+                tracer.record_loss(direction, &transmitted_packet);
+                continue;
+            };
+            if corrupted_packet != transmitted_packet {
+                corrupted_packets_counter += 1;
+            }
+            if impairments.reordering_window > 0
+                && rng.random_bool(impairments.reordering_probability)
+            {
+                let overtakes = rng.random_range(1..=impairments.reordering_window);
+                held_packets.push((
+                    overtakes,
+                    overtakes,
+                    transmitted_packet,
+                    (corrupted_packet, send_instant),
+                ));
                 continue;
             }
-        }
-        log::info!("Finished transmission");
-    });
-
-    // Define transfer parameters
-    let bit_error_probability = f64::powi(10.0, -9);
 
-    // TL thread
-    let transmission_line_thread = thread::spawn(move || {
-        let mut rng = rand::rng();
-        let mut corrupted_packets_delivered_counter: usize = 0;
-        loop {
-            let (transmitted_frame, send_back_to_a_tx, send_instant) = match rx_a_to_tl.recv() {
-                Ok(received) => received,
-                Err(_) => {
-                    // Both TX and RX don't know this
-                    log::info!(
-                        "Number of corrupted packets accepted by RX: {}",
-                        corrupted_packets_delivered_counter
-                    );
+            tracer.record_transit(direction, &transmitted_packet, &corrupted_packet);
+            if rng.random_bool(impairments.duplication_probability) {
+                duplicated_packets_counter += 1;
+                tracer.record_impairment(
+                    direction,
+                    TraceAction::Duplicate,
+                    &transmitted_packet,
+                    &corrupted_packet,
+                );
+                if outgoing
+                    .send((corrupted_packet.clone(), send_instant))
+                    .is_err()
+                {
                     break;
-                    // Write to output file
                 }
-            };
+            }
+            if outgoing.send((corrupted_packet, send_instant)).is_err() {
+                break;
+            }
 
-            let corrupted_frame =
-                transmitted_frame.simulate_errors_with_probability(bit_error_probability, &mut rng);
+            for held_packet in &mut held_packets {
+                held_packet.0 -= 1;
+            }
+            while let Some(position) = held_packets
+                .iter()
+                .position(|(overtakes, ..)| *overtakes == 0)
+            {
+                if !release_held_packet(
+                    held_packets.remove(position),
+                    &mut reordered_packets_counter,
+                ) {
+                    break 'line;
+                }
+            }
+        }
+        // The receiving endpoint may still be lingering for them
+        for held_packet in held_packets.drain(..) {
+            if !release_held_packet(held_packet, &mut reordered_packets_counter) {
+                break;
+            }
+        }
+        log::info!(
+            "Number of corrupted packets on {}: {}",
+            direction,
+            corrupted_packets_counter
+        );
+        log::info!(
+            "Number of duplicated, reordered and lost packets on {}: {}, {} and {}",
+            direction,
+            duplicated_packets_counter,
+            reordered_packets_counter,
+            lost_packets_counter
+        );
+    })
+}
+
+fn main() {
+    env_logger::init();
+
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    match arguments.first().map(String::as_str) {
+        Some("diagram") => render_diagram(&arguments[1..]),
+        Some("sweep") => run_sweeps(&arguments[1..]),
+        Some("check") => run_model_check(&arguments[1..]),
+        Some("proxy") => run_proxy(&arguments[1..]),
+        Some("compression") => run_compression_comparison(&arguments[1..]),
+        _ => transfer_files(),
+    }
+}
+
+/// `diagram <trace.jsonl | --simulate> <output prefix>` writes `<prefix>.svg`, `<prefix>.mmd`
+/// and `<prefix>.puml`.
+fn render_diagram(arguments: &[String]) {
+    let [input, output_prefix] = arguments else {
+        eprintln!("Usage: stopandwait diagram <trace.jsonl | --simulate> <output prefix>");
+        std::process::exit(2);
+    };
+    let events = match input.as_str() {
+        "--simulate" => simulate_short_transfer(),
+        path => trace::read_json_lines(BufReader::new(
+            File::open(path).expect("Unable to open trace"),
+        ))
+        .expect("Unable to read trace"),
+    };
+    let diagram = SequenceDiagram::from_events(&events);
+    for (extension, content) in [
+        ("svg", diagram.to_svg()),
+        ("mmd", diagram.to_mermaid()),
+        ("puml", diagram.to_plantuml()),
+    ] {
+        let path = format!("{}.{}", output_prefix, extension);
+        fs::write(&path, content).expect("Failed to write diagram");
+        log::info!("Wrote {}", path);
+    }
+}
+
+/// Traces a transfer of a few small frames over a noisy line, short enough to be drawn. The
+/// line also replays an error trace, duplicates and reorders packets as configured in the
+/// environment.
+fn simulate_short_transfer() -> Vec<TraceEvent> {
+    let (tracer, trace_events) = Tracer::new();
+    let message: Vec<u8> = (0..SIMULATED_MESSAGE_LENGTH_IN_BYTES as u8).collect();
+    run_simulated_transfer(
+        &message,
+        SIMULATED_PAYLOAD_LENGTH_IN_BYTES,
+        LineErrors::from_environment(SIMULATED_BIT_ERROR_PROBABILITY),
+        Duration::ZERO,
+        LineImpairments::from_environment(),
+        EndpointTimers {
+            delayed_acknowledgement: Duration::from_millis(5),
+            retransmission: Duration::from_millis(50),
+            linger: Duration::from_millis(50),
+            ..EndpointTimers::default()
+        },
+        tracer,
+    );
+
+    trace_events.iter().collect()
+}
+
+/// Sends `message` from endpoint A to endpoint B over two simulated lines, without
+/// compression nor encryption, and returns how long the transfer took.
+fn run_simulated_transfer(
+    message: &[u8],
+    full_payload_length_in_bytes: usize,
+    line_errors: LineErrors,
+    one_way_delay: Duration,
+    impairments: LineImpairments,
+    timers: EndpointTimers,
+    tracer: Tracer,
+) -> Duration {
+    let (tx_a_to_tl, rx_a_to_tl) = mpsc::channel();
+    let (tx_tl_to_a, rx_tl_to_a) = mpsc::channel();
+    let (tx_b_to_tl, rx_b_to_tl) = mpsc::channel();
+    let (tx_tl_to_b, rx_tl_to_b) = mpsc::channel();
+    let session_parameters = SessionParameters {
+        compression: Compression::None,
+        ..SESSION_PARAMETERS
+    };
+    let frames_a = prepare_message(
+        message,
+        full_payload_length_in_bytes,
+        &session_parameters,
+        None,
+    )
+    .expect("Unable to split message into frames");
+    let frames_b = prepare_message(&[], full_payload_length_in_bytes, &session_parameters, None)
+        .expect("Unable to split message into frames");
+
+    let transfer_start_time = time::Instant::now();
+    let transmission_line_a_to_b_thread = spawn_transmission_line(
+        "A->B",
+        rx_a_to_tl,
+        tx_tl_to_b,
+        line_errors.clone(),
+        one_way_delay,
+        impairments,
+        tracer.clone(),
+    );
+    let transmission_line_b_to_a_thread = spawn_transmission_line(
+        "B->A",
+        rx_b_to_tl,
+        tx_tl_to_a,
+        line_errors,
+        one_way_delay,
+        impairments,
+        tracer.clone(),
+    );
+    let tracer_b = tracer.clone();
+    let endpoint_b_thread = thread::spawn(move || {
+        run_endpoint(
+            "B",
+            TransmitterStateMachine::new(frames_b),
+            ReceiverStateMachine::new(None, session_parameters.sequence_number_width),
+            tx_b_to_tl,
+            rx_tl_to_b,
+            timers,
+            tracer_b,
+        )
+    });
+    run_endpoint(
+        "A",
+        TransmitterStateMachine::new(frames_a),
+        ReceiverStateMachine::new(None, session_parameters.sequence_number_width),
+        tx_a_to_tl,
+        rx_tl_to_a,
+        timers,
+        tracer,
+    )
+    .expect("Endpoint A failed");
+    let received_by_b = endpoint_b_thread
+        .join()
+        .unwrap()
+        .expect("Endpoint B failed");
+    // B only knows the transfer is complete once it received the end of the stream
+    let transfer_time = transfer_start_time.elapsed();
+    transmission_line_a_to_b_thread.join().unwrap();
+    transmission_line_b_to_a_thread.join().unwrap();
+
+    assert_eq!(
+        received_by_b
+            .into_message()
+            .expect("Failed to decode received frames"),
+        message
+    );
+    transfer_time
+}
 
-            // Send corrupted packet to B
-            tx_tl_to_b
-                .send((corrupted_frame.clone(), tx_b_to_tl.clone(), send_instant))
-                .expect("Receiving channel should not be closed");
+/// `check [flipped bits per byte]` explores a short transfer for every sequence number width
+/// and prints the shortest trace to the first violation found. Exits with 1 on violations.
+fn run_model_check(arguments: &[String]) {
+    let flipped_bits_per_byte = match arguments {
+        [] => CHECKED_FAULT_BOUNDS.flipped_bits_per_byte,
+        [flipped_bits_per_byte] => flipped_bits_per_byte.parse().unwrap_or_else(|_| {
+            eprintln!("Usage: stopandwait check [flipped bits per byte]");
+            std::process::exit(2);
+        }),
+        _ => {
+            eprintln!("Usage: stopandwait check [flipped bits per byte]");
+            std::process::exit(2);
+        }
+    };
+    let fault_bounds = FaultBounds {
+        flipped_bits_per_byte,
+        ..CHECKED_FAULT_BOUNDS
+    };
+    let message: Vec<u8> = (0..CHECKED_MESSAGE_LENGTH_IN_BYTES as u8).collect();
+    let mut has_violations = false;
 
-            // Receive acknowledgement packet
-            let (transmitted_acknowledge, sent_time): (GenericPacket, Instant) =
-                rx_b_to_tl.recv().unwrap();
-            /*
-            if transmitted_frame != corrupted_frame {
-                assert_eq!(corrupted_frame.is_valid(), false);
+    for sequence_number_width in [
+        SequenceNumberWidth::AlternatingBit,
+        SequenceNumberWidth::Bits8,
+        SequenceNumberWidth::Bits16,
+        SequenceNumberWidth::Bits32,
+    ] {
+        let exploration = model_check::explore(
+            &message,
+            CHECKED_PAYLOAD_LENGTH_IN_BYTES,
+            sequence_number_width,
+            fault_bounds,
+        );
+        println!(
+            "{:?}: {} states, {} transitions under {:?}",
+            sequence_number_width, exploration.states, exploration.transitions, fault_bounds
+        );
+        if let Some((violation, steps)) = exploration.violation {
+            has_violations = true;
+            println!("  Violation: {}", violation);
+            for (step_index, step) in steps.iter().enumerate() {
+                println!("  {:>3}. {}", step_index + 1, step);
             }
-             */
-            let corrupted_acknowledge = transmitted_acknowledge
-                .simulate_errors_with_probability(bit_error_probability, &mut rng);
-            /*
-            if transmitted_frame != corrupted_frame
-                && corrupted_acknowledge
-                    == GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(ACK))
-            {
+        }
+    }
+    if has_violations {
+        std::process::exit(1);
+    }
+}
 
-                corrupted_packets_delivered_counter += 1;
+/// `proxy <listen address> <forward address> [option=value ...]` forwards UDP datagrams
+/// between a client and a server through the same impairments in both directions, logging
+/// every one of them, until interrupted.
+fn run_proxy(arguments: &[String]) {
+    let exit_with_usage = || -> ! {
+        eprintln!("{}", PROXY_USAGE);
+        std::process::exit(2);
+    };
+    let [listen_address, forward_address, options @ ..] = arguments else {
+        exit_with_usage();
+    };
+    let probability = |value: &str| {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|probability| (0.0..=1.0).contains(probability))
+    };
+    let milliseconds = |value: &str| value.parse().ok().map(Duration::from_millis);
+    let mut impairments = Impairments {
+        reordering_delay: Duration::from_millis(DEFAULT_PROXY_REORDERING_DELAY_IN_MS),
+        ..Impairments::default()
+    };
+    for option in options {
+        let Some((name, value)) = option.split_once('=') else {
+            exit_with_usage();
+        };
+        let is_valid = match name {
+            "ber" => probability(value)
+                .map(|bit_error_probability| {
+                    impairments.bit_errors = BitErrors::Random(bit_error_probability)
+                })
+                .is_some(),
+            "burst" => match value.split(',').map(probability).collect::<Vec<_>>()[..] {
+                [
+                    Some(good_to_bad_probability),
+                    Some(bad_to_good_probability),
+                    Some(bad_bit_error_probability),
+                ] => {
+                    impairments.bit_errors = BitErrors::Burst(GilbertElliott {
+                        good_to_bad_probability,
+                        bad_to_good_probability,
+                        good_bit_error_probability: 0.0,
+                        bad_bit_error_probability,
+                        is_bad: false,
+                    });
+                    true
+                }
+                _ => false,
+            },
+            "trace" => {
+                let file = File::open(value).expect("Unable to open error trace");
+                let error_trace =
+                    ErrorTrace::read(BufReader::new(file)).expect("Unable to read error trace");
+                impairments.bit_errors = BitErrors::Replayed(error_trace.replay());
+                true
             }
-            */
-            send_back_to_a_tx
-                .send((corrupted_acknowledge, sent_time))
-                .expect("Channel from TL to TX should not be closed");
+            "loss" => probability(value)
+                .map(|loss_probability| impairments.loss_probability = loss_probability)
+                .is_some(),
+            "delay" => milliseconds(value)
+                .map(|delay| impairments.delay = delay)
+                .is_some(),
+            "duplication" => probability(value)
+                .map(|duplication_probability| {
+                    impairments.duplication_probability = duplication_probability
+                })
+                .is_some(),
+            "reordering" => probability(value)
+                .map(|reordering_probability| {
+                    impairments.reordering_probability = reordering_probability
+                })
+                .is_some(),
+            "reordering_delay" => milliseconds(value)
+                .map(|reordering_delay| impairments.reordering_delay = reordering_delay)
+                .is_some(),
+            "rate" => value
+                .parse::<u64>()
+                .ok()
+                .filter(|&rate_in_bits_per_second| rate_in_bits_per_second > 0)
+                .map(|rate_in_bits_per_second| {
+                    impairments.rate_in_bits_per_second = Some(rate_in_bits_per_second)
+                })
+                .is_some(),
+            "seed" => value
+                .parse::<u64>()
+                .map(|seed| impairments.seed = Some(seed))
+                .is_ok(),
+            _ => false,
+        };
+        if !is_valid {
+            eprintln!("Invalid option {}", option);
+            exit_with_usage();
         }
+    }
+
+    let proxy = Proxy::bind(listen_address.as_str(), forward_address.as_str())
+        .expect("Unable to bind proxy sockets");
+    log::info!(
+        "Forwarding {} to {} from {} with {:?}",
+        proxy.listen_address().expect("Proxy socket has an address"),
+        forward_address,
+        proxy
+            .forwarding_address()
+            .expect("Proxy socket has an address"),
+        impairments
+    );
+    // Both lines follow their own draws from the same seed
+    let backward = Impairments {
+        seed: impairments.seed.map(|seed| seed.wrapping_add(1)),
+        ..impairments.clone()
+    };
+    proxy
+        .spawn(impairments, backward)
+        .expect("Unable to start proxy")
+        .join()
+        .expect("Proxy failed");
+}
+
+/// `compression <file> [bit error probability]` simulates the transfer of the file with every
+/// compression in both modes and prints the compression ratio next to the speed and tries.
+fn run_compression_comparison(arguments: &[String]) {
+    let (path, bit_error_probability) = match arguments {
+        [path] => (path, COMPARED_BIT_ERROR_PROBABILITY),
+        [path, bit_error_probability] => (
+            path,
+            bit_error_probability.parse().unwrap_or_else(|_| {
+                eprintln!("{}", COMPRESSION_USAGE);
+                std::process::exit(2);
+            }),
+        ),
+        _ => {
+            eprintln!("{}", COMPRESSION_USAGE);
+            std::process::exit(2);
+        }
+    };
+    let payload_to_transfer = fs::read(path).expect("Unable to read file");
+
+    for compression_mode in [CompressionMode::PerFrame, CompressionMode::PerStream] {
+        for compression in SUPPORTED_COMPRESSIONS {
+            let results = simulate_transfer(
+                &payload_to_transfer,
+                FULL_PAYLOAD_LENGTH_IN_BYTES,
+                LineErrors::Random(bit_error_probability),
+                &SessionParameters {
+                    compression,
+                    compression_mode,
+                    ..SESSION_PARAMETERS
+                },
+                None,
+            );
+            println!("{:?} {:?} results:", compression, compression_mode);
+            println!("{}\n", results);
+        }
+    }
+}
+
+/// `sweep <output directory>` measures simulated transfers and writes every chart both as SVG
+/// and as PNG.
+fn run_sweeps(arguments: &[String]) {
+    let [output_directory] = arguments else {
+        eprintln!("Usage: stopandwait sweep <output directory>");
+        std::process::exit(2);
+    };
+    fs::create_dir_all(output_directory).expect("Unable to create output directory");
+    let mut payload_to_transfer = vec![0u8; SWEEP_MESSAGE_LENGTH_IN_BYTES];
+    rand::rng().fill(payload_to_transfer.as_mut_slice());
+
+    for (name, chart) in [
+        (
+            "efficiency_vs_frame_size",
+            sweep_efficiency_vs_frame_size(&payload_to_transfer),
+        ),
+        (
+            "tries_vs_bit_error_probability",
+            sweep_tries_vs_bit_error_probability(&payload_to_transfer),
+        ),
+        ("goodput_vs_rtt", sweep_goodput_vs_rtt(&payload_to_transfer)),
+    ] {
+        for extension in ["svg", "png"] {
+            let path = Path::new(output_directory).join(format!("{}.{}", name, extension));
+            chart.render(&path).expect("Failed to write chart");
+            log::info!("Wrote {}", path.display());
+        }
+    }
+}
+
+fn sweep_efficiency_vs_frame_size(payload_to_transfer: &[u8]) -> Chart {
+    let overhead_in_bytes = Frame::overhead_in_bytes(SESSION_PARAMETERS.sequence_number_width);
+    let session_parameters = SessionParameters {
+        compression: Compression::None,
+        ..SESSION_PARAMETERS
+    };
+    // Every frame size starts the line over, so that a replayed trace starts from its beginning
+    let series_for = |label: String, line_errors: LineErrors| Series {
+        label,
+        measured: SWEEP_FRAME_SIZES_IN_BYTES
+            .iter()
+            .map(|&full_payload_length_in_bytes| {
+                let results = simulate_transfer(
+                    payload_to_transfer,
+                    full_payload_length_in_bytes,
+                    line_errors.clone(),
+                    &session_parameters,
+                    None,
+                );
+                (full_payload_length_in_bytes as f64, results.line_efficiency)
+            })
+            .collect(),
+        // Random errors at the same rate, which bursts of a replayed trace depart from
+        analytic: (SWEEP_FRAME_SIZES_IN_BYTES[0]
+            ..=SWEEP_FRAME_SIZES_IN_BYTES[SWEEP_FRAME_SIZES_IN_BYTES.len() - 1])
+            .step_by(16)
+            .map(|payload_bytes| {
+                (
+                    payload_bytes as f64,
+                    analytic::efficiency(
+                        payload_bytes,
+                        overhead_in_bytes,
+                        line_errors.bit_error_rate(),
+                    ),
+                )
+            })
+            .collect(),
+    };
+    let mut series: Vec<Series> = SWEEP_BIT_ERROR_PROBABILITIES
+        .iter()
+        .map(|&bit_error_probability| {
+            series_for(
+                format!("BER {:.0e}", bit_error_probability),
+                LineErrors::Random(bit_error_probability),
+            )
+        })
+        .collect();
+    if let Some(error_trace) = read_error_trace() {
+        let line_errors = LineErrors::Replayed(error_trace.replay());
+        series.push(series_for(
+            format!("Replayed trace, BER {:.0e}", line_errors.bit_error_rate()),
+            line_errors,
+        ));
+    }
+    Chart {
+        title: "Line efficiency vs frame size".to_owned(),
+        x_label: "Payload per frame [B]".to_owned(),
+        y_label: "Payload bits / transmitted bits".to_owned(),
+        logarithmic_x: false,
+        series,
+    }
+}
+
+fn sweep_tries_vs_bit_error_probability(payload_to_transfer: &[u8]) -> Chart {
+    let frame_bytes = SWEEP_FIXED_FRAME_SIZE_IN_BYTES
+        + Frame::overhead_in_bytes(SESSION_PARAMETERS.sequence_number_width);
+    let session_parameters = SessionParameters {
+        compression: Compression::None,
+        ..SESSION_PARAMETERS
+    };
+    let bit_error_probabilities: Vec<f64> = (0..=12)
+        .map(|step| 1e-6 * 10f64.powf(step as f64 / 5.0))
+        .collect();
+    let series = Series {
+        label: format!("{} B frames", SWEEP_FIXED_FRAME_SIZE_IN_BYTES),
+        measured: bit_error_probabilities
+            .iter()
+            .map(|&bit_error_probability| {
+                let results = simulate_transfer(
+                    payload_to_transfer,
+                    SWEEP_FIXED_FRAME_SIZE_IN_BYTES,
+                    LineErrors::Random(bit_error_probability),
+                    &session_parameters,
+                    None,
+                );
+                (bit_error_probability, results.average_tries)
+            })
+            .collect(),
+        analytic: bit_error_probabilities
+            .iter()
+            .map(|&bit_error_probability| {
+                (
+                    bit_error_probability,
+                    analytic::expected_tries(bit_error_probability, frame_bytes),
+                )
+            })
+            .collect(),
+    };
+    Chart {
+        title: "Average tries vs bit error probability".to_owned(),
+        x_label: "Bit error probability".to_owned(),
+        y_label: "Tries per frame".to_owned(),
+        logarithmic_x: true,
+        series: vec![series],
+    }
+}
+
+fn sweep_goodput_vs_rtt(payload_to_transfer: &[u8]) -> Chart {
+    let message = &payload_to_transfer[..SWEEP_RTT_MESSAGE_LENGTH_IN_BYTES];
+    let overhead_in_bytes = Frame::overhead_in_bytes(SESSION_PARAMETERS.sequence_number_width);
+    let frames = message.len().div_ceil(SWEEP_FIXED_FRAME_SIZE_IN_BYTES) as f64;
+    let measured: Vec<(f64, f64)> = SWEEP_ROUND_TRIP_TIMES_IN_MS
+        .iter()
+        .map(|&round_trip_time_in_ms| {
+            let round_trip_time = Duration::from_millis(round_trip_time_in_ms);
+            let transfer_time = run_simulated_transfer(
+                message,
+                SWEEP_FIXED_FRAME_SIZE_IN_BYTES,
+                LineErrors::Random(SWEEP_RTT_BIT_ERROR_PROBABILITY),
+                round_trip_time / 2,
+                LineImpairments::default(),
+                EndpointTimers {
+                    // Acknowledgements must not wait, so that only the line adds to the RTT
+                    delayed_acknowledgement: Duration::ZERO,
+                    retransmission: 4 * round_trip_time + Duration::from_millis(50),
+                    linger: Duration::from_millis(50),
+                    ..EndpointTimers::default()
+                },
+                Tracer::disabled(),
+            );
+            (
+                round_trip_time_in_ms as f64,
+                message.len() as f64 / transfer_time.as_secs_f64() / 1000.0,
+            )
+        })
+        .collect();
+    // Processing time per frame, calibrated on the transfer without delay
+    let per_frame_time =
+        Duration::from_secs_f64(message.len() as f64 / (measured[0].1 * 1000.0) / frames);
+    let last_round_trip_time = SWEEP_ROUND_TRIP_TIMES_IN_MS[SWEEP_ROUND_TRIP_TIMES_IN_MS.len() - 1];
+    let analytic = (0..=last_round_trip_time * 4)
+        .map(|quarter_ms| {
+            let round_trip_time = Duration::from_micros(quarter_ms * 250);
+            (
+                quarter_ms as f64 / 4.0,
+                analytic::goodput(
+                    SWEEP_FIXED_FRAME_SIZE_IN_BYTES,
+                    overhead_in_bytes,
+                    SWEEP_RTT_BIT_ERROR_PROBABILITY,
+                    round_trip_time,
+                    per_frame_time,
+                ) / 1000.0,
+            )
+        })
+        .collect();
+    Chart {
+        title: "Goodput vs round trip time".to_owned(),
+        x_label: "Round trip time [ms]".to_owned(),
+        y_label: "Goodput [kB/s]".to_owned(),
+        logarithmic_x: false,
+        series: vec![Series {
+            label: format!(
+                "{} B frames, BER {:.0e}",
+                SWEEP_FIXED_FRAME_SIZE_IN_BYTES, SWEEP_RTT_BIT_ERROR_PROBABILITY
+            ),
+            measured,
+            analytic,
+        }],
+    }
+}
+
+/// Receiver whose message is written to `output_path` by a thread of its own as the payloads
+/// arrive, so that a slow disk holds the peer back instead of filling the memory.
+fn spawn_file_writer(
+    pre_shared_key: Option<PreSharedKey>,
+    output_path: PathBuf,
+) -> (
+    ReceiverStateMachine,
+    thread::JoinHandle<stopandwait::Result<()>>,
+) {
+    let mut receiver =
+        ReceiverStateMachine::new(pre_shared_key, SESSION_PARAMETERS.sequence_number_width);
+    receiver.set_buffer_limit(Some(RECEIVE_BUFFER_LIMIT_IN_BYTES));
+    let (payload_sink, payloads) = mpsc::sync_channel(FILE_WRITER_QUEUE_LENGTH);
+    receiver.set_payload_sink(payload_sink);
+    let writer_thread = thread::spawn(move || {
+        let output = BufWriter::new(File::create(&output_path)?);
+        write_message(payloads, output)?;
+        log::info!("Wrote {}", output_path.display());
+        Ok(())
     });
+    (receiver, writer_thread)
+}
 
-    // RX thread
-    let receiver_thread = thread::spawn(move || {
-        let mut received_frame_count: usize = 0;
-        let mut received_frames: Vec<Frame> = Vec::with_capacity(2 ^ 20);
-        let mut current_expected_package = SEQUENCE_ZERO;
-        let mut current_ack_with_next_expected_package = ACK_ZERO;
-
-        loop {
-            let (received_frame, reply_tx, send_instant) = match rx_tl_to_b.recv() {
-                Ok(received) => received,
-                Err(_) => {
-                    log::info!(
-                        "Tx has closed channel, stopped receiving and closing Rx channel as well"
-                    );
-                    let mut received_bytes_vec =
-                        Vec::with_capacity(received_frames.len() * FULL_PAYLOAD_LENGTH_IN_BYTES);
-                    for received_frame in received_frames {
-                        received_bytes_vec.append(
-                            &mut received_frame
-                                .get_payload_and_checksum_and_sequence_byte()
-                                .0,
-                        );
-                    }
+/// Outcome of an endpoint whose receiver fed `writer_thread`. A failing writer leaves the
+/// endpoint with a closed sink, so its error comes before the [`ChannelClosed`] it causes.
+///
+/// [`ChannelClosed`]: stopandwait::Error::ChannelClosed
+fn join_file_writer(
+    endpoint_result: stopandwait::Result<ReceiverStateMachine>,
+    writer_thread: thread::JoinHandle<stopandwait::Result<()>>,
+) -> stopandwait::Result<()> {
+    let writer_result = writer_thread.join().unwrap();
+    writer_result.and(endpoint_result.map(|_| ()))
+}
 
-                    let output_file_string =
-                        FOLDER_PREFIX.to_owned() + "received." + &file_extension;
-                    let output_file_path = Path::new(&output_file_string);
+fn transfer_files() {
+    let (tx_a_to_tl, rx_a_to_tl) = mpsc::channel();
+    let (tx_tl_to_a, rx_tl_to_a) = mpsc::channel();
+    let (tx_b_to_tl, rx_b_to_tl) = mpsc::channel();
+    let (tx_tl_to_b, rx_tl_to_b) = mpsc::channel();
 
-                    log::info!("Writing to output file");
-                    fs::write(output_file_path, received_bytes_vec)
-                        .expect("Failed to write to file");
-                    break;
-                    // Write to output file
-                }
-            };
+    let trace_prefix = read_trace_prefix();
+    let is_dashboard_enabled = is_dashboard_enabled();
+    let (tracer, trace_events) = match trace_prefix.is_some() || is_dashboard_enabled {
+        true => {
+            let (tracer, trace_events) = Tracer::new();
+            (tracer, Some(trace_events))
+        }
+        false => (Tracer::disabled(), None),
+    };
+    // Define transfer parameters
+    let line_errors = LineErrors::from_environment(f64::powi(10.0, -9));
+    let impairments = LineImpairments::from_environment();
+
+    log::info!("Waiting for file input");
 
-            log::debug!(
-                "Received frame in {:?} - Starting processing",
-                send_instant.elapsed()
+    // Ask for input file
+    let file_to_transfer = ask_for_input_file_and_return_it().expect("Unable to read input file");
+    let file_to_send_back =
+        ask_for_optional_file_to_send_back().expect("Unable to read file to send back");
+    let cloned_content = file_to_transfer.content.clone();
+    let cloned_content_sent_back = file_to_send_back.as_ref().map(|file| file.content.clone());
+    // Received copies keep the extensions of the original files
+    let output_path = file_to_transfer.output_path("received");
+    let not_passed_output_path = output_path.clone();
+    let output_path_sent_back = file_to_send_back
+        .as_ref()
+        .map(|file| file.output_path("received_back"));
+    let not_passed_output_path_sent_back = output_path_sent_back.clone();
+    // Both endpoints share the key out of band, the salt is announced in the session header
+    let pre_shared_key = read_pre_shared_key();
+    let is_adaptive_enabled = is_adaptive_enabled();
+    let prepare_transmitter = move |content: &[u8]| {
+        let session_parameters = SessionParameters {
+            encryption: match pre_shared_key {
+                Some(_) => Encryption::random_xchacha20poly1305(),
+                None => Encryption::None,
+            },
+            ..SESSION_PARAMETERS
+        };
+        let session_cipher = build_session_cipher(&session_parameters, pre_shared_key.as_ref())
+            .expect("Unable to build session cipher");
+        if is_adaptive_enabled {
+            let frame_source = AdaptiveFrameSource::new(
+                content,
+                FULL_PAYLOAD_LENGTH_IN_BYTES,
+                ADAPTIVE_PAYLOAD_LENGTH_BOUNDS_IN_BYTES,
+                &session_parameters,
+                session_cipher,
+            )
+            .expect("Unable to split file into frames");
+            return (
+                TransmitterStateMachine::adaptive(frame_source),
+                pre_shared_key.clone(),
             );
-            let start_processing_time = Instant::now();
-            let is_received_frame_valid = received_frame.is_valid();
-            log::debug!(
-                "Received frame - finished processing, took {:?} - Valid {}",
-                start_processing_time.elapsed(),
-                is_received_frame_valid
+        }
+        let frames = prepare_message(
+            content,
+            FULL_PAYLOAD_LENGTH_IN_BYTES,
+            &session_parameters,
+            session_cipher.as_ref(),
+        )
+        .expect("Unable to split file into frames");
+        let transmitted_payload_bytes = transmitted_payload_length_in_bytes(&frames);
+        log::info!(
+            "Compressed {} B into {} B with {:?} ({:?}), ratio {:.2}",
+            content.len(),
+            transmitted_payload_bytes,
+            session_parameters.compression,
+            session_parameters.compression_mode,
+            content.len() as f64 / transmitted_payload_bytes as f64
+        );
+        (TransmitterStateMachine::new(frames), pre_shared_key.clone())
+    };
+    let (transmitter_a, pre_shared_key_a) = prepare_transmitter(&file_to_transfer.content);
+    let (transmitter_b, pre_shared_key_b) = prepare_transmitter(
+        file_to_send_back
+            .as_ref()
+            .map(|file| file.content.as_slice())
+            .unwrap_or_default(),
+    );
+
+    // The dashboard consumes the trace, handing it over for export once the transfer is over
+    let (dashboard_thread, trace_events) = match (is_dashboard_enabled, trace_events) {
+        (true, Some(trace_events)) => {
+            let statistics = DashboardStatistics::new(
+                &[
+                    ("A", transmitter_a.progress().1),
+                    ("B", transmitter_b.progress().1),
+                ],
+                line_errors.bit_error_rate(),
             );
+            let keep_events = trace_prefix.is_some();
+            let dashboard_thread = thread::spawn(move || {
+                run_dashboard(trace_events, statistics, keep_events)
+                    .expect("Failed to draw dashboard")
+            });
+            (Some(dashboard_thread), None)
+        }
+        (_, trace_events) => (None, trace_events),
+    };
 
-            if is_received_frame_valid {
-                if received_frame
-                    .get_payload_and_checksum_and_sequence_byte()
-                    .2
-                    == current_expected_package
-                {
-                    current_expected_package = flip_sequence_byte(current_expected_package);
-                    current_ack_with_next_expected_package = ACK::new(current_expected_package);
-                    received_frame_count += 1;
-                    received_frames.push(received_frame);
-                    log::debug!(
-                        "Sending ACK {} for packet {}",
-                        current_expected_package,
-                        received_frame_count
-                    );
-                    reply_tx
-                        .send((
-                            GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(
-                                current_ack_with_next_expected_package,
-                            )),
-                            Instant::now(),
-                        ))
-                        .unwrap();
-                } else {
-                    log::debug!("Received duplicate frame, discarding it silently...");
-                    log::debug!(
-                        "Sending ACK {} for packet {}",
-                        current_expected_package,
-                        received_frame_count
-                    );
-                    reply_tx
-                        .send((
-                            GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(
-                                current_ack_with_next_expected_package,
-                            )),
-                            Instant::now(),
-                        ))
-                        .unwrap();
-                }
-            } else {
-                let received_sequence_byte = received_frame
-                    .get_payload_and_checksum_and_sequence_byte()
-                    .2;
-                log::debug!("Sending NACK for packet {}", received_frame_count);
-                reply_tx
-                    .send((
-                        GenericPacket::Acknowledgement(GenericAcknowledgement::NACK(NACK::new(
-                            received_sequence_byte,
-                        ))),
-                        Instant::now(),
-                    ))
-                    .unwrap();
+    // Endpoint A sends the picked file and receives the one sent back
+    let tracer_a = tracer.clone();
+    let endpoint_a_thread = thread::spawn(move || {
+        let (receiver, writer_thread) = match output_path_sent_back {
+            Some(output_path_sent_back) => {
+                let (receiver, writer_thread) =
+                    spawn_file_writer(pre_shared_key_a, output_path_sent_back);
+                (receiver, Some(writer_thread))
             }
+            None => (
+                ReceiverStateMachine::new(
+                    pre_shared_key_a,
+                    SESSION_PARAMETERS.sequence_number_width,
+                ),
+                None,
+            ),
+        };
+        let endpoint_result = run_endpoint(
+            "A",
+            transmitter_a,
+            receiver,
+            tx_a_to_tl,
+            rx_tl_to_a,
+            EndpointTimers::default(),
+            tracer_a,
+        );
+        match writer_thread {
+            Some(writer_thread) => join_file_writer(endpoint_result, writer_thread),
+            None => endpoint_result.map(|_| ()),
         }
     });
 
+    // TL threads, one per direction
+    let transmission_line_a_to_b_thread = spawn_transmission_line(
+        "A->B",
+        rx_a_to_tl,
+        tx_tl_to_b,
+        line_errors.clone(),
+        Duration::ZERO,
+        impairments,
+        tracer.clone(),
+    );
+    let transmission_line_b_to_a_thread = spawn_transmission_line(
+        "B->A",
+        rx_b_to_tl,
+        tx_tl_to_a,
+        line_errors,
+        Duration::ZERO,
+        impairments,
+        tracer.clone(),
+    );
+
+    // Endpoint B receives the picked file and sends back the optional second one
+    let endpoint_b_thread = thread::spawn(move || {
+        let (receiver, writer_thread) = spawn_file_writer(pre_shared_key_b, output_path);
+        let endpoint_result = run_endpoint(
+            "B",
+            transmitter_b,
+            receiver,
+            tx_b_to_tl,
+            rx_tl_to_b,
+            EndpointTimers::default(),
+            tracer,
+        );
+        join_file_writer(endpoint_result, writer_thread)
+    });
+
     let cleaning_thread = std::thread::spawn(move || {
-        transmitter_thread.join().unwrap();
-        log::info!("Finished transmitting");
+        let result_a = endpoint_a_thread.join().unwrap();
+        log::info!("Finished endpoint A");
 
-        receiver_thread.join().unwrap();
-        log::info!("Finished receiving");
+        let result_b = endpoint_b_thread.join().unwrap();
+        log::info!("Finished endpoint B");
 
-        transmission_line_thread.join().unwrap();
+        transmission_line_a_to_b_thread.join().unwrap();
+        transmission_line_b_to_a_thread.join().unwrap();
+        // Every tracer is gone with its thread, so the trace is complete
+        let events: Option<Vec<TraceEvent>> = match dashboard_thread {
+            Some(dashboard_thread) => Some(dashboard_thread.join().unwrap()),
+            None => trace_events.map(|trace_events| trace_events.iter().collect()),
+        };
+        // The trace is kept even for a failed transfer, it is what explains the failure
+        if let (Some(trace_prefix), Some(events)) = (trace_prefix, events) {
+            log::info!("Writing {} trace events to {}", events.len(), trace_prefix);
+            write_trace(&trace_prefix, &events).expect("Failed to write trace");
+        }
+        for (name, result) in [("A", result_a), ("B", result_b)] {
+            if let Err(error) = result {
+                log::error!("Endpoint {} failed: {}", name, error);
+                std::process::exit(1);
+            }
+        }
 
         log::info!("Asserting that input file is equal to output file");
-        assert_eq!(
-            cloned_content,
-            fs::read(FOLDER_PREFIX.to_owned() + "received." + &not_passed_file_extension).unwrap()
-        );
+        assert_eq!(cloned_content, fs::read(not_passed_output_path).unwrap());
+        if let (Some(content), Some(output_path_sent_back)) =
+            (cloned_content_sent_back, not_passed_output_path_sent_back)
+        {
+            log::info!("Asserting that file sent back is equal to its output file");
+            assert_eq!(content, fs::read(output_path_sent_back).unwrap());
+        }
 
         log::info!("Successful transfer");
     });
@@ -0,0 +1,272 @@
+use std::{collections::VecDeque, io::Write};
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    compression::CompressionMode,
+    encryption::{Encryption, PreSharedKey, SessionCipher},
+    error::{DecodeError, Error, Result},
+    packets::{Packet, frame::Frame, sequence::SequenceNumber},
+    session::SessionParameters,
+};
+
+/// Splits a message into the frames of one transfer.
+///
+/// The first frame always announces the session parameters and the last one carries an empty
+/// payload to mark the end of the stream. Data payloads in between are never empty, so the
+/// end-of-stream frame is unambiguous. An empty message is therefore sent as the header
+/// followed right away by the end of the stream.
+pub fn prepare_message(
+    payload_to_transfer: &[u8],
+    full_payload_length_in_bytes: usize,
+    session_parameters: &SessionParameters,
+    session_cipher: Option<&SessionCipher>,
+) -> Result<VecDeque<Frame>> {
+    if full_payload_length_in_bytes == 0 || !full_payload_length_in_bytes.is_multiple_of(8) {
+        return Err(Error::InvalidPayloadLength(full_payload_length_in_bytes));
+    }
+
+    let payloads = session_parameters.compression_mode.compress_into_payloads(
+        session_parameters.compression,
+        payload_to_transfer,
+        full_payload_length_in_bytes,
+    );
+
+    let mut frames_to_be_transmitted: VecDeque<Frame> = VecDeque::with_capacity(payloads.len() + 2);
+    let mut current_sequence_number =
+        SequenceNumber::zero(session_parameters.sequence_number_width);
+    let session_header = session_parameters.to_bytes();
+    // Plaintext frames are all written into this buffer, and share it
+    let frame_overhead_in_bytes = Frame::overhead_in_bytes(current_sequence_number.width());
+    let mut buffer = BytesMut::with_capacity(match session_cipher {
+        Some(_) => session_header.len() + frame_overhead_in_bytes,
+        None => {
+            session_header.len()
+                + payloads.iter().map(Bytes::len).sum::<usize>()
+                + (payloads.len() + 2) * frame_overhead_in_bytes
+        }
+    });
+    frames_to_be_transmitted.push_back(Frame::new_in(
+        &mut buffer,
+        &session_header,
+        current_sequence_number,
+    ));
+    current_sequence_number = current_sequence_number.next();
+    for payload in payloads.iter().map(|payload| &payload[..]).chain([&[][..]]) {
+        let frame_index = frames_to_be_transmitted.len() as u64;
+        frames_to_be_transmitted.push_back(match session_cipher {
+            Some(session_cipher) => {
+                session_cipher.seal(frame_index, payload, current_sequence_number)
+            }
+            None => Frame::new_in(&mut buffer, payload, current_sequence_number),
+        });
+        current_sequence_number = current_sequence_number.next();
+    }
+
+    assert!(
+        frames_to_be_transmitted
+            .iter()
+            .enumerate()
+            .all(|(frame_index, frame)| {
+                frame
+                    .get_payload_and_checksum_and_sequence_number()
+                    .is_ok_and(|(_, _, sequence_number)| {
+                        sequence_number
+                            == SequenceNumber::for_frame_index(
+                                frame_index as u64,
+                                session_parameters.sequence_number_width,
+                            )
+                    })
+            })
+    );
+
+    Ok(frames_to_be_transmitted)
+}
+
+/// Returns the payload of `frame` if it passes the CRC or, for encrypted sessions, the
+/// authentication tag. The session header frame is never encrypted, and plaintext payloads
+/// share the bytes of the frame.
+pub fn open_frame(
+    frame: &Frame,
+    frame_index: u64,
+    session_cipher: Option<&SessionCipher>,
+) -> Result<Bytes> {
+    match session_cipher {
+        Some(session_cipher) if frame_index > 0 => {
+            session_cipher.open(frame_index, frame).map(Bytes::from)
+        }
+        _ => {
+            let (payload, _, _) = frame.get_payload_and_checksum_and_sequence_number()?;
+            match frame.is_valid() {
+                true => Ok(payload),
+                false => Err(Error::ChecksumMismatch),
+            }
+        }
+    }
+}
+
+pub fn build_session_cipher(
+    session_parameters: &SessionParameters,
+    pre_shared_key: Option<&PreSharedKey>,
+) -> Result<Option<SessionCipher>> {
+    if session_parameters.encryption == Encryption::None {
+        return Ok(None);
+    }
+    Ok(SessionCipher::new(
+        pre_shared_key.ok_or(Error::MissingPreSharedKey)?,
+        session_parameters.encryption,
+        &session_parameters.to_bytes(),
+    ))
+}
+
+/// Decodes the session header carried by the first payload and decompresses the remaining ones.
+/// The empty end-of-stream payload may be included or not.
+pub fn reassemble_message(payloads: &[Bytes]) -> Result<Vec<u8>> {
+    let (session_header, data_payloads) = payloads
+        .split_first()
+        .ok_or(DecodeError::InvalidSessionHeader)?;
+    let session_parameters = SessionParameters::from_bytes(session_header)?;
+
+    session_parameters.compression_mode.decompress_payloads(
+        session_parameters.compression,
+        data_payloads
+            .iter()
+            .filter(|payload| !payload.is_empty())
+            .map(|payload| &payload[..]),
+    )
+}
+
+/// Streaming counterpart of [`reassemble_message`], writing the message to `output` as the
+/// payloads come. Per-stream compressed payloads can only be decompressed once they are all
+/// there, so they are gathered first.
+pub fn write_message(
+    payloads: impl IntoIterator<Item = Bytes>,
+    mut output: impl Write,
+) -> Result<()> {
+    let mut payloads = payloads.into_iter();
+    let session_header = payloads.next().ok_or(DecodeError::InvalidSessionHeader)?;
+    let session_parameters = SessionParameters::from_bytes(&session_header)?;
+    let compression = session_parameters.compression;
+    let data_payloads = payloads.filter(|payload| !payload.is_empty());
+    match session_parameters.compression_mode {
+        CompressionMode::PerFrame => {
+            for payload in data_payloads {
+                output.write_all(&compression.decompress(&payload)?)?;
+            }
+        }
+        CompressionMode::PerStream => {
+            let stream: Vec<u8> = data_payloads.flatten().collect();
+            output.write_all(&compression.decompress(&stream)?)?;
+        }
+    }
+    output.flush()?;
+    Ok(())
+}
+
+#[test]
+fn empty_and_tiny_messages_round_trip() {
+    use crate::compression::SUPPORTED_COMPRESSIONS;
+
+    for compression in SUPPORTED_COMPRESSIONS {
+        for compression_mode in [CompressionMode::PerFrame, CompressionMode::PerStream] {
+            let session_parameters = SessionParameters {
+                compression,
+                compression_mode,
+                ..SessionParameters::default()
+            };
+            for message in [&[][..], &[42], &[1, 2, 3, 4, 5, 6, 7, 8, 9]] {
+                let frames = prepare_message(message, 8, &session_parameters, None).unwrap();
+                let payloads: Vec<Bytes> = frames
+                    .iter()
+                    .enumerate()
+                    .map(|(frame_index, frame)| {
+                        open_frame(frame, frame_index as u64, None).unwrap()
+                    })
+                    .collect();
+                assert!(payloads.last().unwrap().is_empty());
+                assert_eq!(
+                    reassemble_message(&payloads).unwrap(),
+                    message,
+                    "{:?} {:?}",
+                    compression,
+                    compression_mode
+                );
+                let mut written_message = Vec::new();
+                write_message(payloads, &mut written_message).unwrap();
+                assert_eq!(written_message, message);
+            }
+        }
+    }
+
+    assert!(matches!(
+        prepare_message(&[], 0, &SessionParameters::default(), None),
+        Err(Error::InvalidPayloadLength(0))
+    ));
+    assert!(matches!(
+        reassemble_message(&[]),
+        Err(Error::Decode(DecodeError::InvalidSessionHeader))
+    ));
+
+    // Plaintext frames follow each other in a single buffer
+    let frames = prepare_message(&[7; 100], 16, &SessionParameters::default(), None).unwrap();
+    for (frame, next_frame) in frames.iter().zip(frames.iter().skip(1)) {
+        assert_eq!(
+            frame.content.as_ptr_range().end,
+            next_frame.content.as_ptr()
+        );
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn frames_are_numbered_in_order_and_reassemble_into_the_message(
+        message in proptest::collection::vec(proptest::num::u8::ANY, 0..20_000),
+        payload_blocks in 1usize..512,
+        compression in proptest::sample::select(
+            crate::compression::SUPPORTED_COMPRESSIONS.to_vec()
+        ),
+        compression_mode in proptest::sample::select(vec![
+            crate::compression::CompressionMode::PerFrame,
+            crate::compression::CompressionMode::PerStream,
+        ]),
+        sequence_number_width in proptest::sample::select(vec![
+            crate::packets::sequence::SequenceNumberWidth::AlternatingBit,
+            crate::packets::sequence::SequenceNumberWidth::Bits8,
+            crate::packets::sequence::SequenceNumberWidth::Bits16,
+            crate::packets::sequence::SequenceNumberWidth::Bits32,
+        ]),
+    ) {
+        let session_parameters = SessionParameters {
+            compression,
+            compression_mode,
+            sequence_number_width,
+            ..SessionParameters::default()
+        };
+        let frames =
+            prepare_message(&message, payload_blocks * 8, &session_parameters, None).unwrap();
+
+        let sequence_numbers: Vec<SequenceNumber> = frames
+            .iter()
+            .map(|frame| frame.get_payload_and_checksum_and_sequence_number().unwrap().2)
+            .collect();
+        proptest::prop_assert_eq!(
+            sequence_numbers[0],
+            SequenceNumber::zero(sequence_number_width)
+        );
+        for pair in sequence_numbers.windows(2) {
+            proptest::prop_assert_eq!(pair[1], pair[0].next());
+            proptest::prop_assert_ne!(pair[1], pair[0]);
+        }
+
+        let payloads: Vec<Bytes> = frames
+            .iter()
+            .enumerate()
+            .map(|(frame_index, frame)| open_frame(frame, frame_index as u64, None).unwrap())
+            .collect();
+        proptest::prop_assert!(payloads[1..payloads.len() - 1]
+            .iter()
+            .all(|payload| !payload.is_empty()));
+        proptest::prop_assert_eq!(reassemble_message(&payloads).unwrap(), message);
+    }
+}
@@ -0,0 +1,545 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use bytes::Bytes;
+
+use crate::{
+    compression::Compression,
+    endpoint::{FrameOutcome, ReceiverStateMachine, TransmitterAction, TransmitterStateMachine},
+    message::prepare_message,
+    packets::{
+        acknowledgement::GenericAcknowledgement,
+        frame::{Frame, flip_bit_in_u8},
+        sequence::SequenceNumberWidth,
+    },
+    session::SessionParameters,
+};
+
+// Exhaustive exploration of every interleaving of a transfer between the transmitter and the
+// receiver state machines over two lines that lose, corrupt and duplicate a bounded number of
+// packets. The retransmission timer only expires once both lines are empty, so it never fires
+// early: duplicated frames come from the line alone.
+
+/// Faults the lines may inject over a whole transfer, in both directions together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultBounds {
+    pub losses: u8,
+    pub corruptions: u8,
+    pub duplications: u8,
+    /// A corruption flips the lowest 1 to `flipped_bits_per_byte` bits of a single byte of the
+    /// frame trailer or of the acknowledgement.
+    pub flipped_bits_per_byte: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    TransmitterToReceiver,
+    ReceiverToTransmitter,
+}
+
+/// A transition between two states of the transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    DeliverFrame {
+        frame_index: usize,
+        outcome: FrameOutcome,
+    },
+    DeliverAcknowledgement(TransmitterAction),
+    RetransmissionTimeout,
+    Lose(Direction),
+    Duplicate(Direction),
+    Corrupt {
+        direction: Direction,
+        byte_index: usize,
+        flipped_bits: u8,
+    },
+}
+
+/// A property of the protocol that does not hold in some reachable state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Violation {
+    /// The receiver accepted another frame than the next one of the message.
+    OutOfOrderDelivery {
+        frame_index: usize,
+        expected_frame_index: usize,
+    },
+    /// The transmitter moved past a frame that the receiver has not accepted.
+    PrematureRelease {
+        released_frame_count: usize,
+        received_frame_count: usize,
+    },
+    /// The receiver gave up on a frame of the transfer.
+    ReceiverFailure(String),
+    /// The transfer can no longer complete, whatever the lines do.
+    NoProgress,
+}
+
+/// Outcome of [`explore`].
+#[derive(Debug)]
+pub struct Exploration {
+    pub states: usize,
+    pub transitions: usize,
+    /// First violation found, with the shortest sequence of steps leading to it.
+    pub violation: Option<(Violation, Vec<Step>)>,
+}
+
+#[derive(Clone)]
+struct State {
+    transmitter: TransmitterStateMachine,
+    receiver: ReceiverStateMachine,
+    /// Frames on the line together with their index in the message.
+    frames_in_transit: VecDeque<(usize, Frame)>,
+    acknowledgements_in_transit: VecDeque<GenericAcknowledgement>,
+    remaining_losses: u8,
+    remaining_corruptions: u8,
+    remaining_duplications: u8,
+}
+
+/// Everything that tells two states apart: both state machines only depend on how many frames
+/// they went through as long as no violation was found.
+#[derive(PartialEq, Eq, Hash)]
+struct StateKey {
+    released_frame_count: usize,
+    received_frame_count: usize,
+    is_receiver_finished: bool,
+    frames_in_transit: Vec<(usize, Bytes)>,
+    acknowledgements_in_transit: Vec<Vec<u8>>,
+    remaining_faults: [u8; 3],
+}
+
+impl State {
+    fn key(&self) -> StateKey {
+        StateKey {
+            released_frame_count: self.transmitter.progress().0,
+            received_frame_count: self.receiver.received_frame_count(),
+            is_receiver_finished: self.receiver.is_finished(),
+            frames_in_transit: self
+                .frames_in_transit
+                .iter()
+                .map(|(frame_index, frame)| (*frame_index, frame.content.clone()))
+                .collect(),
+            acknowledgements_in_transit: self
+                .acknowledgements_in_transit
+                .iter()
+                .map(GenericAcknowledgement::to_bytes)
+                .collect(),
+            remaining_faults: [
+                self.remaining_losses,
+                self.remaining_corruptions,
+                self.remaining_duplications,
+            ],
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.transmitter.is_finished() && self.receiver.is_finished()
+    }
+
+    fn transmit_current_frame(&mut self) {
+        if let Some(frame) = self.transmitter.current_frame() {
+            let frame_index = self.transmitter.progress().0;
+            self.frames_in_transit
+                .push_back((frame_index, frame.clone()));
+        }
+    }
+
+    fn deliver_frame(&self) -> Option<(Step, Result<State, Violation>)> {
+        let mut next = self.clone();
+        let (frame_index, frame) = next.frames_in_transit.pop_front()?;
+        let (acknowledgement, outcome) = match next.receiver.handle_frame(&frame) {
+            Ok(response) => response,
+            Err(error) => {
+                return Some((
+                    Step::DeliverFrame {
+                        frame_index,
+                        outcome: FrameOutcome::Rejected,
+                    },
+                    Err(Violation::ReceiverFailure(error.to_string())),
+                ));
+            }
+        };
+        let step = Step::DeliverFrame {
+            frame_index,
+            outcome,
+        };
+        let expected_frame_index = next.receiver.received_frame_count().saturating_sub(1);
+        if outcome == FrameOutcome::Accepted && frame_index != expected_frame_index {
+            return Some((
+                step,
+                Err(Violation::OutOfOrderDelivery {
+                    frame_index,
+                    expected_frame_index,
+                }),
+            ));
+        }
+        next.acknowledgements_in_transit.push_back(acknowledgement);
+        Some((step, Ok(next)))
+    }
+
+    fn deliver_acknowledgement(&self) -> Option<(Step, Result<State, Violation>)> {
+        let mut next = self.clone();
+        let acknowledgement = next.acknowledgements_in_transit.pop_front()?;
+        let action = next.transmitter.handle_acknowledgement(&acknowledgement);
+        let step = Step::DeliverAcknowledgement(action);
+        let released_frame_count = next.transmitter.progress().0;
+        let received_frame_count = next.receiver.received_frame_count();
+        if released_frame_count > received_frame_count {
+            return Some((
+                step,
+                Err(Violation::PrematureRelease {
+                    released_frame_count,
+                    received_frame_count,
+                }),
+            ));
+        }
+        if matches!(
+            action,
+            TransmitterAction::SendNextFrame | TransmitterAction::RetransmitFrame
+        ) {
+            next.transmit_current_frame();
+        }
+        Some((step, Ok(next)))
+    }
+
+    fn line_faults(
+        &self,
+        direction: Direction,
+        flipped_bits_per_byte: u8,
+    ) -> Vec<(Step, Result<State, Violation>)> {
+        let packet_bytes = match direction {
+            Direction::TransmitterToReceiver => self
+                .frames_in_transit
+                .front()
+                .map(|(_, frame)| frame.content.to_vec()),
+            Direction::ReceiverToTransmitter => self
+                .acknowledgements_in_transit
+                .front()
+                .map(GenericAcknowledgement::to_bytes),
+        };
+        let Some(packet_bytes) = packet_bytes else {
+            return Vec::new();
+        };
+        let mut successors = Vec::new();
+
+        if self.remaining_losses > 0 {
+            let mut next = self.clone();
+            next.remaining_losses -= 1;
+            match direction {
+                Direction::TransmitterToReceiver => {
+                    next.frames_in_transit.pop_front();
+                }
+                Direction::ReceiverToTransmitter => {
+                    next.acknowledgements_in_transit.pop_front();
+                }
+            }
+            successors.push((Step::Lose(direction), Ok(next)));
+        }
+
+        if self.remaining_duplications > 0 {
+            let mut next = self.clone();
+            next.remaining_duplications -= 1;
+            match direction {
+                Direction::TransmitterToReceiver => {
+                    let duplicate = next.frames_in_transit[0].clone();
+                    next.frames_in_transit.push_front(duplicate);
+                }
+                Direction::ReceiverToTransmitter => {
+                    let duplicate = next.acknowledgements_in_transit[0];
+                    next.acknowledgements_in_transit.push_front(duplicate);
+                }
+            }
+            successors.push((Step::Duplicate(direction), Ok(next)));
+        }
+
+        if self.remaining_corruptions > 0 {
+            // The sequence number sits at the end of a frame, all over an acknowledgement
+            let byte_indices: Vec<usize> = match direction {
+                Direction::TransmitterToReceiver => vec![0, packet_bytes.len() - 1],
+                Direction::ReceiverToTransmitter => (0..packet_bytes.len()).collect(),
+            };
+            for byte_index in byte_indices {
+                for flipped_bits in 1..=flipped_bits_per_byte.min(8) {
+                    let mut corrupted_bytes = packet_bytes.clone();
+                    for bit_index in 0..flipped_bits {
+                        corrupted_bytes[byte_index] =
+                            flip_bit_in_u8(&corrupted_bytes[byte_index], bit_index);
+                    }
+                    let mut next = self.clone();
+                    next.remaining_corruptions -= 1;
+                    match direction {
+                        Direction::TransmitterToReceiver => {
+                            next.frames_in_transit[0].1.content = corrupted_bytes.into();
+                        }
+                        Direction::ReceiverToTransmitter => {
+                            let sequence_number_width = next.acknowledgements_in_transit[0]
+                                .sequence_number()
+                                .width();
+                            next.acknowledgements_in_transit[0] =
+                                GenericAcknowledgement::from_bytes(
+                                    &corrupted_bytes,
+                                    sequence_number_width,
+                                )
+                                .expect("Corruption keeps the length of the acknowledgement");
+                        }
+                    }
+                    successors.push((
+                        Step::Corrupt {
+                            direction,
+                            byte_index,
+                            flipped_bits,
+                        },
+                        Ok(next),
+                    ));
+                }
+            }
+        }
+
+        successors
+    }
+
+    fn successors(&self, flipped_bits_per_byte: u8) -> Vec<(Step, Result<State, Violation>)> {
+        if self.is_complete() {
+            return Vec::new();
+        }
+        let mut successors: Vec<_> = [self.deliver_frame(), self.deliver_acknowledgement()]
+            .into_iter()
+            .flatten()
+            .collect();
+        if self.frames_in_transit.is_empty()
+            && self.acknowledgements_in_transit.is_empty()
+            && !self.transmitter.is_finished()
+        {
+            let mut next = self.clone();
+            next.transmit_current_frame();
+            successors.push((Step::RetransmissionTimeout, Ok(next)));
+        }
+        for direction in [
+            Direction::TransmitterToReceiver,
+            Direction::ReceiverToTransmitter,
+        ] {
+            successors.extend(self.line_faults(direction, flipped_bits_per_byte));
+        }
+        successors
+    }
+}
+
+/// Explores every interleaving of the transfer of `message`, split into frames of
+/// `full_payload_length_in_bytes`, with at most `fault_bounds` faults on the lines.
+///
+/// Checks that frames are accepted once and in order, that the transmitter never moves past a
+/// frame the receiver has not accepted, and that the transfer can complete from every reachable
+/// state.
+pub fn explore(
+    message: &[u8],
+    full_payload_length_in_bytes: usize,
+    sequence_number_width: SequenceNumberWidth,
+    fault_bounds: FaultBounds,
+) -> Exploration {
+    let session_parameters = SessionParameters {
+        compression: Compression::None,
+        sequence_number_width,
+        ..SessionParameters::default()
+    };
+    let frames = prepare_message(
+        message,
+        full_payload_length_in_bytes,
+        &session_parameters,
+        None,
+    )
+    .expect("Model checking uses a valid payload length");
+    let mut initial_state = State {
+        transmitter: TransmitterStateMachine::new(frames),
+        receiver: ReceiverStateMachine::new(None, sequence_number_width),
+        frames_in_transit: VecDeque::new(),
+        acknowledgements_in_transit: VecDeque::new(),
+        remaining_losses: fault_bounds.losses,
+        remaining_corruptions: fault_bounds.corruptions,
+        remaining_duplications: fault_bounds.duplications,
+    };
+    initial_state.transmit_current_frame();
+
+    // Breadth first, so that the first violation found has a shortest trace
+    let mut indices: HashMap<StateKey, usize> = HashMap::from([(initial_state.key(), 0)]);
+    let mut parents: Vec<Option<(usize, Step)>> = vec![None];
+    let mut successor_indices: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut is_complete: Vec<bool> = vec![initial_state.is_complete()];
+    let mut queue = VecDeque::from([(0, initial_state)]);
+    let mut transitions = 0;
+
+    let trace_to = |parents: &[Option<(usize, Step)>], mut index: usize| {
+        let mut steps = Vec::new();
+        while let Some((parent, step)) = &parents[index] {
+            steps.push(step.clone());
+            index = *parent;
+        }
+        steps.reverse();
+        steps
+    };
+
+    while let Some((index, state)) = queue.pop_front() {
+        for (step, next) in state.successors(fault_bounds.flipped_bits_per_byte) {
+            transitions += 1;
+            let next = match next {
+                Ok(next) => next,
+                Err(violation) => {
+                    let mut steps = trace_to(&parents, index);
+                    steps.push(step);
+                    return Exploration {
+                        states: parents.len(),
+                        transitions,
+                        violation: Some((violation, steps)),
+                    };
+                }
+            };
+            let next_index = *indices.entry(next.key()).or_insert_with(|| {
+                parents.push(Some((index, step)));
+                successor_indices.push(Vec::new());
+                is_complete.push(next.is_complete());
+                queue.push_back((parents.len() - 1, next));
+                parents.len() - 1
+            });
+            successor_indices[index].push(next_index);
+        }
+    }
+
+    // Every state from which a complete state can be reached, walking the transitions backwards
+    let mut predecessor_indices = vec![Vec::new(); parents.len()];
+    for (index, successors) in successor_indices.iter().enumerate() {
+        for &successor in successors {
+            predecessor_indices[successor].push(index);
+        }
+    }
+    let mut can_complete = is_complete.clone();
+    let mut stack: Vec<usize> = (0..parents.len()).filter(|&i| is_complete[i]).collect();
+    while let Some(index) = stack.pop() {
+        for &predecessor in &predecessor_indices[index] {
+            if !can_complete[predecessor] {
+                can_complete[predecessor] = true;
+                stack.push(predecessor);
+            }
+        }
+    }
+
+    Exploration {
+        states: parents.len(),
+        transitions,
+        // States are numbered in breadth first order, so the first one is the closest
+        violation: can_complete
+            .iter()
+            .position(|&can_complete| !can_complete)
+            .map(|index| (Violation::NoProgress, trace_to(&parents, index))),
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::TransmitterToReceiver => write!(f, "frame"),
+            Direction::ReceiverToTransmitter => write!(f, "acknowledgement"),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::DeliverFrame {
+                frame_index,
+                outcome,
+            } => write!(f, "Receiver gets frame {}: {:?}", frame_index, outcome),
+            Step::DeliverAcknowledgement(action) => {
+                write!(f, "Transmitter gets acknowledgement: {:?}", action)
+            }
+            Step::RetransmissionTimeout => write!(f, "Retransmission timer expires"),
+            Step::Lose(direction) => write!(f, "Line loses the next {}", direction),
+            Step::Duplicate(direction) => write!(f, "Line duplicates the next {}", direction),
+            Step::Corrupt {
+                direction,
+                byte_index,
+                flipped_bits,
+            } => write!(
+                f,
+                "Line flips {} bits of byte {} of the next {}",
+                flipped_bits, byte_index, direction
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::OutOfOrderDelivery {
+                frame_index,
+                expected_frame_index,
+            } => write!(
+                f,
+                "Receiver accepted frame {} as frame {}",
+                frame_index, expected_frame_index
+            ),
+            Violation::PrematureRelease {
+                released_frame_count,
+                received_frame_count,
+            } => write!(
+                f,
+                "Transmitter released {} frames while the receiver accepted {}",
+                released_frame_count, received_frame_count
+            ),
+            Violation::ReceiverFailure(error) => write!(f, "Receiver failed: {}", error),
+            Violation::NoProgress => write!(f, "Transfer can no longer complete"),
+        }
+    }
+}
+
+#[test]
+fn sequence_numbers_hold_up_to_the_corruption_they_are_designed_for() {
+    let message: Vec<u8> = (0..12).collect();
+    let fault_bounds = FaultBounds {
+        losses: 2,
+        corruptions: 1,
+        duplications: 1,
+        flipped_bits_per_byte: 3,
+    };
+
+    // Majority vote recovers the alternating bit from up to 3 flipped bits of its byte
+    let exploration = explore(
+        &message,
+        8,
+        SequenceNumberWidth::AlternatingBit,
+        fault_bounds,
+    );
+    assert!(exploration.violation.is_none(), "{:?}", exploration);
+    assert!(exploration.states > 100);
+
+    // 4 flipped bits of a duplicated acknowledgement pass for the next one
+    let exploration = explore(
+        &message,
+        8,
+        SequenceNumberWidth::AlternatingBit,
+        FaultBounds {
+            flipped_bits_per_byte: 4,
+            ..fault_bounds
+        },
+    );
+    assert!(matches!(
+        exploration.violation,
+        Some((
+            Violation::OutOfOrderDelivery { .. } | Violation::PrematureRelease { .. },
+            _
+        ))
+    ));
+
+    // Numbered sequences are covered by a checksum, whatever happens to a single byte
+    let exploration = explore(
+        &message,
+        8,
+        SequenceNumberWidth::Bits8,
+        FaultBounds {
+            flipped_bits_per_byte: 8,
+            ..fault_bounds
+        },
+    );
+    assert!(exploration.violation.is_none(), "{:?}", exploration);
+}
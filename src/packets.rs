@@ -1,11 +1,22 @@
+use crate::{
+    error::{Error, Result},
+    trace::PacketType,
+};
+
 pub mod acknowledgement;
 pub mod frame;
+pub mod hdlc;
+pub mod sequence;
 
 const ACK_VALUE: u8 = 0b0000_1100;
 
 const NACK_VALUE: u8 = 0b1111_0011;
 
-type SequenceByte = u8;
+/// ACK of a receiver that cannot take more frames for now, four bits away from both the ACK and
+/// the NACK values, the most a third value can get.
+const RNR_VALUE: u8 = 0b0011_0000;
+
+pub type SequenceByte = u8;
 pub const SEQUENCE_ZERO: SequenceByte = 0b0000_0000;
 pub const SEQUENCE_ONE: SequenceByte = 0b1111_1111;
 
@@ -28,40 +39,240 @@ pub fn flip_sequence_byte(sequence_byte: SequenceByte) -> SequenceByte {
         return flip_sequence_byte(correct_sequence_byte(sequence_byte));
     }
 }
+/// CRC-8 (polynomial 0x07) protecting the few header bytes of numbered acknowledgements.
+pub const fn header_checksum(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        crc ^= bytes[i];
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    crc
+}
+/// Positions of the bits flipped among `length_in_bits`, each one independently with
+/// `bit_error_probability`.
+///
+/// Draws the number of intact bits before each error from a geometric distribution instead of
+/// drawing every bit, so that the cost grows with the number of errors and not with the length.
+/// Panics like [`rand::Rng::random_bool`] when the probability is not within 0 and 1.
+pub fn sample_bit_errors<R: rand::Rng + ?Sized>(
+    length_in_bits: usize,
+    bit_error_probability: f64,
+    rng: &mut R,
+) -> Vec<usize> {
+    assert!(
+        (0.0..=1.0).contains(&bit_error_probability),
+        "Bit error probability {} is not within 0 and 1",
+        bit_error_probability
+    );
+    if bit_error_probability == 0.0 {
+        return Vec::new();
+    }
+    if bit_error_probability == 1.0 {
+        return (0..length_in_bits).collect();
+    }
+    let log_of_intact_probability = (-bit_error_probability).ln_1p();
+    let mut bit_errors = Vec::new();
+    let mut bit_index = 0;
+    loop {
+        // P(gap >= k) = P(uniform <= (1 - p)^k) = (1 - p)^k, with uniform within (0, 1]
+        let uniform = 1.0 - rng.random::<f64>();
+        let gap = (uniform.ln() / log_of_intact_probability).floor();
+        if gap >= (length_in_bits - bit_index) as f64 {
+            return bit_errors;
+        }
+        bit_index += gap as usize;
+        bit_errors.push(bit_index);
+        bit_index += 1;
+    }
+}
+
 pub trait Packet {
-    fn simulate_errors_with_probability(
+    /// Copy of the packet as received over a binary symmetric channel, every bit flipped
+    /// independently with `bit_error_probability`.
+    fn simulate_errors_with_probability<R: rand::Rng + ?Sized>(
         &self,
         bit_error_probability: f64,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> Self;
+        rng: &mut R,
+    ) -> Self
+    where
+        Self: Sized,
+    {
+        self.with_flipped_bits(&sample_bit_errors(
+            self.length_in_bits(),
+            bit_error_probability,
+            rng,
+        ))
+    }
     fn is_valid(&self) -> bool;
+    /// Bits the packet takes on the line.
+    fn length_in_bits(&self) -> usize;
+    /// Copy of the packet with the given bits of its bytes on the line flipped, bit `i` being
+    /// bit `i % 8` of byte `i / 8`. Bits past the end of the packet are ignored.
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self;
 }
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum GenericPacket {
     Frame(frame::Frame),
     Acknowledgement(acknowledgement::GenericAcknowledgement),
+    // Data frame with the acknowledgement for the opposite direction piggybacked on it
+    FrameWithAcknowledgement(frame::Frame, acknowledgement::GenericAcknowledgement),
 }
 
-impl Packet for GenericPacket {
-    fn simulate_errors_with_probability(
-        &self,
-        bit_error_probability: f64,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> Self {
+impl GenericPacket {
+    /// The frame carried by the packet, for consumers that only expect frames.
+    pub fn frame(&self) -> Result<&frame::Frame> {
         match self {
-            GenericPacket::Frame(frame) => GenericPacket::Frame(
-                frame.simulate_errors_with_probability(bit_error_probability, rng),
-            ),
-            GenericPacket::Acknowledgement(acknowledgement) => GenericPacket::Acknowledgement(
-                acknowledgement.simulate_errors_with_probability(bit_error_probability, rng),
-            ),
+            GenericPacket::Frame(frame) | GenericPacket::FrameWithAcknowledgement(frame, _) => {
+                Ok(frame)
+            }
+            GenericPacket::Acknowledgement(_) => Err(self.unexpected()),
         }
     }
 
+    /// The acknowledgement carried by the packet, for consumers that only expect
+    /// acknowledgements.
+    pub fn acknowledgement(&self) -> Result<&acknowledgement::GenericAcknowledgement> {
+        match self {
+            GenericPacket::Acknowledgement(acknowledgement)
+            | GenericPacket::FrameWithAcknowledgement(_, acknowledgement) => Ok(acknowledgement),
+            GenericPacket::Frame(_) => Err(self.unexpected()),
+        }
+    }
+
+    fn unexpected(&self) -> Error {
+        let (frame, acknowledgement) = match self {
+            GenericPacket::Frame(frame) => (Some(frame), None),
+            GenericPacket::Acknowledgement(acknowledgement) => (None, Some(acknowledgement)),
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                (Some(frame), Some(acknowledgement))
+            }
+        };
+        Error::UnexpectedPacketType(
+            PacketType::of(frame, acknowledgement).expect("Packet carries a frame or an ACK"),
+        )
+    }
+
+    /// Bytes put on the line, with a piggybacked acknowledgement following the frame.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            GenericPacket::Frame(frame) => frame.content.to_vec(),
+            GenericPacket::Acknowledgement(acknowledgement) => acknowledgement.to_bytes(),
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                let mut bytes = frame.content.to_vec();
+                bytes.extend_from_slice(&acknowledgement.to_bytes());
+                bytes
+            }
+        }
+    }
+}
+
+impl Packet for GenericPacket {
     fn is_valid(&self) -> bool {
         match self {
             GenericPacket::Frame(frame) => frame.is_valid(),
             GenericPacket::Acknowledgement(acknowledgement) => acknowledgement.is_valid(),
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                frame.is_valid() && acknowledgement.is_valid()
+            }
         }
     }
+
+    fn length_in_bits(&self) -> usize {
+        match self {
+            GenericPacket::Frame(frame) => frame.length_in_bits(),
+            GenericPacket::Acknowledgement(acknowledgement) => acknowledgement.length_in_bits(),
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                frame.length_in_bits() + acknowledgement.length_in_bits()
+            }
+        }
+    }
+
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self {
+        match self {
+            GenericPacket::Frame(frame) => {
+                GenericPacket::Frame(frame.with_flipped_bits(bit_indices))
+            }
+            GenericPacket::Acknowledgement(acknowledgement) => {
+                GenericPacket::Acknowledgement(acknowledgement.with_flipped_bits(bit_indices))
+            }
+            // The acknowledgement follows the frame on the line
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                let frame_length_in_bits = frame.length_in_bits();
+                let acknowledgement_bit_indices: Vec<usize> = bit_indices
+                    .iter()
+                    .filter_map(|bit_index| bit_index.checked_sub(frame_length_in_bits))
+                    .collect();
+                GenericPacket::FrameWithAcknowledgement(
+                    frame.with_flipped_bits(bit_indices),
+                    acknowledgement.with_flipped_bits(&acknowledgement_bit_indices),
+                )
+            }
+        }
+    }
+}
+
+#[test]
+fn sampled_bit_errors_follow_independent_flips() {
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(41);
+    assert!(sample_bit_errors(100, 0.0, &mut rng).is_empty());
+    assert_eq!(
+        sample_bit_errors(100, 1.0, &mut rng),
+        (0..100).collect::<Vec<_>>()
+    );
+
+    let (length_in_bits, bit_error_probability, trials) = (1000, 0.01, 20_000);
+    let mut error_counts = Vec::with_capacity(trials);
+    let mut errors_per_tenth = [0usize; 10];
+    let mut adjacent_errors = 0;
+    for _ in 0..trials {
+        let bit_errors = sample_bit_errors(length_in_bits, bit_error_probability, &mut rng);
+        assert!(bit_errors.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(
+            bit_errors
+                .iter()
+                .all(|&bit_index| bit_index < length_in_bits)
+        );
+        adjacent_errors += bit_errors
+            .windows(2)
+            .filter(|pair| pair[1] == pair[0] + 1)
+            .count();
+        for &bit_index in &bit_errors {
+            errors_per_tenth[bit_index * 10 / length_in_bits] += 1;
+        }
+        error_counts.push(bit_errors.len() as f64);
+    }
+
+    // Binomial count of errors, spread evenly and independently over the bits
+    let mean = error_counts.iter().sum::<f64>() / trials as f64;
+    let variance = error_counts
+        .iter()
+        .map(|count| (count - mean).powi(2))
+        .sum::<f64>()
+        / trials as f64;
+    let expected_mean = length_in_bits as f64 * bit_error_probability;
+    assert!((mean - expected_mean).abs() < 0.1, "mean {}", mean);
+    assert!(
+        (variance - expected_mean * (1.0 - bit_error_probability)).abs() < 0.4,
+        "variance {}",
+        variance
+    );
+    let total_errors = errors_per_tenth.iter().sum::<usize>() as f64;
+    for errors in errors_per_tenth {
+        assert!((errors as f64 / total_errors - 0.1).abs() < 0.005);
+    }
+    let expected_adjacent_errors =
+        (length_in_bits - 1) as f64 * bit_error_probability.powi(2) * trials as f64;
+    assert!((adjacent_errors as f64 / expected_adjacent_errors - 1.0).abs() < 0.1);
 }
@@ -1,34 +1,170 @@
-use crate::packets::Packet;
+use crate::{
+    error::{DecodeError, Result},
+    packets::{
+        ACK_VALUE, NACK_VALUE, Packet, RNR_VALUE, header_checksum,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+};
 
 pub mod ack;
 pub mod nack;
 
-#[derive(PartialEq, Debug)]
+/// Bytes of an acknowledgement on the line: its value, the sequence number and, for numbered
+/// sequences, the header checksum.
+pub const fn acknowledgement_length_in_bytes(sequence_number_width: SequenceNumberWidth) -> usize {
+    match sequence_number_width.is_alternating_bit() {
+        true => 1 + sequence_number_width.length_in_bytes(),
+        false => 1 + sequence_number_width.length_in_bytes() + 1,
+    }
+}
+
+/// Packs `value | sequence number` into the low bytes of a `u64`, followed by a header
+/// checksum for numbered sequences. Returns the content and its length in bytes.
+pub(crate) fn encode_acknowledgement(value: u8, sequence_number: SequenceNumber) -> (u64, u8) {
+    let mut bytes = vec![value];
+    bytes.extend_from_slice(&sequence_number.to_bytes());
+    if !sequence_number.width().is_alternating_bit() {
+        bytes.push(header_checksum(&bytes));
+    }
+    let content = bytes
+        .iter()
+        .fold(0u64, |content, &byte| (content << 8) | byte as u64);
+    (content, bytes.len() as u8)
+}
+
+/// Inverse of [`encode_acknowledgement`], also telling whether the header checksum matches.
+pub(crate) fn decode_acknowledgement(
+    content: u64,
+    length_in_bytes: u8,
+) -> (u8, SequenceNumber, bool) {
+    let length_in_bytes = length_in_bytes as usize;
+    let bytes = &content.to_be_bytes()[8 - length_in_bytes..];
+    let sequence_number_width = match length_in_bytes {
+        2 => SequenceNumberWidth::AlternatingBit,
+        3 => SequenceNumberWidth::Bits8,
+        4 => SequenceNumberWidth::Bits16,
+        _ => SequenceNumberWidth::Bits32,
+    };
+    let sequence_number_length = sequence_number_width.length_in_bytes();
+    let sequence_number =
+        SequenceNumber::from_bytes(&bytes[1..1 + sequence_number_length], sequence_number_width)
+            .expect("Slice has the length of the width");
+    let is_header_intact = sequence_number_width.is_alternating_bit()
+        || header_checksum(&bytes[..length_in_bytes - 1]) == bytes[length_in_bytes - 1];
+    (bytes[0], sequence_number, is_header_intact)
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum GenericAcknowledgement {
     ACK(ack::ACK),
     NACK(nack::NACK),
 }
 
-impl Packet for GenericAcknowledgement {
-    fn simulate_errors_with_probability(
-        &self,
-        bit_error_probability: f64,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> Self {
+impl GenericAcknowledgement {
+    /// Parses the bytes of [`GenericAcknowledgement::to_bytes`].
+    ///
+    /// The type is the one whose value is the closest to the first byte, the not-ready value
+    /// counting as an ACK, so a corrupted
+    /// acknowledgement is still returned and only fails [`Packet::is_valid`].
+    pub fn from_bytes(bytes: &[u8], sequence_number_width: SequenceNumberWidth) -> Result<Self> {
+        let expected_length_in_bytes = acknowledgement_length_in_bytes(sequence_number_width);
+        if bytes.len() != expected_length_in_bytes {
+            return Err(DecodeError::InvalidAcknowledgementLength {
+                length_in_bytes: bytes.len(),
+                expected_length_in_bytes,
+            }
+            .into());
+        }
+        let content = bytes
+            .iter()
+            .fold(0u64, |content, &byte| (content << 8) | byte as u64);
+        let length_in_bytes = bytes.len() as u8;
+        let distance = |value: u8| (bytes[0] ^ value).count_ones();
+        let is_nack = distance(NACK_VALUE) < distance(ACK_VALUE).min(distance(RNR_VALUE));
+        Ok(match is_nack {
+            true => {
+                GenericAcknowledgement::NACK(nack::NACK::from_content(content, length_in_bytes))
+            }
+            false => GenericAcknowledgement::ACK(ack::ACK::from_content(content, length_in_bytes)),
+        })
+    }
+
+    pub fn sequence_number(&self) -> SequenceNumber {
+        match self {
+            GenericAcknowledgement::ACK(ack) => ack.get_ack_and_sequence_number().1,
+            GenericAcknowledgement::NACK(nack) => nack.get_ack_and_sequence_number().1,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            GenericAcknowledgement::ACK(ack) => GenericAcknowledgement::ACK(
-                ack.simulate_errors_with_probability(bit_error_probability, rng),
-            ),
-            GenericAcknowledgement::NACK(nack) => GenericAcknowledgement::NACK(
-                nack.simulate_errors_with_probability(bit_error_probability, rng),
-            ),
+            GenericAcknowledgement::ACK(ack) => ack.to_bytes(),
+            GenericAcknowledgement::NACK(nack) => nack.to_bytes(),
         }
     }
+}
 
+impl Packet for GenericAcknowledgement {
     fn is_valid(&self) -> bool {
         match self {
             GenericAcknowledgement::ACK(ack) => ack.is_valid(),
             GenericAcknowledgement::NACK(nack) => nack.is_valid(),
         }
     }
+
+    fn length_in_bits(&self) -> usize {
+        match self {
+            GenericAcknowledgement::ACK(ack) => ack.length_in_bits(),
+            GenericAcknowledgement::NACK(nack) => nack.length_in_bits(),
+        }
+    }
+
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self {
+        match self {
+            GenericAcknowledgement::ACK(ack) => {
+                GenericAcknowledgement::ACK(ack.with_flipped_bits(bit_indices))
+            }
+            GenericAcknowledgement::NACK(nack) => {
+                GenericAcknowledgement::NACK(nack.with_flipped_bits(bit_indices))
+            }
+        }
+    }
+}
+
+#[test]
+fn numbered_acknowledgements_detect_corrupted_sequence_numbers() {
+    use crate::packets::acknowledgement::{ack::ACK, nack::NACK};
+
+    for width in [
+        SequenceNumberWidth::AlternatingBit,
+        SequenceNumberWidth::Bits8,
+        SequenceNumberWidth::Bits16,
+        SequenceNumberWidth::Bits32,
+    ] {
+        let sequence_number = SequenceNumber::new(0x1234_5679, width);
+        let ack = ACK::new(sequence_number);
+        let nack = NACK::new(sequence_number);
+        assert!(ack.is_valid() && nack.is_valid());
+        assert_eq!(ack.get_ack_and_sequence_number().1, sequence_number);
+        assert_eq!(nack.get_ack_and_sequence_number().1, sequence_number);
+
+        // Lowest bit of the sequence number, which majority vote only recovers for one bit
+        let mut corrupted_ack = ack;
+        corrupted_ack.flip_bit(if width.is_alternating_bit() { 0 } else { 8 });
+        assert_eq!(corrupted_ack.is_valid(), width.is_alternating_bit());
+
+        for acknowledgement in [
+            GenericAcknowledgement::ACK(ack),
+            GenericAcknowledgement::NACK(nack),
+            GenericAcknowledgement::ACK(corrupted_ack),
+        ] {
+            let bytes = acknowledgement.to_bytes();
+            assert_eq!(bytes.len(), acknowledgement_length_in_bytes(width));
+            assert_eq!(
+                GenericAcknowledgement::from_bytes(&bytes, width).unwrap(),
+                acknowledgement
+            );
+            assert!(GenericAcknowledgement::from_bytes(&bytes[1..], width).is_err());
+        }
+    }
 }
@@ -1,45 +1,70 @@
-use rand::Rng;
-
-use crate::packets::{NACK_VALUE, Packet, SequenceByte};
+use crate::packets::{
+    NACK_VALUE, Packet,
+    acknowledgement::{decode_acknowledgement, encode_acknowledgement},
+    sequence::SequenceNumber,
+};
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub struct NACK {
-    content: u16,
+    content: u64,
+    length_in_bytes: u8,
 }
 
 impl NACK {
-    pub const fn new(sequence_byte_of_rejected_package: SequenceByte) -> Self {
+    pub fn new(sequence_number_of_rejected_package: SequenceNumber) -> Self {
+        let (content, length_in_bytes) =
+            encode_acknowledgement(NACK_VALUE, sequence_number_of_rejected_package);
         Self {
-            content: u16::from_be_bytes([NACK_VALUE, sequence_byte_of_rejected_package]),
+            content,
+            length_in_bytes,
+        }
+    }
+
+    /// Wraps bytes read from the line, see [`GenericAcknowledgement::from_bytes`].
+    ///
+    /// [`GenericAcknowledgement::from_bytes`]: crate::packets::acknowledgement::GenericAcknowledgement::from_bytes
+    pub(crate) fn from_content(content: u64, length_in_bytes: u8) -> Self {
+        Self {
+            content,
+            length_in_bytes,
         }
     }
 
     pub fn flip_bit(&mut self, bit_index: u8) {
-        if bit_index < 16 {
-            self.content ^= 1u16 << bit_index;
+        if bit_index < self.length_in_bytes * 8 {
+            self.content ^= 1u64 << bit_index;
         }
     }
 
-    pub fn get_ack_and_sequence_byte(&self) -> (u8, SequenceByte) {
-        return (self.content.to_be_bytes()[0], self.content.to_be_bytes()[1]);
+    pub fn get_ack_and_sequence_number(&self) -> (u8, SequenceNumber) {
+        let (ack, sequence_number, _) = decode_acknowledgement(self.content, self.length_in_bytes);
+        (ack, sequence_number)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.content.to_be_bytes()[8 - self.length_in_bytes as usize..].to_vec()
     }
 }
 
 impl Packet for NACK {
-    fn simulate_errors_with_probability(
-        &self,
-        bit_error_probability: f64,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> Self {
-        let mut cloned_ack = self.clone();
-        for i in 0..16 {
-            if rng.random_bool(bit_error_probability) {
-                cloned_ack.flip_bit(i);
+    fn is_valid(&self) -> bool {
+        let (ack, _, is_header_intact) = decode_acknowledgement(self.content, self.length_in_bytes);
+        ack == NACK_VALUE && is_header_intact
+    }
+
+    fn length_in_bits(&self) -> usize {
+        self.length_in_bytes as usize * 8
+    }
+
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self {
+        let mut flipped = *self;
+        for &bit_index in bit_indices {
+            if bit_index < self.length_in_bits() {
+                // The content keeps the first byte on the line in its most significant byte
+                let byte_from_end = self.length_in_bytes as usize - 1 - bit_index / 8;
+                flipped.flip_bit((byte_from_end * 8 + bit_index % 8) as u8);
             }
         }
-        cloned_ack
-    }
-    fn is_valid(&self) -> bool {
-        self.get_ack_and_sequence_byte().0 == NACK_VALUE
+        flipped
     }
 }
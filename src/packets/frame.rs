@@ -1,13 +1,20 @@
-use std::{
-    f64,
-    fmt::{self, Display},
-};
+use std::fmt::{self, Display};
+
+use bytes::{BufMut, Bytes, BytesMut};
 
-use crate::packets::{Packet, SEQUENCE_ZERO, SequenceByte};
-use rand::Rng;
+use crate::{
+    error::{DecodeError, Error, Result},
+    packets::{
+        Packet,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+};
+/// Cloning a frame, as the transmitter does for every attempt, shares its bytes.
 #[derive(Debug, PartialEq, Clone)]
 pub struct Frame {
-    pub content: Vec<u8>,
+    pub content: Bytes,
+    // Needed to tell where the payload ends, both sides agree on it before the transfer
+    pub sequence_number_width: SequenceNumberWidth,
 }
 /*
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -58,75 +65,148 @@ pub fn flip_bit_in_u8(byte: &u8, i: u8) -> u8 {
     byte ^ 1u8 << i
 }
 impl Frame {
-    pub fn new(payload_data: &[u8], sequence_byte: SequenceByte) -> Self {
-        let mut complete_payload: Vec<u8> = Vec::with_capacity(payload_data.len() + 2);
-        for &byte in payload_data {
-            complete_payload.push(byte);
-        }
-
-        let checksum_in_bytes = checksum(payload_data).to_be_bytes();
-        for checksum_byte in checksum_in_bytes {
-            complete_payload.push(checksum_byte);
-        }
+    /// Builds `payload | checksum | sequence number`.
+    ///
+    /// With the alternating bit the checksum only covers the payload, as the sequence byte is
+    /// recovered by majority vote. Numbered frames also cover the sequence number with it.
+    pub fn new(payload_data: &[u8], sequence_number: SequenceNumber) -> Self {
+        let mut buffer = BytesMut::with_capacity(
+            payload_data.len() + Self::overhead_in_bytes(sequence_number.width()),
+        );
+        Self::new_in(&mut buffer, payload_data, sequence_number)
+    }
 
-        complete_payload.push(sequence_byte);
+    /// Same as [`Frame::new`], writing the frame into the spare capacity of `buffer` so that
+    /// the frames of a message can share a single allocation. Clears `buffer` first.
+    pub fn new_in(
+        buffer: &mut BytesMut,
+        payload_data: &[u8],
+        sequence_number: SequenceNumber,
+    ) -> Self {
+        let sequence_number_bytes = sequence_number.to_bytes();
+        buffer.clear();
+        buffer.extend_from_slice(payload_data);
+        buffer.put_u32(frame_checksum(
+            payload_data,
+            &sequence_number_bytes,
+            sequence_number.width(),
+        ));
+        buffer.extend_from_slice(&sequence_number_bytes);
         Self {
-            content: complete_payload,
+            content: buffer.split().freeze(),
+            sequence_number_width: sequence_number.width(),
         }
     }
-    pub fn get_payload_and_checksum_and_sequence_byte(&self) -> (Vec<u8>, u32, SequenceByte) {
-        let (payload, checksum_and_sequence_byte) = self
+    /// Bytes added to the payload of every frame.
+    pub const fn overhead_in_bytes(sequence_number_width: SequenceNumberWidth) -> usize {
+        4 + sequence_number_width.length_in_bytes()
+    }
+
+    /// Fails with [`DecodeError::TruncatedFrame`] when the frame cannot even hold its trailer.
+    /// The payload shares the bytes of the frame.
+    pub fn get_payload_and_checksum_and_sequence_number(
+        &self,
+    ) -> Result<(Bytes, u32, SequenceNumber)> {
+        let (payload, checksum, _) = self.split_content()?;
+
+        Ok((
+            self.content.slice_ref(payload),
+            checksum,
+            self.sequence_number()?,
+        ))
+    }
+
+    /// Borrowed view of the payload, see [`Frame::get_payload_and_checksum_and_sequence_number`].
+    pub fn payload(&self) -> Result<&[u8]> {
+        self.split_content().map(|(payload, _, _)| payload)
+    }
+
+    pub fn sequence_number(&self) -> Result<SequenceNumber> {
+        let (_, _, sequence_number_bytes) = self.split_content()?;
+        Ok(
+            SequenceNumber::from_bytes(sequence_number_bytes, self.sequence_number_width)
+                .expect("Trailer holds a sequence number of the frame width"),
+        )
+    }
+
+    fn split_content(&self) -> Result<(&[u8], u32, &[u8])> {
+        let minimum_length_in_bytes = Self::overhead_in_bytes(self.sequence_number_width);
+        if self.content.len() < minimum_length_in_bytes {
+            return Err(Error::Decode(DecodeError::TruncatedFrame {
+                length_in_bytes: self.content.len(),
+                minimum_length_in_bytes,
+            }));
+        }
+        let (payload, trailer) = self
             .content
-            .split_last_chunk::<5>()
-            .unwrap_or((&[], &[0, 0, 0, 0, SEQUENCE_ZERO]));
-
-        let (checksum, sequence): (u32, SequenceByte) = (
-            u32::from_be_bytes([
-                checksum_and_sequence_byte[0],
-                checksum_and_sequence_byte[1],
-                checksum_and_sequence_byte[2],
-                checksum_and_sequence_byte[3],
-            ]),
-            *checksum_and_sequence_byte.last().unwrap() as SequenceByte,
-        );
+            .split_at(self.content.len() - minimum_length_in_bytes);
+        let (checksum, sequence_number_bytes) = trailer.split_at(4);
 
-        (payload.to_vec(), checksum, sequence)
+        Ok((
+            payload,
+            u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]),
+            sequence_number_bytes,
+        ))
     }
 }
 
-impl Packet for Frame {
-    fn simulate_errors_with_probability(
-        &self,
-        bit_error_probability: f64,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> Self {
-        let mut cloned_content = self.content.clone();
-        for byte in &mut cloned_content {
-            for i in 0..=7 {
-                let should_flip = rng.random_bool(bit_error_probability);
-                if should_flip {
-                    *byte = flip_bit_in_u8(byte, i);
-                }
-            }
-        }
-        Self {
-            content: cloned_content,
-        }
+fn frame_checksum(
+    payload: &[u8],
+    sequence_number_bytes: &[u8],
+    sequence_number_width: SequenceNumberWidth,
+) -> u32 {
+    if sequence_number_width.is_alternating_bit() {
+        return checksum(payload);
     }
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.update(sequence_number_bytes);
+    hasher.finalize()
+}
+
+impl Packet for Frame {
     fn is_valid(&self) -> bool {
-        let (received_payload, received_checksum, _received_sequence) =
-            self.get_payload_and_checksum_and_sequence_byte();
+        let Ok((received_payload, received_checksum, received_sequence_number_bytes)) =
+            self.split_content()
+        else {
+            return false;
+        };
 
-        let computed_checksum = checksum(&received_payload);
+        let computed_checksum = frame_checksum(
+            received_payload,
+            received_sequence_number_bytes,
+            self.sequence_number_width,
+        );
         let is_valid = received_checksum == computed_checksum;
         return is_valid;
     }
+
+    fn length_in_bits(&self) -> usize {
+        self.content.len() * 8
+    }
+
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self {
+        // Most packets go through intact, and keep sharing their bytes
+        if bit_indices.is_empty() {
+            return self.clone();
+        }
+        let mut content = BytesMut::from(&self.content[..]);
+        for &bit_index in bit_indices {
+            if let Some(byte) = content.get_mut(bit_index / 8) {
+                *byte = flip_bit_in_u8(byte, (bit_index % 8) as u8);
+            }
+        }
+        Self {
+            content: content.freeze(),
+            sequence_number_width: self.sequence_number_width,
+        }
+    }
 }
 
 impl Display for Frame {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut res = String::with_capacity(self.content.len() * 9);
-        for byte in &self.content {
+        for byte in self.content.iter() {
             res.push_str(&format!("{:08b}", byte));
         }
         write!(f, "{}", res)
@@ -146,10 +226,51 @@ fn error_detection_encoding_is_applied_correctly() {
     let b1: u8 = 10;
     let b2: u8 = 201;
 
-    // let frame = Frame::new(&[b1, b2]);
+    let frame = Frame::new(
+        &[b1, b2],
+        SequenceNumber::zero(SequenceNumberWidth::AlternatingBit),
+    );
+    assert!(frame.is_valid());
+
+    assert_eq!(flip_bit_in_u8(&b1, 1), 8);
+    assert_eq!(flip_bit_in_u8(&b2, 3), 193);
+
+    // Every single flipped bit of the payload or of the checksum is detected
+    for bit_index in 0..(2 + 4) * 8 {
+        let corrupted_frame = frame.with_flipped_bits(&[bit_index]);
+        assert!(!corrupted_frame.is_valid(), "bit {}", bit_index);
+    }
+}
 
-    // println!("{:08b} -> {:08b}", b1, flip_bit_in_u8(&b1, 1));
-    // println!("{:08b} -> {:08b}", b2, flip_bit_in_u8(&b2, 3));
+#[test]
+fn truncated_frames_are_rejected() {
+    for width in [
+        SequenceNumberWidth::AlternatingBit,
+        SequenceNumberWidth::Bits8,
+        SequenceNumberWidth::Bits16,
+        SequenceNumberWidth::Bits32,
+    ] {
+        let frame = Frame::new(&[], SequenceNumber::zero(width));
+        assert_eq!(frame.content.len(), Frame::overhead_in_bytes(width));
+        assert!(frame.is_valid());
+        assert_eq!(
+            frame
+                .get_payload_and_checksum_and_sequence_number()
+                .unwrap()
+                .0,
+            Vec::<u8>::new()
+        );
 
-    // println!("{}", frame);
+        for length_in_bytes in 0..frame.content.len() {
+            let truncated = Frame {
+                content: frame.content.slice(..length_in_bytes),
+                sequence_number_width: width,
+            };
+            assert!(!truncated.is_valid());
+            assert!(matches!(
+                truncated.get_payload_and_checksum_and_sequence_number(),
+                Err(Error::Decode(DecodeError::TruncatedFrame { .. }))
+            ));
+        }
+    }
 }
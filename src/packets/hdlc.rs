@@ -0,0 +1,639 @@
+use bytes::Bytes;
+
+use crate::{
+    error::{DecodeError, Error, Result},
+    packets::{
+        GenericPacket,
+        acknowledgement::{GenericAcknowledgement, ack::ACK, nack::NACK},
+        frame::Frame,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+};
+
+// HDLC/LAPB frames, `address | control | information | FCS`, next to the custom format of the
+// crate. Flags and transparency are those of the serial module, whose `HdlcLink` runs a
+// transfer in this format. Our frames map onto I frames, ACKs onto RR and NACKs onto REJ or
+// SREJ, both numbering the next frame expected, while U frames set up and tear down the link
+// and have no counterpart.
+
+/// Counting of the N(S) and N(R) fields.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Modulus {
+    /// 3-bit counters in a 1 byte control field.
+    Modulo8,
+    /// 7-bit counters in a 2 byte control field, for the extended modes.
+    Modulo128,
+}
+
+impl Modulus {
+    pub const fn value(&self) -> u8 {
+        match self {
+            Modulus::Modulo8 => 8,
+            Modulus::Modulo128 => 128,
+        }
+    }
+
+    /// Length of the control field of I and S frames, U frames always take a single byte.
+    pub const fn control_length_in_bytes(&self) -> usize {
+        match self {
+            Modulus::Modulo8 => 1,
+            Modulus::Modulo128 => 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SupervisoryFunction {
+    ReceiveReady,
+    ReceiveNotReady,
+    Reject,
+    SelectiveReject,
+}
+
+impl SupervisoryFunction {
+    const fn code(&self) -> u8 {
+        match self {
+            SupervisoryFunction::ReceiveReady => 0,
+            SupervisoryFunction::ReceiveNotReady => 1,
+            SupervisoryFunction::Reject => 2,
+            SupervisoryFunction::SelectiveReject => 3,
+        }
+    }
+
+    const fn from_code(code: u8) -> Self {
+        match code & 0b11 {
+            0 => SupervisoryFunction::ReceiveReady,
+            1 => SupervisoryFunction::ReceiveNotReady,
+            2 => SupervisoryFunction::Reject,
+            _ => SupervisoryFunction::SelectiveReject,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UnnumberedFunction {
+    SetAsynchronousBalancedMode,
+    SetAsynchronousBalancedModeExtended,
+    Disconnect,
+    UnnumberedAcknowledgement,
+    DisconnectedMode,
+    FrameReject,
+    UnnumberedInformation,
+}
+
+impl UnnumberedFunction {
+    /// Control byte with the P/F bit cleared.
+    const fn control(&self) -> u8 {
+        match self {
+            UnnumberedFunction::SetAsynchronousBalancedMode => 0x2F,
+            UnnumberedFunction::SetAsynchronousBalancedModeExtended => 0x6F,
+            UnnumberedFunction::Disconnect => 0x43,
+            UnnumberedFunction::UnnumberedAcknowledgement => 0x63,
+            UnnumberedFunction::DisconnectedMode => 0x0F,
+            UnnumberedFunction::FrameReject => 0x87,
+            UnnumberedFunction::UnnumberedInformation => 0x03,
+        }
+    }
+
+    const fn from_control(control: u8) -> Option<Self> {
+        match control & !POLL_FINAL_BIT {
+            0x2F => Some(UnnumberedFunction::SetAsynchronousBalancedMode),
+            0x6F => Some(UnnumberedFunction::SetAsynchronousBalancedModeExtended),
+            0x43 => Some(UnnumberedFunction::Disconnect),
+            0x63 => Some(UnnumberedFunction::UnnumberedAcknowledgement),
+            0x0F => Some(UnnumberedFunction::DisconnectedMode),
+            0x87 => Some(UnnumberedFunction::FrameReject),
+            0x03 => Some(UnnumberedFunction::UnnumberedInformation),
+            _ => None,
+        }
+    }
+}
+
+/// P/F bit of 1 byte control fields.
+const POLL_FINAL_BIT: u8 = 0x10;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Control {
+    Information {
+        send_sequence_number: u8,
+        receive_sequence_number: u8,
+        poll_final: bool,
+    },
+    Supervisory {
+        function: SupervisoryFunction,
+        receive_sequence_number: u8,
+        poll_final: bool,
+    },
+    Unnumbered {
+        function: UnnumberedFunction,
+        poll_final: bool,
+    },
+}
+
+impl Control {
+    /// Bits of the control field in the order they go on the line, counters being reduced
+    /// modulo `modulus`.
+    pub fn to_bytes(&self, modulus: Modulus) -> Vec<u8> {
+        let counter_mask = modulus.value() - 1;
+        match (*self, modulus) {
+            (
+                Control::Information {
+                    send_sequence_number,
+                    receive_sequence_number,
+                    poll_final,
+                },
+                Modulus::Modulo8,
+            ) => vec![
+                (send_sequence_number & counter_mask) << 1
+                    | (poll_final as u8) << 4
+                    | (receive_sequence_number & counter_mask) << 5,
+            ],
+            (
+                Control::Information {
+                    send_sequence_number,
+                    receive_sequence_number,
+                    poll_final,
+                },
+                Modulus::Modulo128,
+            ) => vec![
+                (send_sequence_number & counter_mask) << 1,
+                poll_final as u8 | (receive_sequence_number & counter_mask) << 1,
+            ],
+            (
+                Control::Supervisory {
+                    function,
+                    receive_sequence_number,
+                    poll_final,
+                },
+                Modulus::Modulo8,
+            ) => vec![
+                0b01 | function.code() << 2
+                    | (poll_final as u8) << 4
+                    | (receive_sequence_number & counter_mask) << 5,
+            ],
+            (
+                Control::Supervisory {
+                    function,
+                    receive_sequence_number,
+                    poll_final,
+                },
+                Modulus::Modulo128,
+            ) => vec![
+                0b01 | function.code() << 2,
+                poll_final as u8 | (receive_sequence_number & counter_mask) << 1,
+            ],
+            (
+                Control::Unnumbered {
+                    function,
+                    poll_final,
+                },
+                _,
+            ) => vec![function.control() | (poll_final as u8) << 4],
+        }
+    }
+
+    /// Parses the control field at the start of `bytes` and returns it with its length.
+    fn from_bytes(bytes: &[u8], modulus: Modulus) -> Result<(Self, usize)> {
+        let first = bytes[0];
+        let invalid = || DecodeError::InvalidHdlcControl { control: first };
+        if first & 0b11 == 0b11 {
+            let function = UnnumberedFunction::from_control(first).ok_or_else(invalid)?;
+            return Ok((
+                Control::Unnumbered {
+                    function,
+                    poll_final: first & POLL_FINAL_BIT != 0,
+                },
+                1,
+            ));
+        }
+        let control = match modulus {
+            Modulus::Modulo8 => {
+                let poll_final = first & POLL_FINAL_BIT != 0;
+                let receive_sequence_number = first >> 5;
+                match first & 1 {
+                    0 => Control::Information {
+                        send_sequence_number: (first >> 1) & 0b111,
+                        receive_sequence_number,
+                        poll_final,
+                    },
+                    _ => Control::Supervisory {
+                        function: SupervisoryFunction::from_code(first >> 2),
+                        receive_sequence_number,
+                        poll_final,
+                    },
+                }
+            }
+            Modulus::Modulo128 => {
+                let &second = bytes.get(1).ok_or_else(invalid)?;
+                let poll_final = second & 1 != 0;
+                let receive_sequence_number = second >> 1;
+                match first & 1 {
+                    0 => Control::Information {
+                        send_sequence_number: first >> 1,
+                        receive_sequence_number,
+                        poll_final,
+                    },
+                    // The upper bits of extended S frames are reserved
+                    _ if first & 0xF0 != 0 => return Err(invalid().into()),
+                    _ => Control::Supervisory {
+                        function: SupervisoryFunction::from_code(first >> 2),
+                        receive_sequence_number,
+                        poll_final,
+                    },
+                }
+            }
+        };
+        Ok((control, modulus.control_length_in_bytes()))
+    }
+}
+
+/// Frame check sequence of HDLC, the CRC-16 of ITU-T X.25 that goes on the line low byte
+/// first.
+pub const fn fcs16(bytes: &[u8]) -> u16 {
+    let mut fcs: u16 = 0xFFFF;
+    let mut i = 0;
+    while i < bytes.len() {
+        fcs ^= bytes[i] as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            fcs = if fcs & 1 != 0 {
+                (fcs >> 1) ^ 0x8408
+            } else {
+                fcs >> 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    !fcs
+}
+
+/// One HDLC frame, without its flags.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct HdlcFrame {
+    pub address: u8,
+    pub control: Control,
+    /// Only I and UI frames carry information.
+    pub information: Bytes,
+}
+
+impl HdlcFrame {
+    /// Bytes added to the information of I frames, the same as the length of S frames.
+    pub const fn overhead_in_bytes(modulus: Modulus) -> usize {
+        1 + modulus.control_length_in_bytes() + 2
+    }
+
+    pub fn to_bytes(&self, modulus: Modulus) -> Vec<u8> {
+        let mut bytes = vec![self.address];
+        bytes.extend_from_slice(&self.control.to_bytes(modulus));
+        bytes.extend_from_slice(&self.information);
+        bytes.extend_from_slice(&fcs16(&bytes).to_le_bytes());
+        bytes
+    }
+
+    /// Fails with [`Error::ChecksumMismatch`] if the FCS does not match, with
+    /// [`DecodeError::TruncatedFrame`] if there is no room for the address, a control byte and
+    /// the FCS, and with [`DecodeError::InvalidHdlcControl`] if the control field is unknown.
+    pub fn from_bytes(bytes: &[u8], modulus: Modulus) -> Result<Self> {
+        let minimum_length_in_bytes = 1 + 1 + 2;
+        if bytes.len() < minimum_length_in_bytes {
+            return Err(DecodeError::TruncatedFrame {
+                length_in_bytes: bytes.len(),
+                minimum_length_in_bytes,
+            }
+            .into());
+        }
+        let (protected_bytes, fcs) = bytes.split_at(bytes.len() - 2);
+        if fcs16(protected_bytes) != u16::from_le_bytes([fcs[0], fcs[1]]) {
+            return Err(Error::ChecksumMismatch);
+        }
+        let (control, control_length_in_bytes) =
+            Control::from_bytes(&protected_bytes[1..], modulus)?;
+        Ok(Self {
+            address: protected_bytes[0],
+            control,
+            information: Bytes::copy_from_slice(&protected_bytes[1 + control_length_in_bytes..]),
+        })
+    }
+}
+
+/// Translates the packets of a transfer to and from HDLC frames.
+///
+/// Both directions keep the state needed to turn the 3 or 7-bit counters back into sequence
+/// numbers of any width: a received counter stands for the sequence number closest to the one
+/// expected. Like in HDLC, every I frame repeats the last N(R) sent, so only a change of N(R)
+/// is handed over as a piggybacked acknowledgement.
+#[derive(Debug, Clone)]
+pub struct HdlcCodec {
+    address: u8,
+    modulus: Modulus,
+    sequence_number_width: SequenceNumberWidth,
+    /// N(R) carried by the last acknowledgement sent.
+    sent_receive_sequence_number: SequenceNumber,
+    /// Sequence number of the next I frame expected.
+    expected_send_sequence_number: SequenceNumber,
+    /// Sequence number of the last acknowledgement received.
+    received_receive_sequence_number: SequenceNumber,
+    /// Supervisory function that NACKs are sent as.
+    reject_function: SupervisoryFunction,
+}
+
+impl HdlcCodec {
+    pub fn new(address: u8, modulus: Modulus, sequence_number_width: SequenceNumberWidth) -> Self {
+        let zero = SequenceNumber::zero(sequence_number_width);
+        Self {
+            address,
+            modulus,
+            sequence_number_width,
+            sent_receive_sequence_number: zero,
+            expected_send_sequence_number: zero,
+            received_receive_sequence_number: zero,
+            reject_function: SupervisoryFunction::Reject,
+        }
+    }
+
+    /// Sends NACKs as SREJ rather than REJ. With a single frame outstanding both ask for that
+    /// frame again, and both are decoded as NACKs.
+    pub fn set_selective_reject(&mut self, is_selective: bool) {
+        self.reject_function = match is_selective {
+            true => SupervisoryFunction::SelectiveReject,
+            false => SupervisoryFunction::Reject,
+        };
+    }
+
+    pub fn modulus(&self) -> Modulus {
+        self.modulus
+    }
+
+    pub fn sequence_number_width(&self) -> SequenceNumberWidth {
+        self.sequence_number_width
+    }
+
+    /// HDLC frame carrying `packet`. Frames must be plaintext, as only their payload is kept
+    /// and protected by the FCS instead of the CRC-32. A NACK piggybacked on a frame only
+    /// acknowledges, since an I frame cannot carry a reject.
+    pub fn encode(&mut self, packet: &GenericPacket) -> Result<Vec<u8>> {
+        let hdlc_frame = match packet {
+            GenericPacket::Frame(frame) => self.information_frame(frame)?,
+            GenericPacket::Acknowledgement(acknowledgement) => {
+                let receive_sequence_number = acknowledgement.sequence_number();
+                let function = match acknowledgement {
+                    GenericAcknowledgement::ACK(ack) => {
+                        self.sent_receive_sequence_number = receive_sequence_number;
+                        match ack.is_ready() {
+                            true => SupervisoryFunction::ReceiveReady,
+                            false => SupervisoryFunction::ReceiveNotReady,
+                        }
+                    }
+                    GenericAcknowledgement::NACK(_) => self.reject_function,
+                };
+                HdlcFrame {
+                    address: self.address,
+                    control: Control::Supervisory {
+                        function,
+                        receive_sequence_number: self.counter(receive_sequence_number),
+                        poll_final: false,
+                    },
+                    information: Bytes::new(),
+                }
+            }
+            GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+                self.sent_receive_sequence_number = acknowledgement.sequence_number();
+                self.information_frame(frame)?
+            }
+        };
+        Ok(hdlc_frame.to_bytes(self.modulus))
+    }
+
+    /// U frame with this side's address.
+    pub fn encode_unnumbered(&self, function: UnnumberedFunction, poll_final: bool) -> Vec<u8> {
+        HdlcFrame {
+            address: self.address,
+            control: Control::Unnumbered {
+                function,
+                poll_final,
+            },
+            information: Bytes::new(),
+        }
+        .to_bytes(self.modulus)
+    }
+
+    /// Packet carried by `bytes`, or `None` for U frames, RNR being a not-ready ACK. Fails
+    /// like [`HdlcFrame::from_bytes`], a frame that fails its FCS being dropped like HDLC does.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<Option<GenericPacket>> {
+        let hdlc_frame = HdlcFrame::from_bytes(bytes, self.modulus)?;
+        Ok(self.decode_frame(&hdlc_frame))
+    }
+
+    /// Like [`HdlcCodec::decode`], for a frame parsed already.
+    pub fn decode_frame(&mut self, hdlc_frame: &HdlcFrame) -> Option<GenericPacket> {
+        match hdlc_frame.control {
+            Control::Information {
+                send_sequence_number,
+                receive_sequence_number,
+                ..
+            } => {
+                let sequence_number =
+                    self.sequence_number(send_sequence_number, self.expected_send_sequence_number);
+                self.expected_send_sequence_number = sequence_number.next();
+                let frame = Frame::new(&hdlc_frame.information, sequence_number);
+                match self.acknowledgement(receive_sequence_number) {
+                    Some(acknowledgement) => Some(GenericPacket::FrameWithAcknowledgement(
+                        frame,
+                        acknowledgement,
+                    )),
+                    None => Some(GenericPacket::Frame(frame)),
+                }
+            }
+            Control::Supervisory {
+                function,
+                receive_sequence_number,
+                ..
+            } => {
+                // A repeated RR is an acknowledgement all the same
+                let receive_sequence_number = self.sequence_number(
+                    receive_sequence_number,
+                    self.received_receive_sequence_number,
+                );
+                let acknowledgement = match function {
+                    SupervisoryFunction::ReceiveReady => {
+                        self.received_receive_sequence_number = receive_sequence_number;
+                        GenericAcknowledgement::ACK(ACK::new(receive_sequence_number))
+                    }
+                    SupervisoryFunction::ReceiveNotReady => {
+                        self.received_receive_sequence_number = receive_sequence_number;
+                        GenericAcknowledgement::ACK(ACK::not_ready(receive_sequence_number))
+                    }
+                    SupervisoryFunction::Reject | SupervisoryFunction::SelectiveReject => {
+                        GenericAcknowledgement::NACK(NACK::new(receive_sequence_number))
+                    }
+                };
+                Some(GenericPacket::Acknowledgement(acknowledgement))
+            }
+            Control::Unnumbered { function, .. } => {
+                log::debug!("Ignoring {:?} frame", function);
+                None
+            }
+        }
+    }
+
+    fn information_frame(&self, frame: &Frame) -> Result<HdlcFrame> {
+        Ok(HdlcFrame {
+            address: self.address,
+            control: Control::Information {
+                send_sequence_number: self.counter(frame.sequence_number()?),
+                receive_sequence_number: self.counter(self.sent_receive_sequence_number),
+                poll_final: false,
+            },
+            information: Bytes::copy_from_slice(frame.payload()?),
+        })
+    }
+
+    /// New acknowledgement carried by the N(R) of an I frame.
+    fn acknowledgement(&mut self, receive_sequence_number: u8) -> Option<GenericAcknowledgement> {
+        let receive_sequence_number = self.sequence_number(
+            receive_sequence_number,
+            self.received_receive_sequence_number,
+        );
+        if receive_sequence_number == self.received_receive_sequence_number {
+            return None;
+        }
+        self.received_receive_sequence_number = receive_sequence_number;
+        Some(GenericAcknowledgement::ACK(ACK::new(
+            receive_sequence_number,
+        )))
+    }
+
+    fn counter(&self, sequence_number: SequenceNumber) -> u8 {
+        (sequence_number.value() % self.modulus.value() as u32) as u8
+    }
+
+    /// Sequence number within half the modulus of `reference` whose counter is `counter`.
+    fn sequence_number(&self, counter: u8, reference: SequenceNumber) -> SequenceNumber {
+        let modulus = self.modulus.value() as i64;
+        let offset = (counter as i64 - reference.value() as i64).rem_euclid(modulus);
+        let offset = match offset >= modulus / 2 {
+            true => offset - modulus,
+            false => offset,
+        };
+        SequenceNumber::new(
+            (reference.value() as i64 + offset)
+                .rem_euclid(self.sequence_number_width.modulus() as i64) as u64,
+            self.sequence_number_width,
+        )
+    }
+}
+
+#[test]
+fn transfers_map_onto_hdlc_frames_and_back() {
+    // Check value of CRC-16/X.25
+    assert_eq!(fcs16(b"123456789"), 0x906E);
+
+    // RR with N(R) = 5 and I frame with N(S) = 3, N(R) = 6 and P set, in both moduli
+    let receive_ready = Control::Supervisory {
+        function: SupervisoryFunction::ReceiveReady,
+        receive_sequence_number: 5,
+        poll_final: false,
+    };
+    let information = Control::Information {
+        send_sequence_number: 3,
+        receive_sequence_number: 6,
+        poll_final: true,
+    };
+    assert_eq!(receive_ready.to_bytes(Modulus::Modulo8), [0xA1]);
+    assert_eq!(information.to_bytes(Modulus::Modulo8), [0xD6]);
+    assert_eq!(receive_ready.to_bytes(Modulus::Modulo128), [0x01, 0x0A]);
+    assert_eq!(information.to_bytes(Modulus::Modulo128), [0x06, 0x0D]);
+    let set_mode = HdlcFrame {
+        address: 0x03,
+        control: Control::Unnumbered {
+            function: UnnumberedFunction::SetAsynchronousBalancedMode,
+            poll_final: true,
+        },
+        information: Bytes::new(),
+    };
+    let set_mode_bytes = set_mode.to_bytes(Modulus::Modulo8);
+    assert_eq!(set_mode_bytes[..2], [0x03, 0x3F]);
+    assert_eq!(
+        HdlcFrame::from_bytes(&set_mode_bytes, Modulus::Modulo128).unwrap(),
+        set_mode
+    );
+
+    // Counters wrap many times over a transfer numbered with wider sequence numbers
+    for (modulus, sequence_number_width) in [
+        (Modulus::Modulo8, SequenceNumberWidth::AlternatingBit),
+        (Modulus::Modulo8, SequenceNumberWidth::Bits16),
+        (Modulus::Modulo128, SequenceNumberWidth::Bits8),
+        (Modulus::Modulo128, SequenceNumberWidth::Bits32),
+    ] {
+        let mut sender = HdlcCodec::new(0x03, modulus, sequence_number_width);
+        let mut receiver = HdlcCodec::new(0x01, modulus, sequence_number_width);
+        for frame_index in 0..600u64 {
+            let sequence_number =
+                SequenceNumber::for_frame_index(frame_index, sequence_number_width);
+            let frame = Frame::new(&frame_index.to_be_bytes(), sequence_number);
+            for packet in [
+                GenericPacket::Frame(frame.clone()),
+                // Retransmission after a lost acknowledgement
+                GenericPacket::Frame(frame.clone()),
+            ] {
+                let bytes = sender.encode(&packet).unwrap();
+                assert_eq!(bytes.len(), 8 + HdlcFrame::overhead_in_bytes(modulus));
+                assert_eq!(receiver.decode(&bytes).unwrap(), Some(packet));
+            }
+
+            let next_sequence_number = sequence_number.next();
+            let acknowledgements = [
+                GenericPacket::Acknowledgement(GenericAcknowledgement::NACK(NACK::new(
+                    sequence_number,
+                ))),
+                GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(ACK::not_ready(
+                    next_sequence_number,
+                ))),
+                GenericPacket::Acknowledgement(GenericAcknowledgement::ACK(ACK::new(
+                    next_sequence_number,
+                ))),
+            ];
+            for acknowledgement in acknowledgements {
+                let bytes = receiver.encode(&acknowledgement).unwrap();
+                assert_eq!(bytes.len(), HdlcFrame::overhead_in_bytes(modulus));
+                assert_eq!(sender.decode(&bytes).unwrap(), Some(acknowledgement));
+            }
+        }
+
+        // The receiver answers with a frame of its own, acknowledging nothing new
+        let frame = Frame::new(&[42], SequenceNumber::zero(sequence_number_width));
+        let mut bytes = receiver
+            .encode(&GenericPacket::Frame(frame.clone()))
+            .unwrap();
+        assert_eq!(
+            sender.decode(&bytes).unwrap(),
+            Some(GenericPacket::Frame(frame))
+        );
+        bytes[1] ^= 0x01;
+        assert!(matches!(
+            sender.decode(&bytes),
+            Err(Error::ChecksumMismatch)
+        ));
+    }
+
+    // NACKs may go as SREJ, which reads back as a NACK too
+    let mut receiver = HdlcCodec::new(0x01, Modulus::Modulo8, SequenceNumberWidth::Bits8);
+    receiver.set_selective_reject(true);
+    let nack = GenericPacket::Acknowledgement(GenericAcknowledgement::NACK(NACK::new(
+        SequenceNumber::new(3, SequenceNumberWidth::Bits8),
+    )));
+    let bytes = receiver.encode(&nack).unwrap();
+    assert_eq!(
+        HdlcFrame::from_bytes(&bytes, Modulus::Modulo8)
+            .unwrap()
+            .control,
+        Control::Supervisory {
+            function: SupervisoryFunction::SelectiveReject,
+            receive_sequence_number: 3,
+            poll_final: false,
+        }
+    );
+    let mut sender = HdlcCodec::new(0x03, Modulus::Modulo8, SequenceNumberWidth::Bits8);
+    assert_eq!(sender.decode(&bytes).unwrap(), Some(nack));
+}
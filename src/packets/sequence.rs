@@ -0,0 +1,171 @@
+use std::fmt::{self, Display};
+
+use crate::packets::{SEQUENCE_ONE, SEQUENCE_ZERO, correct_sequence_byte};
+
+/// Number of bits used to count frames.
+///
+/// `AlternatingBit` keeps the original single bit spread over a whole byte and recovered by
+/// majority vote. The other widths are plain modular counters that are covered by the frame
+/// checksum, so a corrupted sequence number is detected instead of guessed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SequenceNumberWidth {
+    AlternatingBit,
+    Bits8,
+    Bits16,
+    Bits32,
+}
+
+impl SequenceNumberWidth {
+    pub const fn length_in_bytes(&self) -> usize {
+        match self {
+            SequenceNumberWidth::AlternatingBit | SequenceNumberWidth::Bits8 => 1,
+            SequenceNumberWidth::Bits16 => 2,
+            SequenceNumberWidth::Bits32 => 4,
+        }
+    }
+
+    pub const fn modulus(&self) -> u64 {
+        match self {
+            SequenceNumberWidth::AlternatingBit => 2,
+            SequenceNumberWidth::Bits8 => 1 << 8,
+            SequenceNumberWidth::Bits16 => 1 << 16,
+            SequenceNumberWidth::Bits32 => 1 << 32,
+        }
+    }
+
+    pub const fn id(&self) -> u8 {
+        match self {
+            SequenceNumberWidth::AlternatingBit => 1,
+            SequenceNumberWidth::Bits8 => 8,
+            SequenceNumberWidth::Bits16 => 16,
+            SequenceNumberWidth::Bits32 => 32,
+        }
+    }
+
+    pub const fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(SequenceNumberWidth::AlternatingBit),
+            8 => Some(SequenceNumberWidth::Bits8),
+            16 => Some(SequenceNumberWidth::Bits16),
+            32 => Some(SequenceNumberWidth::Bits32),
+            _ => None,
+        }
+    }
+
+    pub const fn is_alternating_bit(&self) -> bool {
+        matches!(self, SequenceNumberWidth::AlternatingBit)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SequenceNumber {
+    value: u32,
+    width: SequenceNumberWidth,
+}
+
+impl SequenceNumber {
+    pub const fn new(value: u64, width: SequenceNumberWidth) -> Self {
+        Self {
+            value: (value % width.modulus()) as u32,
+            width,
+        }
+    }
+
+    pub const fn zero(width: SequenceNumberWidth) -> Self {
+        Self::new(0, width)
+    }
+
+    /// Sequence number carried by the `frame_index`-th frame of a transfer.
+    pub const fn for_frame_index(frame_index: u64, width: SequenceNumberWidth) -> Self {
+        Self::new(frame_index, width)
+    }
+
+    pub const fn value(&self) -> u32 {
+        self.value
+    }
+
+    pub const fn width(&self) -> SequenceNumberWidth {
+        self.width
+    }
+
+    pub const fn next(&self) -> Self {
+        Self::new(self.value as u64 + 1, self.width)
+    }
+
+    pub const fn previous(&self) -> Self {
+        Self::new(self.value as u64 + self.width.modulus() - 1, self.width)
+    }
+
+    /// Signed modular distance from `other` to `self`, in the range `[-modulus/2, modulus/2)`.
+    pub fn distance_from(&self, other: SequenceNumber) -> i64 {
+        let modulus = self.width.modulus() as i64;
+        let difference = (self.value as i64 - other.value as i64).rem_euclid(modulus);
+        if difference >= modulus / 2 {
+            difference - modulus
+        } else {
+            difference
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self.width {
+            SequenceNumberWidth::AlternatingBit => vec![match self.value {
+                0 => SEQUENCE_ZERO,
+                _ => SEQUENCE_ONE,
+            }],
+            width => self.value.to_be_bytes()[4 - width.length_in_bytes()..].to_vec(),
+        }
+    }
+
+    /// Returns `None` if `bytes` does not have the length of `width`.
+    pub fn from_bytes(bytes: &[u8], width: SequenceNumberWidth) -> Option<Self> {
+        if bytes.len() != width.length_in_bytes() {
+            return None;
+        }
+        match width {
+            SequenceNumberWidth::AlternatingBit => Some(Self::new(
+                (correct_sequence_byte(bytes[0]) == SEQUENCE_ONE) as u64,
+                width,
+            )),
+            width => {
+                let mut value = [0u8; 4];
+                value[4 - bytes.len()..].copy_from_slice(bytes);
+                Some(Self::new(u32::from_be_bytes(value) as u64, width))
+            }
+        }
+    }
+}
+
+impl Display for SequenceNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.value)
+    }
+}
+
+#[test]
+fn sequence_numbers_wrap_and_round_trip() {
+    for width in [
+        SequenceNumberWidth::AlternatingBit,
+        SequenceNumberWidth::Bits8,
+        SequenceNumberWidth::Bits16,
+        SequenceNumberWidth::Bits32,
+    ] {
+        let last = SequenceNumber::new(width.modulus() - 1, width);
+        assert_eq!(last.next(), SequenceNumber::zero(width));
+        assert_eq!(SequenceNumber::zero(width).previous(), last);
+        assert_eq!(
+            SequenceNumber::from_bytes(&last.to_bytes(), width),
+            Some(last)
+        );
+    }
+
+    let width = SequenceNumberWidth::Bits8;
+    assert_eq!(
+        SequenceNumber::new(2, width).distance_from(SequenceNumber::new(250, width)),
+        8
+    );
+    assert_eq!(
+        SequenceNumber::new(250, width).distance_from(SequenceNumber::new(2, width)),
+        -8
+    );
+}
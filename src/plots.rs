@@ -0,0 +1,165 @@
+use std::{io, ops::Range, path::Path};
+
+use plotters::{
+    coord::{
+        Shift,
+        ranged1d::{AsRangedCoord, ValueFormatter},
+    },
+    prelude::*,
+};
+
+use crate::error::Result;
+
+const CHART_SIZE: (u32, u32) = (960, 640);
+
+/// Measured points of one configuration, and the analytic curve they should follow.
+#[derive(Debug, Clone, Default)]
+pub struct Series {
+    pub label: String,
+    pub measured: Vec<(f64, f64)>,
+    pub analytic: Vec<(f64, f64)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chart {
+    pub title: String,
+    pub x_label: String,
+    pub y_label: String,
+    pub logarithmic_x: bool,
+    pub series: Vec<Series>,
+}
+
+impl Chart {
+    fn points(&self) -> impl Iterator<Item = &(f64, f64)> {
+        self.series
+            .iter()
+            .flat_map(|series| series.measured.iter().chain(&series.analytic))
+    }
+
+    fn x_range(&self) -> Range<f64> {
+        let (min, max) = self
+            .points()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &(x, _)| {
+                (min.min(x), max.max(x))
+            });
+        match min < max {
+            true => min..max,
+            false => min - 1.0..min + 1.0,
+        }
+    }
+
+    fn y_range(&self) -> Range<f64> {
+        let max = self
+            .points()
+            .map(|&(_, y)| y)
+            .filter(|y| y.is_finite())
+            .fold(0.0, f64::max);
+        0.0..max * 1.1 + f64::EPSILON
+    }
+
+    /// Writes the chart as SVG or PNG, depending on the extension of `path`.
+    pub fn render(&self, path: &Path) -> Result<()> {
+        let is_png = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("png"));
+        match is_png {
+            true => self.draw(BitMapBackend::new(path, CHART_SIZE).into_drawing_area())?,
+            false => self.draw(SVGBackend::new(path, CHART_SIZE).into_drawing_area())?,
+        }
+        Ok(())
+    }
+
+    fn draw<DB: DrawingBackend>(&self, root: DrawingArea<DB, Shift>) -> io::Result<()> {
+        let x_range = self.x_range();
+        let y_range = self.y_range();
+        match self.logarithmic_x {
+            true => self.draw_on(root, x_range.log_scale(), y_range),
+            false => self.draw_on(root, x_range, y_range),
+        }
+    }
+
+    fn draw_on<DB, X>(
+        &self,
+        root: DrawingArea<DB, Shift>,
+        x_range: X,
+        y_range: Range<f64>,
+    ) -> io::Result<()>
+    where
+        DB: DrawingBackend,
+        X: AsRangedCoord<Value = f64>,
+        X::CoordDescType: ValueFormatter<f64>,
+    {
+        let to_io_error =
+            |error: DrawingAreaErrorKind<DB::ErrorType>| io::Error::other(error.to_string());
+        root.fill(&WHITE).map_err(to_io_error)?;
+        let mut chart = ChartBuilder::on(&root)
+            .caption(&self.title, ("sans-serif", 24))
+            .margin(16)
+            .x_label_area_size(48)
+            .y_label_area_size(72)
+            .build_cartesian_2d(x_range, y_range)
+            .map_err(to_io_error)?;
+        chart
+            .configure_mesh()
+            .x_desc(&self.x_label)
+            .y_desc(&self.y_label)
+            .draw()
+            .map_err(to_io_error)?;
+
+        for (index, series) in self.series.iter().enumerate() {
+            let color = Palette99::pick(index).to_rgba();
+            chart
+                .draw_series(LineSeries::new(series.analytic.iter().copied(), color))
+                .map_err(to_io_error)?
+                .label(format!("{} (analytic)", series.label))
+                .legend(move |(x, y)| PathElement::new([(x, y), (x + 20, y)], color));
+            chart
+                .draw_series(
+                    series
+                        .measured
+                        .iter()
+                        .map(|&point| Circle::new(point, 4, color.filled())),
+                )
+                .map_err(to_io_error)?
+                .label(format!("{} (measured)", series.label))
+                .legend(move |(x, y)| Circle::new((x + 10, y), 4, color.filled()));
+        }
+
+        chart
+            .configure_series_labels()
+            .background_style(WHITE.mix(0.8))
+            .border_style(BLACK)
+            .draw()
+            .map_err(to_io_error)?;
+        root.present().map_err(to_io_error)
+    }
+}
+
+#[test]
+fn charts_are_written_as_svg_and_png() {
+    let chart = Chart {
+        title: "Average tries".to_owned(),
+        x_label: "Bit error probability".to_owned(),
+        y_label: "Tries".to_owned(),
+        logarithmic_x: true,
+        series: vec![Series {
+            label: "1024 B".to_owned(),
+            measured: vec![(1e-6, 1.0), (1e-4, 2.4)],
+            analytic: vec![(1e-6, 1.01), (1e-5, 1.09), (1e-4, 2.28)],
+        }],
+    };
+    let directory = std::env::temp_dir().join(format!("stopandwait-plots-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+
+    chart.render(&directory.join("tries.svg")).unwrap();
+    chart.render(&directory.join("tries.png")).unwrap();
+    let svg = std::fs::read_to_string(directory.join("tries.svg")).unwrap();
+    assert!(svg.contains("<svg") && svg.contains("1024 B (measured)"));
+    assert!(
+        std::fs::read(directory.join("tries.png"))
+            .unwrap()
+            .starts_with(b"\x89PNG")
+    );
+
+    std::fs::remove_dir_all(directory).unwrap();
+}
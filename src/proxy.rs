@@ -0,0 +1,538 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{
+    error::Result,
+    error_trace::ErrorTraceReplay,
+    packets::{Packet, sample_bit_errors},
+    reliable_stream::MAX_DATAGRAM_LENGTH_IN_BYTES,
+};
+
+// Forwards UDP datagrams between a client and a server through the impairments of the
+// simulated lines, like netem does for a network interface, so that real endpoints can be
+// tested unmodified. Datagrams from the client go through the forward line to the server, the
+// answers through the backward line to the last client heard from.
+
+/// How often the forwarding threads check whether they have been stopped.
+const STOP_POLLING_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Two-state burst error model: bits are flipped with a low probability in the good state and
+/// a high one in the bad state, and the line moves between them after every bit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GilbertElliott {
+    pub good_to_bad_probability: f64,
+    pub bad_to_good_probability: f64,
+    pub good_bit_error_probability: f64,
+    pub bad_bit_error_probability: f64,
+    pub is_bad: bool,
+}
+
+impl GilbertElliott {
+    /// Long-run fraction of flipped bits.
+    pub fn bit_error_rate(&self) -> f64 {
+        let bad_fraction = self.good_to_bad_probability
+            / (self.good_to_bad_probability + self.bad_to_good_probability);
+        bad_fraction * self.bad_bit_error_probability
+            + (1.0 - bad_fraction) * self.good_bit_error_probability
+    }
+
+    /// Positions of the bits flipped among the next `length_in_bits`, carrying the state over
+    /// from one call to the next.
+    pub fn sample_bit_errors<R: Rng + ?Sized>(
+        &mut self,
+        length_in_bits: usize,
+        rng: &mut R,
+    ) -> Vec<usize> {
+        let mut bit_errors = Vec::new();
+        let mut bit_index = 0;
+        while bit_index < length_in_bits {
+            let (leaving_probability, bit_error_probability) = match self.is_bad {
+                true => (self.bad_to_good_probability, self.bad_bit_error_probability),
+                false => (
+                    self.good_to_bad_probability,
+                    self.good_bit_error_probability,
+                ),
+            };
+            // The bit of the transition is still sent in the current state
+            let remaining_length_in_bits = length_in_bits - bit_index;
+            let stay_length_in_bits =
+                sample_bit_errors(remaining_length_in_bits, leaving_probability, rng)
+                    .first()
+                    .map(|&transition| transition + 1);
+            let segment_length_in_bits = stay_length_in_bits.unwrap_or(remaining_length_in_bits);
+            bit_errors.extend(
+                sample_bit_errors(segment_length_in_bits, bit_error_probability, rng)
+                    .into_iter()
+                    .map(|offset| bit_index + offset),
+            );
+            bit_index += segment_length_in_bits;
+            if stay_length_in_bits.is_some() {
+                self.is_bad = !self.is_bad;
+            }
+        }
+        bit_errors
+    }
+}
+
+/// Bits flipped in the datagrams that get through.
+#[derive(Debug, Clone, Default)]
+pub enum BitErrors {
+    #[default]
+    None,
+    /// Binary symmetric channel, every bit flipped independently with this probability.
+    Random(f64),
+    Burst(GilbertElliott),
+    /// Captured errors and losses, played one datagram after the other.
+    Replayed(ErrorTraceReplay),
+}
+
+/// Impairments applied to every datagram of a line, in this order: loss, bit errors,
+/// duplication, serialization at the line rate, then propagation delay.
+#[derive(Debug, Clone, Default)]
+pub struct Impairments {
+    pub bit_errors: BitErrors,
+    pub loss_probability: f64,
+    pub delay: Duration,
+    pub duplication_probability: f64,
+    /// Probability that a datagram is held back for `reordering_delay` on top of the delay,
+    /// letting the ones sent after it overtake it.
+    pub reordering_probability: f64,
+    pub reordering_delay: Duration,
+    /// Unlimited when `None`, datagrams then only queue for the delay.
+    pub rate_in_bits_per_second: Option<u64>,
+    /// Seed of the random impairments, so that a run can be reproduced. Drawn from the system
+    /// when `None`.
+    pub seed: Option<u64>,
+}
+
+/// What a line did to the datagrams it carried.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImpairmentCounters {
+    pub datagrams: usize,
+    pub lost: usize,
+    pub corrupted: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+}
+
+/// Raw bytes of a datagram, for the bit error models of packets.
+#[derive(Debug, Clone, PartialEq)]
+struct Datagram(Vec<u8>);
+
+impl Packet for Datagram {
+    fn is_valid(&self) -> bool {
+        true
+    }
+
+    fn length_in_bits(&self) -> usize {
+        self.0.len() * 8
+    }
+
+    fn with_flipped_bits(&self, bit_indices: &[usize]) -> Self {
+        let mut bytes = self.0.clone();
+        for &bit_index in bit_indices {
+            if let Some(byte) = bytes.get_mut(bit_index / 8) {
+                *byte ^= 1 << (bit_index % 8);
+            }
+        }
+        Self(bytes)
+    }
+}
+
+/// One direction of the proxy, scheduling the delivery of every datagram it is handed.
+#[derive(Debug)]
+pub struct ImpairedLine {
+    direction: &'static str,
+    impairments: Impairments,
+    rng: StdRng,
+    /// When the last datagram queued for serialization is fully sent.
+    line_free_at: Instant,
+    /// Deliveries by time, then by order of scheduling.
+    scheduled: BinaryHeap<Reverse<(Instant, usize, Vec<u8>)>>,
+    scheduled_datagrams: usize,
+    counters: ImpairmentCounters,
+}
+
+impl ImpairedLine {
+    pub fn new(direction: &'static str, impairments: Impairments) -> Self {
+        let rng = match impairments.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_rng(&mut rand::rng()),
+        };
+        Self {
+            direction,
+            impairments,
+            rng,
+            line_free_at: Instant::now(),
+            scheduled: BinaryHeap::new(),
+            scheduled_datagrams: 0,
+            counters: ImpairmentCounters::default(),
+        }
+    }
+
+    pub fn counters(&self) -> ImpairmentCounters {
+        self.counters
+    }
+
+    /// Applies the impairments to `datagram`, received at `now`, and logs every one of them.
+    pub fn push(&mut self, datagram: &[u8], now: Instant) {
+        self.counters.datagrams += 1;
+        let index = self.counters.datagrams;
+        let direction = self.direction;
+        if self.rng.random_bool(self.impairments.loss_probability) {
+            self.counters.lost += 1;
+            log::info!("{}: datagram {} lost", direction, index);
+            return;
+        }
+        let datagram = Datagram(datagram.to_vec());
+        let flipped_bit_indices = match &mut self.impairments.bit_errors {
+            BitErrors::None => Vec::new(),
+            BitErrors::Random(bit_error_probability) => sample_bit_errors(
+                datagram.length_in_bits(),
+                *bit_error_probability,
+                &mut self.rng,
+            ),
+            BitErrors::Burst(model) => {
+                model.sample_bit_errors(datagram.length_in_bits(), &mut self.rng)
+            }
+            BitErrors::Replayed(replay) => match replay.transmit_bits(datagram.length_in_bits()) {
+                Some(flipped_bit_indices) => flipped_bit_indices,
+                None => {
+                    self.counters.lost += 1;
+                    log::info!("{}: datagram {} lost by the error trace", direction, index);
+                    return;
+                }
+            },
+        };
+        if !flipped_bit_indices.is_empty() {
+            self.counters.corrupted += 1;
+            log::info!(
+                "{}: datagram {} corrupted, bits {:?} flipped",
+                direction,
+                index,
+                flipped_bit_indices
+            );
+        }
+        let datagram = datagram.with_flipped_bits(&flipped_bit_indices).0;
+        let copies = match self
+            .rng
+            .random_bool(self.impairments.duplication_probability)
+        {
+            true => {
+                self.counters.duplicated += 1;
+                log::info!("{}: datagram {} duplicated", direction, index);
+                2
+            }
+            false => 1,
+        };
+
+        for _ in 0..copies {
+            let mut delivery = now;
+            if let Some(rate_in_bits_per_second) = self.impairments.rate_in_bits_per_second {
+                let serialization_time = Duration::from_secs_f64(
+                    (datagram.len() * 8) as f64 / rate_in_bits_per_second as f64,
+                );
+                self.line_free_at = self.line_free_at.max(now) + serialization_time;
+                if self.line_free_at > now + serialization_time {
+                    log::info!(
+                        "{}: datagram {} queued for {:?} behind the line rate",
+                        direction,
+                        index,
+                        self.line_free_at - now - serialization_time
+                    );
+                }
+                delivery = self.line_free_at;
+            }
+            delivery += self.impairments.delay;
+            if self
+                .rng
+                .random_bool(self.impairments.reordering_probability)
+            {
+                self.counters.reordered += 1;
+                log::info!(
+                    "{}: datagram {} held back {:?} to be reordered",
+                    direction,
+                    index,
+                    self.impairments.reordering_delay
+                );
+                delivery += self.impairments.reordering_delay;
+            }
+            self.scheduled_datagrams += 1;
+            self.scheduled.push(Reverse((
+                delivery,
+                self.scheduled_datagrams,
+                datagram.clone(),
+            )));
+        }
+    }
+
+    pub fn next_delivery(&self) -> Option<Instant> {
+        self.scheduled
+            .peek()
+            .map(|Reverse((delivery, _, _))| *delivery)
+    }
+
+    /// Next datagram due at `now`, if any.
+    pub fn pop_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        match self.next_delivery() {
+            Some(delivery) if delivery <= now => self
+                .scheduled
+                .pop()
+                .map(|Reverse((_, _, datagram))| datagram),
+            _ => None,
+        }
+    }
+}
+
+/// Sockets of a proxy, bound but not forwarding yet.
+#[derive(Debug)]
+pub struct Proxy {
+    /// Where clients send their datagrams.
+    listening_socket: UdpSocket,
+    /// Connected to the server.
+    forwarding_socket: UdpSocket,
+}
+
+impl Proxy {
+    /// Listens on `listen_address` and forwards to `forward_address` from an ephemeral port.
+    pub fn bind(
+        listen_address: impl ToSocketAddrs,
+        forward_address: impl ToSocketAddrs,
+    ) -> Result<Self> {
+        let listening_socket = UdpSocket::bind(listen_address)?;
+        let forward_address = forward_address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No forward address"))?;
+        let unspecified_address: SocketAddr = match forward_address {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let forwarding_socket = UdpSocket::bind(unspecified_address)?;
+        forwarding_socket.connect(forward_address)?;
+        Ok(Self {
+            listening_socket,
+            forwarding_socket,
+        })
+    }
+
+    pub fn listen_address(&self) -> Result<SocketAddr> {
+        Ok(self.listening_socket.local_addr()?)
+    }
+
+    /// Address the server sees the datagrams coming from.
+    pub fn forwarding_address(&self) -> Result<SocketAddr> {
+        Ok(self.forwarding_socket.local_addr()?)
+    }
+
+    /// Forwards in both directions on their own threads until [`ProxyHandle::stop`].
+    pub fn spawn(self, forward: Impairments, backward: Impairments) -> Result<ProxyHandle> {
+        let is_stopped = Arc::new(AtomicBool::new(false));
+        let client_address = Arc::new(Mutex::new(None));
+        let listening_socket = self.listening_socket.try_clone()?;
+        let forwarding_socket = self.forwarding_socket.try_clone()?;
+
+        let forward_thread = {
+            let is_stopped = is_stopped.clone();
+            let client_address = client_address.clone();
+            thread::spawn(move || {
+                forward_datagrams(
+                    ImpairedLine::new("client->server", forward),
+                    &self.listening_socket,
+                    |datagram| forwarding_socket.send(datagram).map(|_| ()),
+                    |source| *client_address.lock().unwrap() = Some(source),
+                    &is_stopped,
+                )
+            })
+        };
+        let backward_thread = {
+            let is_stopped = is_stopped.clone();
+            thread::spawn(move || {
+                forward_datagrams(
+                    ImpairedLine::new("server->client", backward),
+                    &self.forwarding_socket,
+                    |datagram| match *client_address.lock().unwrap() {
+                        Some(client_address) => listening_socket
+                            .send_to(datagram, client_address)
+                            .map(|_| ()),
+                        None => {
+                            log::warn!("Dropping datagram from the server before any client");
+                            Ok(())
+                        }
+                    },
+                    |_| {},
+                    &is_stopped,
+                )
+            })
+        };
+        Ok(ProxyHandle {
+            is_stopped,
+            threads: [forward_thread, backward_thread],
+        })
+    }
+}
+
+/// Proxy forwarding on its threads.
+#[derive(Debug)]
+pub struct ProxyHandle {
+    is_stopped: Arc<AtomicBool>,
+    threads: [thread::JoinHandle<Result<ImpairmentCounters>>; 2],
+}
+
+impl ProxyHandle {
+    /// Waits for the threads, which only return on an I/O error unless stopped.
+    pub fn join(self) -> Result<[ImpairmentCounters; 2]> {
+        let [forward_thread, backward_thread] = self.threads;
+        let forward = forward_thread.join().expect("Forwarding thread panicked")?;
+        let backward = backward_thread
+            .join()
+            .expect("Forwarding thread panicked")?;
+        Ok([forward, backward])
+    }
+
+    /// Stops forwarding, dropping the datagrams still in flight, and returns the counters of
+    /// the forward and backward lines.
+    pub fn stop(self) -> Result<[ImpairmentCounters; 2]> {
+        self.is_stopped.store(true, Ordering::Relaxed);
+        self.join()
+    }
+}
+
+/// Receives datagrams from `socket` into `line` and sends them on once they are due.
+fn forward_datagrams(
+    mut line: ImpairedLine,
+    socket: &UdpSocket,
+    send: impl Fn(&[u8]) -> io::Result<()>,
+    on_source: impl Fn(SocketAddr),
+    is_stopped: &AtomicBool,
+) -> Result<ImpairmentCounters> {
+    let mut buffer = vec![0; MAX_DATAGRAM_LENGTH_IN_BYTES];
+    while !is_stopped.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        while let Some(datagram) = line.pop_due(now) {
+            match send(&datagram) {
+                Ok(()) => {}
+                // Nobody listening yet, the datagram is lost like on a real network
+                Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+                    log::debug!("Datagram refused by the peer");
+                }
+                Err(error) => return Err(error.into()),
+            }
+        }
+        let timeout = line
+            .next_delivery()
+            .map_or(STOP_POLLING_INTERVAL, |delivery| {
+                delivery
+                    .saturating_duration_since(now)
+                    .min(STOP_POLLING_INTERVAL)
+            })
+            .max(Duration::from_micros(1));
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv_from(&mut buffer) {
+            Ok((length_in_bytes, source)) => {
+                on_source(source);
+                line.push(&buffer[..length_in_bytes], Instant::now());
+            }
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::ConnectionRefused
+                ) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(line.counters())
+}
+
+#[test]
+fn streams_get_through_an_impaired_proxy() {
+    use std::io::{Read, Write};
+
+    use crate::{
+        endpoint::EndpointTimers, packets::sequence::SequenceNumberWidth,
+        reliable_stream::ReliableStream, session::SessionParameters,
+    };
+
+    // Bursts average a bit error rate of 1e-4 over the long run
+    let burst = GilbertElliott {
+        good_to_bad_probability: 1e-5,
+        bad_to_good_probability: 1e-2,
+        good_bit_error_probability: 0.0,
+        bad_bit_error_probability: 0.1,
+        is_bad: false,
+    };
+    assert!((burst.bit_error_rate() - 1e-4).abs() < 1e-6);
+    let forward = Impairments {
+        bit_errors: BitErrors::Burst(burst),
+        loss_probability: 0.1,
+        delay: Duration::from_millis(2),
+        duplication_probability: 0.1,
+        reordering_probability: 0.1,
+        reordering_delay: Duration::from_millis(5),
+        rate_in_bits_per_second: Some(20_000_000),
+        // Every impairment shows up within the first datagrams with these seeds
+        seed: Some(1),
+    };
+    let backward = Impairments {
+        bit_errors: BitErrors::Random(1e-3),
+        loss_probability: 0.1,
+        seed: Some(2),
+        ..Impairments::default()
+    };
+
+    let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let proxy = Proxy::bind("127.0.0.1:0", server_socket.local_addr().unwrap()).unwrap();
+    let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client_socket
+        .connect(proxy.listen_address().unwrap())
+        .unwrap();
+    server_socket
+        .connect(proxy.forwarding_address().unwrap())
+        .unwrap();
+    let proxy = proxy.spawn(forward, backward).unwrap();
+
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(30),
+        linger: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(5)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters {
+        sequence_number_width: SequenceNumberWidth::Bits16,
+        ..SessionParameters::default()
+    };
+    let message: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let expected_message = message.clone();
+    let server = thread::spawn(move || {
+        let mut stream =
+            ReliableStream::new(server_socket, 512, session_parameters, None, timers).unwrap();
+        let mut received_message = Vec::new();
+        stream.read_to_end(&mut received_message).unwrap();
+        assert_eq!(received_message, expected_message);
+        stream.close().unwrap();
+    });
+    let mut stream =
+        ReliableStream::new(client_socket, 512, session_parameters, None, timers).unwrap();
+    stream.write_all(&message).unwrap();
+    stream.close().unwrap();
+    server.join().unwrap();
+
+    let [forward, backward] = proxy.stop().unwrap();
+    assert!(forward.datagrams > 40 && backward.datagrams > 40);
+    assert!(forward.lost > 0 && forward.duplicated > 0 && forward.reordered > 0);
+    assert!(backward.lost > 0 && backward.duplicated == 0);
+}
@@ -0,0 +1,547 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::UdpSocket,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, Bytes};
+
+use crate::{
+    compression::{Compression, CompressionMode},
+    encryption::{PreSharedKey, SessionCipher},
+    endpoint::{EndpointTimers, ReceiverStateMachine, TransmitterAction, TransmitterStateMachine},
+    error::{Error, Result},
+    message::build_session_cipher,
+    packets::{
+        acknowledgement::{GenericAcknowledgement, acknowledgement_length_in_bytes},
+        frame::Frame,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    },
+    session::SessionParameters,
+};
+
+// Ordered, retransmitted byte stream in both directions over a datagram socket. Each direction
+// is a transfer that never ends until the stream is closed: the session header, then one frame
+// per flushed chunk of bytes, then the empty end-of-stream frame. Frames and acknowledgements
+// share the socket and are told apart by their length, every frame being longer than an
+// acknowledgement.
+
+/// Largest datagram accepted from the socket, well above the largest frame.
+pub const MAX_DATAGRAM_LENGTH_IN_BYTES: usize = 64 * 1024;
+
+/// Socket that may lose, corrupt, duplicate or reorder datagrams, but never splits them.
+pub trait DatagramSocket {
+    fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()>;
+    /// Waits at most `timeout` for a datagram, forever for `None`, and returns its length or
+    /// `None` once the timeout expires.
+    fn recv_datagram(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<usize>>;
+}
+
+/// Socket connected to the peer.
+impl DatagramSocket for UdpSocket {
+    fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.send(datagram).map(|_| ())
+    }
+
+    fn recv_datagram(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<usize>> {
+        // A zero timeout is rejected by the socket
+        self.set_read_timeout(timeout.map(|timeout| timeout.max(Duration::from_micros(1))))?;
+        match self.recv(buffer) {
+            Ok(length_in_bytes) => Ok(Some(length_in_bytes)),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// A refused datagram is the peer socket gone away, anything else is an I/O error.
+fn socket_error(error: io::Error) -> Error {
+    match error.kind() {
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => Error::ChannelClosed,
+        _ => Error::Io(error),
+    }
+}
+
+/// Reliable byte stream over a [`DatagramSocket`], like a TCP stream over UDP.
+///
+/// Written bytes are buffered up to a full payload, then sent as one frame and retransmitted
+/// until acknowledged, so a write returns once the previous chunk got through. Reading first
+/// sends what was written so far, so that a request is never held back waiting for its
+/// answer. Reads return 0 once the peer has closed its side with [`ReliableStream::close`].
+///
+/// Payloads are always compressed frame by frame, as the receiver delivers them as they come.
+///
+/// Received bytes wait for the reader in memory. With
+/// [`ReliableStream::set_receive_buffer_limit`], the peer is told to hold its frames back
+/// while too many are waiting, so a slow reader slows the writer down instead. Two sides that
+/// only write then wait for each other until the idle timer expires, like over TCP.
+pub struct ReliableStream<S> {
+    socket: S,
+    session_parameters: SessionParameters,
+    session_cipher: Option<SessionCipher>,
+    full_payload_length_in_bytes: usize,
+    timers: EndpointTimers,
+    transmitter: TransmitterStateMachine,
+    queued_frames: u64,
+    must_transmit: bool,
+    retransmission_deadline: Option<Instant>,
+    receiver: ReceiverStateMachine,
+    /// Compression announced by the peer, known once its session header is received.
+    peer_compression: Option<Compression>,
+    write_buffer: Vec<u8>,
+    read_buffer: VecDeque<Bytes>,
+}
+
+impl<S: DatagramSocket> ReliableStream<S> {
+    /// Both sides must agree on the sequence number width and on the pre-shared key, the rest of
+    /// `session_parameters` only applies to the bytes sent by this side.
+    ///
+    /// Fails with [`Error::InvalidPayloadLength`] if `full_payload_length_in_bytes` is not a
+    /// non-zero multiple of 8.
+    pub fn new(
+        socket: S,
+        full_payload_length_in_bytes: usize,
+        session_parameters: SessionParameters,
+        pre_shared_key: Option<PreSharedKey>,
+        timers: EndpointTimers,
+    ) -> Result<Self> {
+        if full_payload_length_in_bytes == 0 || !full_payload_length_in_bytes.is_multiple_of(8) {
+            return Err(Error::InvalidPayloadLength(full_payload_length_in_bytes));
+        }
+        let session_parameters = SessionParameters {
+            compression_mode: CompressionMode::PerFrame,
+            ..session_parameters
+        };
+        let session_cipher = build_session_cipher(&session_parameters, pre_shared_key.as_ref())?;
+        let sequence_number_width = session_parameters.sequence_number_width;
+        let session_header = Frame::new(
+            &session_parameters.to_bytes(),
+            SequenceNumber::zero(sequence_number_width),
+        );
+        Ok(Self {
+            socket,
+            session_parameters,
+            session_cipher,
+            full_payload_length_in_bytes,
+            timers,
+            transmitter: TransmitterStateMachine::new(VecDeque::from([session_header])),
+            queued_frames: 1,
+            must_transmit: true,
+            retransmission_deadline: None,
+            receiver: ReceiverStateMachine::new(pre_shared_key, sequence_number_width),
+            peer_compression: None,
+            write_buffer: Vec::with_capacity(full_payload_length_in_bytes),
+            read_buffer: VecDeque::new(),
+        })
+    }
+
+    /// Sends the end of the stream and returns once both sides have closed, keeping on
+    /// answering retransmissions until the peer has been quiet for `timers.linger`. Bytes
+    /// received and not read yet are dropped.
+    pub fn close(mut self) -> Result<()> {
+        self.set_receive_buffer_limit(None)?;
+        self.queue_buffered_bytes();
+        self.queue_frame(&[]);
+        self.drive_until(|stream| {
+            stream.transmitter.is_finished() && stream.receiver.is_finished()
+        })?;
+
+        let mut buffer = vec![0; MAX_DATAGRAM_LENGTH_IN_BYTES];
+        loop {
+            match self
+                .socket
+                .recv_datagram(&mut buffer, Some(self.timers.linger))
+                .map_err(socket_error)
+            {
+                Ok(Some(length_in_bytes)) => self.handle_datagram(&buffer[..length_in_bytes])?,
+                // The peer only leaves once it has received every acknowledgement
+                Ok(None) | Err(Error::ChannelClosed) => break,
+                Err(error) => return Err(error),
+            }
+        }
+        log::info!("Stream closed");
+        Ok(())
+    }
+
+    /// Stops taking frames while the received bytes not read yet, compressed, add up to
+    /// `receive_buffer_limit_in_bytes`. `None` takes every frame.
+    pub fn set_receive_buffer_limit(
+        &mut self,
+        receive_buffer_limit_in_bytes: Option<NonZeroUsize>,
+    ) -> Result<()> {
+        let was_ready = self.receiver.is_ready();
+        self.receiver
+            .set_buffer_limit(receive_buffer_limit_in_bytes);
+        self.announce_readiness(was_ready)
+    }
+
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn queue_buffered_bytes(&mut self) {
+        if !self.write_buffer.is_empty() {
+            let bytes = std::mem::take(&mut self.write_buffer);
+            self.queue_frame(&bytes);
+        }
+    }
+
+    /// Only the end-of-stream frame has an empty payload.
+    fn queue_frame(&mut self, bytes: &[u8]) {
+        let payload = match bytes.is_empty() {
+            true => Vec::new(),
+            false => self.session_parameters.compression.compress(bytes),
+        };
+        let sequence_number = SequenceNumber::for_frame_index(
+            self.queued_frames,
+            self.session_parameters.sequence_number_width,
+        );
+        let frame = match &self.session_cipher {
+            Some(session_cipher) => {
+                session_cipher.seal(self.queued_frames, &payload, sequence_number)
+            }
+            None => Frame::new(&payload, sequence_number),
+        };
+        // An idle transmitter sends the frame right away
+        self.must_transmit |= self.transmitter.is_finished();
+        self.transmitter.push_frame(frame);
+        self.queued_frames += 1;
+    }
+
+    /// Sends frames, answers the peer and retransmits until `is_done`.
+    ///
+    /// Fails with [`Error::Timeout`] if nothing was heard from the peer for `timers.idle`, and
+    /// with [`Error::ChannelClosed`] if its socket has gone away.
+    fn drive_until(&mut self, is_done: impl Fn(&Self) -> bool) -> Result<()> {
+        let mut idle_deadline = self.timers.idle.map(|idle| Instant::now() + idle);
+        let mut buffer = vec![0; MAX_DATAGRAM_LENGTH_IN_BYTES];
+
+        while !is_done(self) {
+            if self.must_transmit
+                && let Some(frame) = self.transmitter.current_frame()
+            {
+                let (frames_transmitted, total_number_of_frames_to_transmit) =
+                    self.transmitter.progress();
+                log::debug!(
+                    "Sending frame {}/{}",
+                    frames_transmitted + 1,
+                    total_number_of_frames_to_transmit
+                );
+                self.socket
+                    .send_datagram(&frame.content)
+                    .map_err(socket_error)?;
+                self.retransmission_deadline = Some(Instant::now() + self.timers.retransmission);
+            }
+            self.must_transmit = false;
+
+            let now = Instant::now();
+            if idle_deadline.is_some_and(|deadline| now >= deadline) {
+                log::warn!("Nothing heard from the peer, giving up");
+                return Err(Error::Timeout);
+            }
+            let deadline = [
+                self.retransmission_deadline
+                    .filter(|_| !self.transmitter.is_finished()),
+                idle_deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+            let received = self
+                .socket
+                .recv_datagram(
+                    &mut buffer,
+                    deadline.map(|deadline| deadline.saturating_duration_since(now)),
+                )
+                .map_err(socket_error)?;
+            match received {
+                Some(length_in_bytes) => {
+                    idle_deadline = self.timers.idle.map(|idle| Instant::now() + idle);
+                    self.handle_datagram(&buffer[..length_in_bytes])?;
+                }
+                None => {
+                    if self
+                        .retransmission_deadline
+                        .is_some_and(|deadline| Instant::now() >= deadline)
+                        && !self.transmitter.is_finished()
+                    {
+                        log::debug!("Retransmission timer expired - Retrying same packet");
+                        self.must_transmit = true;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn sequence_number_width(&self) -> SequenceNumberWidth {
+        self.session_parameters.sequence_number_width
+    }
+
+    /// Acknowledges frames right away, their payloads waiting in the receiver for the reader.
+    fn handle_datagram(&mut self, datagram: &[u8]) -> Result<()> {
+        let sequence_number_width = self.sequence_number_width();
+        if datagram.len() == acknowledgement_length_in_bytes(sequence_number_width) {
+            let acknowledgement =
+                GenericAcknowledgement::from_bytes(datagram, sequence_number_width)?;
+            let action = self.transmitter.handle_acknowledgement(&acknowledgement);
+            self.must_transmit |= matches!(
+                action,
+                TransmitterAction::SendNextFrame | TransmitterAction::RetransmitFrame
+            );
+            return Ok(());
+        }
+
+        let frame = Frame {
+            content: Bytes::copy_from_slice(datagram),
+            sequence_number_width,
+        };
+        let (acknowledgement, outcome) = self.receiver.handle_frame(&frame)?;
+        log::debug!("Received frame - {:?}", outcome);
+        self.socket
+            .send_datagram(&acknowledgement.to_bytes())
+            .map_err(socket_error)
+    }
+
+    /// Moves the received payloads over to the read buffer once it is empty.
+    fn take_received_payloads(&mut self) -> Result<()> {
+        if !self.read_buffer.is_empty() || !self.receiver.has_payloads() {
+            return Ok(());
+        }
+        let was_ready = self.receiver.is_ready();
+        for payload in self.receiver.take_payloads() {
+            match self.peer_compression {
+                // Checked by the receiver already
+                None => {
+                    self.peer_compression =
+                        Some(SessionParameters::from_bytes(&payload)?.compression)
+                }
+                Some(_) if payload.is_empty() => {}
+                Some(compression) => self
+                    .read_buffer
+                    .push_back(compression.decompress(&payload)?.into()),
+            }
+        }
+        self.announce_readiness(was_ready)
+    }
+
+    /// Tells the peer that held its frames back that the receiver takes them again, rather than
+    /// having it wait for its retransmission timer.
+    fn announce_readiness(&mut self, was_ready: bool) -> Result<()> {
+        if was_ready || !self.receiver.is_ready() {
+            return Ok(());
+        }
+        log::debug!("Receiver is ready again");
+        self.socket
+            .send_datagram(&self.receiver.acknowledgement().to_bytes())
+            .map_err(socket_error)
+    }
+}
+
+impl<S: DatagramSocket> Read for ReliableStream<S> {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if buffer.is_empty() {
+            return Ok(0);
+        }
+        self.queue_buffered_bytes();
+        loop {
+            self.take_received_payloads()?;
+            if !self.read_buffer.is_empty() || self.receiver.is_finished() {
+                break;
+            }
+            self.drive_until(|stream| {
+                stream.receiver.has_payloads() || stream.receiver.is_finished()
+            })?;
+        }
+        let Some(payload) = self.read_buffer.front_mut() else {
+            return Ok(0);
+        };
+        let length_in_bytes = payload.len().min(buffer.len());
+        buffer[..length_in_bytes].copy_from_slice(&payload[..length_in_bytes]);
+        payload.advance(length_in_bytes);
+        if payload.is_empty() {
+            self.read_buffer.pop_front();
+        }
+        Ok(length_in_bytes)
+    }
+}
+
+impl<S: DatagramSocket> Write for ReliableStream<S> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let length_in_bytes = bytes
+            .len()
+            .min(self.full_payload_length_in_bytes - self.write_buffer.len());
+        self.write_buffer
+            .extend_from_slice(&bytes[..length_in_bytes]);
+        if self.write_buffer.len() == self.full_payload_length_in_bytes {
+            self.flush()?;
+        }
+        Ok(length_in_bytes)
+    }
+
+    /// Returns once every written byte has been acknowledged.
+    fn flush(&mut self) -> io::Result<()> {
+        self.queue_buffered_bytes();
+        Ok(self.drive_until(|stream| stream.transmitter.is_finished())?)
+    }
+}
+
+#[test]
+fn bytes_get_through_a_lossy_socket_in_both_directions() {
+    use std::thread;
+
+    /// Drops every third datagram it is asked to send.
+    struct LossySocket {
+        socket: UdpSocket,
+        sent_datagrams: usize,
+    }
+
+    impl DatagramSocket for LossySocket {
+        fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+            self.sent_datagrams += 1;
+            match self.sent_datagrams % 3 {
+                0 => Ok(()),
+                _ => self.socket.send_datagram(datagram),
+            }
+        }
+
+        fn recv_datagram(
+            &mut self,
+            buffer: &mut [u8],
+            timeout: Option<Duration>,
+        ) -> io::Result<Option<usize>> {
+            self.socket.recv_datagram(buffer, timeout)
+        }
+    }
+
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(20),
+        linger: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(5)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters {
+        compression: Compression::Lz4,
+        sequence_number_width: SequenceNumberWidth::Bits16,
+        ..SessionParameters::default()
+    };
+    let request: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+    socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+    let mut stream_a = ReliableStream::new(
+        LossySocket {
+            socket: socket_a,
+            sent_datagrams: 0,
+        },
+        512,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+    let mut stream_b = ReliableStream::new(
+        LossySocket {
+            socket: socket_b,
+            sent_datagrams: 0,
+        },
+        512,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+
+    // B answers with the request reversed, then waits for A to close
+    let request_length_in_bytes = request.len();
+    let peer = thread::spawn(move || {
+        let mut received_request = vec![0; request_length_in_bytes];
+        stream_b.read_exact(&mut received_request).unwrap();
+        received_request.reverse();
+        stream_b.write_all(&received_request).unwrap();
+        stream_b.flush().unwrap();
+        let mut rest = Vec::new();
+        stream_b.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        stream_b.close().unwrap();
+    });
+
+    for chunk in request.chunks(700) {
+        stream_a.write_all(chunk).unwrap();
+    }
+    let mut response = vec![0; request.len()];
+    stream_a.read_exact(&mut response).unwrap();
+    response.reverse();
+    assert_eq!(response, request);
+    stream_a.close().unwrap();
+    peer.join().unwrap();
+}
+
+#[test]
+fn a_busy_receiver_holds_the_writer_back() {
+    use std::thread;
+
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(20),
+        linger: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(5)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters::default();
+    let socket_a = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let socket_b = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket_a.connect(socket_b.local_addr().unwrap()).unwrap();
+    socket_b.connect(socket_a.local_addr().unwrap()).unwrap();
+    let mut stream_a =
+        ReliableStream::new(socket_a, 512, session_parameters, None, timers).unwrap();
+    let mut stream_b =
+        ReliableStream::new(socket_b, 512, session_parameters, None, timers).unwrap();
+    stream_b
+        .set_receive_buffer_limit(NonZeroUsize::new(1024))
+        .unwrap();
+
+    // A sends its request while B is still busy sending a long greeting
+    let request: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let greeting: Vec<u8> = (0..20_000u32).map(|i| (i % 13) as u8).collect();
+    let expected_greeting = greeting.clone();
+    let expected_request = request.clone();
+    let peer = thread::spawn(move || {
+        stream_a.write_all(&request).unwrap();
+        stream_a.flush().unwrap();
+        let mut received_greeting = vec![0; expected_greeting.len()];
+        stream_a.read_exact(&mut received_greeting).unwrap();
+        assert_eq!(received_greeting, expected_greeting);
+        stream_a.close().unwrap();
+    });
+
+    stream_b.write_all(&greeting).unwrap();
+    stream_b.flush().unwrap();
+    // The frame that reached the limit is the last one taken
+    let buffered_length_in_bytes = stream_b.receiver.buffered_length_in_bytes();
+    assert!(buffered_length_in_bytes < 1024 + 512 + 64);
+    assert!(!stream_b.receiver.is_ready());
+
+    let mut received_request = vec![0; expected_request.len()];
+    stream_b.read_exact(&mut received_request).unwrap();
+    assert_eq!(received_request, expected_request);
+    stream_b.close().unwrap();
+    peer.join().unwrap();
+}
@@ -0,0 +1,580 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    packets::{
+        GenericPacket,
+        acknowledgement::{GenericAcknowledgement, acknowledgement_length_in_bytes},
+        frame::Frame,
+        hdlc::{Control, HdlcCodec, HdlcFrame, Modulus, UnnumberedFunction},
+    },
+    reliable_stream::{DatagramSocket, MAX_DATAGRAM_LENGTH_IN_BYTES},
+};
+
+// Delimits packets on byte streams such as UARTs and pseudo-terminals with the asynchronous
+// HDLC framing of RFC 1662: every packet sits between flag bytes, and flag or escape bytes
+// within it are sent as the escape byte followed by the byte XOR 0x20. A flag can therefore
+// only ever mean a packet boundary, so the receiver is back in step at the next flag whatever
+// the line did to the bytes before it. Corrupted packets are left to the checksum of the frame.
+
+pub const FLAG: u8 = 0x7E;
+pub const ESCAPE: u8 = 0x7D;
+const ESCAPE_MASK: u8 = 0x20;
+
+/// Appends `packet` to `line` between flags, escaping the flag and escape bytes it contains.
+pub fn stuff_packet(packet: &[u8], line: &mut Vec<u8>) {
+    line.reserve(packet.len() + 2);
+    line.push(FLAG);
+    for &byte in packet {
+        match byte {
+            FLAG | ESCAPE => line.extend_from_slice(&[ESCAPE, byte ^ ESCAPE_MASK]),
+            _ => line.push(byte),
+        }
+    }
+    line.push(FLAG);
+}
+
+/// Recovers the packets of [`stuff_packet`] from the bytes read off the line.
+#[derive(Debug, Clone)]
+pub struct Destuffer {
+    packet: Vec<u8>,
+    max_packet_length_in_bytes: usize,
+    is_escaped: bool,
+    /// Set once the packet cannot be kept, until the next flag.
+    is_discarding: bool,
+    discarded_packets: usize,
+}
+
+impl Destuffer {
+    pub fn new(max_packet_length_in_bytes: usize) -> Self {
+        Self {
+            packet: Vec::new(),
+            max_packet_length_in_bytes,
+            is_escaped: false,
+            is_discarding: false,
+            discarded_packets: 0,
+        }
+    }
+
+    /// Packets dropped for being too long or aborted by an escaped flag.
+    pub fn discarded_packets(&self) -> usize {
+        self.discarded_packets
+    }
+
+    /// Feeds the next byte of the line and returns the packet that it closes, if any.
+    ///
+    /// Consecutive flags delimit nothing, so a flag that was lost or added only merges or
+    /// splits packets, which then fail their checksum.
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        if byte == FLAG {
+            // An escape right before a flag aborts the packet, as in HDLC
+            let is_aborted = self.is_escaped || self.is_discarding;
+            self.is_escaped = false;
+            self.is_discarding = false;
+            if is_aborted {
+                self.discard();
+                return None;
+            }
+            return match self.packet.is_empty() {
+                true => None,
+                false => Some(std::mem::take(&mut self.packet)),
+            };
+        }
+        if self.is_discarding {
+            return None;
+        }
+        if byte == ESCAPE && !self.is_escaped {
+            self.is_escaped = true;
+            return None;
+        }
+        let byte = match std::mem::take(&mut self.is_escaped) {
+            true => byte ^ ESCAPE_MASK,
+            false => byte,
+        };
+        if self.packet.len() == self.max_packet_length_in_bytes {
+            log::debug!("Packet longer than {} B", self.max_packet_length_in_bytes);
+            self.is_discarding = true;
+            return None;
+        }
+        self.packet.push(byte);
+        None
+    }
+
+    fn discard(&mut self) {
+        log::debug!("Discarding {} B up to the flag", self.packet.len());
+        self.packet.clear();
+        self.discarded_packets += 1;
+    }
+}
+
+/// [`DatagramSocket`] over a byte stream such as a serial port, so that a
+/// [`ReliableStream`] can run over it.
+///
+/// Reads from `port` must give up after a while without bytes, returning 0 or a
+/// [`io::ErrorKind::WouldBlock`] or [`io::ErrorKind::TimedOut`] error: a terminal set up with
+/// `VMIN = 0` and `VTIME > 0` does, so does a socket with a read timeout. Receive timeouts are
+/// only checked between reads.
+///
+/// [`ReliableStream`]: crate::reliable_stream::ReliableStream
+#[derive(Debug)]
+pub struct SerialLink<P> {
+    port: P,
+    destuffer: Destuffer,
+    received_packets: VecDeque<Vec<u8>>,
+    line: Vec<u8>,
+}
+
+impl<P: Read + Write> SerialLink<P> {
+    pub fn new(port: P) -> Self {
+        Self {
+            port,
+            destuffer: Destuffer::new(MAX_DATAGRAM_LENGTH_IN_BYTES),
+            received_packets: VecDeque::new(),
+            line: Vec::new(),
+        }
+    }
+
+    pub fn discarded_packets(&self) -> usize {
+        self.destuffer.discarded_packets()
+    }
+
+    pub fn into_inner(self) -> P {
+        self.port
+    }
+}
+
+impl<P: Read + Write> DatagramSocket for SerialLink<P> {
+    fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        self.line.clear();
+        stuff_packet(datagram, &mut self.line);
+        self.port.write_all(&self.line)?;
+        self.port.flush()
+    }
+
+    fn recv_datagram(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<usize>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut bytes = [0; 4096];
+        loop {
+            if let Some(packet) = self.received_packets.pop_front() {
+                // Like a datagram socket, the end of a packet too long for the buffer is lost
+                let length_in_bytes = packet.len().min(buffer.len());
+                buffer[..length_in_bytes].copy_from_slice(&packet[..length_in_bytes]);
+                return Ok(Some(length_in_bytes));
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+            let length_in_bytes = match self.port.read(&mut bytes) {
+                Ok(length_in_bytes) => length_in_bytes,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    0
+                }
+                Err(error) => return Err(error),
+            };
+            self.received_packets.extend(
+                bytes[..length_in_bytes]
+                    .iter()
+                    .filter_map(|&byte| self.destuffer.push(byte)),
+            );
+        }
+    }
+}
+
+/// [`DatagramSocket`] carrying the datagrams of a [`ReliableStream`] as the HDLC frames of
+/// `codec`, usually over a [`SerialLink`], so that the transfer runs in the HDLC format
+/// instead of the custom one. Frames must be plaintext, see [`HdlcCodec::encode`].
+///
+/// The link is set up with SABM, or SABME in modulo 128, ahead of the first frame, and the
+/// SABM, SABME and DISC of the peer are answered with UA. Frames failing their FCS are
+/// dropped, the peer sending them again once its retransmission timer expires.
+///
+/// [`ReliableStream`]: crate::reliable_stream::ReliableStream
+#[derive(Debug)]
+pub struct HdlcLink<S> {
+    socket: S,
+    codec: HdlcCodec,
+    is_set_up: bool,
+    /// Datagrams in the custom format decoded already, an acknowledgement piggybacked on an
+    /// I frame coming after the frame.
+    received_datagrams: VecDeque<Vec<u8>>,
+    buffer: Vec<u8>,
+}
+
+impl<S: DatagramSocket> HdlcLink<S> {
+    pub fn new(socket: S, codec: HdlcCodec) -> Self {
+        Self {
+            socket,
+            codec,
+            is_set_up: false,
+            received_datagrams: VecDeque::new(),
+            buffer: vec![0; MAX_DATAGRAM_LENGTH_IN_BYTES],
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn handle_unnumbered(
+        &mut self,
+        function: UnnumberedFunction,
+        poll_final: bool,
+    ) -> io::Result<()> {
+        match function {
+            UnnumberedFunction::SetAsynchronousBalancedMode
+            | UnnumberedFunction::SetAsynchronousBalancedModeExtended
+            | UnnumberedFunction::Disconnect => {
+                log::debug!("Answering {:?} frame", function);
+                self.socket.send_datagram(
+                    &self.codec.encode_unnumbered(
+                        UnnumberedFunction::UnnumberedAcknowledgement,
+                        poll_final,
+                    ),
+                )
+            }
+            _ => {
+                log::debug!("Ignoring {:?} frame", function);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<S: DatagramSocket> DatagramSocket for HdlcLink<S> {
+    fn send_datagram(&mut self, datagram: &[u8]) -> io::Result<()> {
+        if !self.is_set_up {
+            let function = match self.codec.modulus() {
+                Modulus::Modulo8 => UnnumberedFunction::SetAsynchronousBalancedMode,
+                Modulus::Modulo128 => UnnumberedFunction::SetAsynchronousBalancedModeExtended,
+            };
+            self.socket
+                .send_datagram(&self.codec.encode_unnumbered(function, true))?;
+            self.is_set_up = true;
+        }
+        // Told apart by their length, like the stream does
+        let sequence_number_width = self.codec.sequence_number_width();
+        let packet = match datagram.len() == acknowledgement_length_in_bytes(sequence_number_width)
+        {
+            true => GenericPacket::Acknowledgement(GenericAcknowledgement::from_bytes(
+                datagram,
+                sequence_number_width,
+            )?),
+            false => GenericPacket::Frame(Frame {
+                content: Bytes::copy_from_slice(datagram),
+                sequence_number_width,
+            }),
+        };
+        let hdlc_frame_bytes = self.codec.encode(&packet)?;
+        self.socket.send_datagram(&hdlc_frame_bytes)
+    }
+
+    fn recv_datagram(
+        &mut self,
+        buffer: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<Option<usize>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(datagram) = self.received_datagrams.pop_front() {
+                let length_in_bytes = datagram.len().min(buffer.len());
+                buffer[..length_in_bytes].copy_from_slice(&datagram[..length_in_bytes]);
+                return Ok(Some(length_in_bytes));
+            }
+            let Some(length_in_bytes) = self.socket.recv_datagram(
+                &mut self.buffer,
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+            )?
+            else {
+                return Ok(None);
+            };
+            let hdlc_frame = match HdlcFrame::from_bytes(
+                &self.buffer[..length_in_bytes],
+                self.codec.modulus(),
+            ) {
+                Ok(hdlc_frame) => hdlc_frame,
+                Err(error) => {
+                    log::debug!("Dropping HDLC frame - {}", error);
+                    continue;
+                }
+            };
+            if let Control::Unnumbered {
+                function,
+                poll_final,
+            } = hdlc_frame.control
+            {
+                self.handle_unnumbered(function, poll_final)?;
+                continue;
+            }
+            match self.codec.decode_frame(&hdlc_frame) {
+                Some(GenericPacket::Frame(frame)) => {
+                    self.received_datagrams.push_back(frame.content.to_vec())
+                }
+                Some(GenericPacket::Acknowledgement(acknowledgement)) => self
+                    .received_datagrams
+                    .push_back(acknowledgement.to_bytes()),
+                Some(GenericPacket::FrameWithAcknowledgement(frame, acknowledgement)) => {
+                    self.received_datagrams.push_back(frame.content.to_vec());
+                    self.received_datagrams
+                        .push_back(acknowledgement.to_bytes());
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn corrupted_lines_resynchronize_on_the_next_flag() {
+    let packets: [&[u8]; 4] = [
+        &[1, 2, 3],
+        &[FLAG, ESCAPE, 0, FLAG],
+        &[ESCAPE ^ ESCAPE_MASK],
+        &[9],
+    ];
+    let mut line = Vec::new();
+    for packet in packets {
+        stuff_packet(packet, &mut line);
+    }
+    assert_eq!(
+        line.iter().filter(|&&byte| byte == FLAG).count(),
+        2 * packets.len()
+    );
+
+    let destuff = |line: &[u8]| {
+        let mut destuffer = Destuffer::new(8);
+        let packets: Vec<Vec<u8>> = line
+            .iter()
+            .filter_map(|&byte| destuffer.push(byte))
+            .collect();
+        (packets, destuffer.discarded_packets())
+    };
+    assert_eq!(destuff(&line), (packets.map(<[u8]>::to_vec).to_vec(), 0));
+
+    // Noise before the first flag and within a packet only damages that packet
+    let mut noisy_line = vec![0x55, ESCAPE, 0xAA];
+    noisy_line.extend_from_slice(&line);
+    noisy_line[3 + 2] ^= 0x01;
+    let (noisy_packets, _) = destuff(&noisy_line);
+    assert_eq!(noisy_packets[0], [0x55, 0xAA ^ ESCAPE_MASK]);
+    assert_eq!(noisy_packets[1], [1, 3, 3]);
+    assert_eq!(
+        noisy_packets[2..],
+        packets[1..]
+            .iter()
+            .map(|packet| packet.to_vec())
+            .collect::<Vec<_>>()
+    );
+
+    // An escaped flag aborts the packet, an overlong one is dropped up to the next flag
+    let (packets_after_abort, discarded_packets) = destuff(&[
+        FLAG, 1, ESCAPE, FLAG, 2, FLAG, 0, 1, 2, 3, 4, 5, 6, 7, 8, FLAG, 3, FLAG,
+    ]);
+    assert_eq!(packets_after_abort, [vec![2], vec![3]]);
+    assert_eq!(discarded_packets, 2);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn streams_get_through_a_corrupted_pseudo_terminal_pair() {
+    use std::{fs::File, thread};
+
+    use nix::{
+        pty::openpty,
+        sys::termios::{SetArg, SpecialCharacterIndices, cfmakeraw, tcgetattr, tcsetattr},
+    };
+
+    use crate::{
+        endpoint::EndpointTimers, packets::sequence::SequenceNumberWidth,
+        reliable_stream::ReliableStream, session::SessionParameters,
+    };
+
+    // Raw terminal whose reads give up after 100 ms without bytes, and the master side of it
+    let open_port = || {
+        let pty = openpty(None, None).unwrap();
+        let mut termios = tcgetattr(&pty.slave).unwrap();
+        cfmakeraw(&mut termios);
+        termios.control_chars[SpecialCharacterIndices::VMIN as usize] = 0;
+        termios.control_chars[SpecialCharacterIndices::VTIME as usize] = 1;
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).unwrap();
+        (File::from(pty.slave), File::from(pty.master))
+    };
+    let (port_a, master_a) = open_port();
+    let (port_b, master_b) = open_port();
+
+    // Null-modem cable between the two masters, flipping a bit of every 500th byte. It stops
+    // once the terminals are closed and reading their masters fails.
+    let cable = |mut from: File, mut to: File| {
+        thread::spawn(move || {
+            let mut bytes = [0; 4096];
+            let mut forwarded_bytes = 0;
+            while let Ok(length_in_bytes) = from.read(&mut bytes)
+                && length_in_bytes > 0
+            {
+                for byte in &mut bytes[..length_in_bytes] {
+                    forwarded_bytes += 1;
+                    if forwarded_bytes % 500 == 0 {
+                        *byte ^= 0x10;
+                    }
+                }
+                if to.write_all(&bytes[..length_in_bytes]).is_err() {
+                    break;
+                }
+            }
+        })
+    };
+    cable(master_a.try_clone().unwrap(), master_b.try_clone().unwrap());
+    cable(master_b, master_a);
+
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(300),
+        linger: Duration::from_millis(300),
+        idle: Some(Duration::from_secs(10)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters {
+        sequence_number_width: SequenceNumberWidth::Bits8,
+        ..SessionParameters::default()
+    };
+    // Plenty of flag and escape bytes to stuff
+    let message: Vec<u8> = (0..6_000u32).map(|i| (i % 7) as u8 + 0x7A).collect();
+    let mut stream_a = ReliableStream::new(
+        SerialLink::new(port_a),
+        256,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+    let mut stream_b = ReliableStream::new(
+        SerialLink::new(port_b),
+        256,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+
+    let expected_message = message.clone();
+    let peer = thread::spawn(move || {
+        let mut received_message = Vec::new();
+        stream_b.read_to_end(&mut received_message).unwrap();
+        assert_eq!(received_message, expected_message);
+        stream_b.close().unwrap();
+    });
+    stream_a.write_all(&message).unwrap();
+    stream_a.close().unwrap();
+    peer.join().unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn streams_get_through_a_corrupted_line_in_hdlc_format() {
+    use std::{os::unix::net::UnixStream, thread};
+
+    use crate::{
+        endpoint::EndpointTimers,
+        packets::{hdlc::Modulus, sequence::SequenceNumberWidth},
+        reliable_stream::ReliableStream,
+        session::SessionParameters,
+    };
+
+    /// Flips a bit of every 700th byte written.
+    struct NoisyPort {
+        stream: UnixStream,
+        written_bytes: usize,
+    }
+
+    impl Read for NoisyPort {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            self.stream.read(buffer)
+        }
+    }
+
+    impl Write for NoisyPort {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            let mut bytes = buffer.to_vec();
+            for byte in &mut bytes {
+                self.written_bytes += 1;
+                if self.written_bytes.is_multiple_of(700) {
+                    *byte ^= 0x04;
+                }
+            }
+            self.stream.write_all(&bytes)?;
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.stream.flush()
+        }
+    }
+
+    let (stream_a, stream_b) = UnixStream::pair().unwrap();
+    let hdlc_link = |stream: UnixStream, address: u8, is_selective: bool| {
+        stream
+            .set_read_timeout(Some(Duration::from_millis(20)))
+            .unwrap();
+        let mut codec = HdlcCodec::new(address, Modulus::Modulo8, SequenceNumberWidth::Bits8);
+        codec.set_selective_reject(is_selective);
+        HdlcLink::new(
+            SerialLink::new(NoisyPort {
+                stream,
+                written_bytes: 0,
+            }),
+            codec,
+        )
+    };
+    let timers = EndpointTimers {
+        retransmission: Duration::from_millis(50),
+        linger: Duration::from_millis(100),
+        idle: Some(Duration::from_secs(10)),
+        ..EndpointTimers::default()
+    };
+    let session_parameters = SessionParameters {
+        sequence_number_width: SequenceNumberWidth::Bits8,
+        ..SessionParameters::default()
+    };
+    let message: Vec<u8> = (0..8_000u32).map(|i| (i % 7) as u8 + 0x7A).collect();
+    let mut stream_a = ReliableStream::new(
+        hdlc_link(stream_a, 0x03, false),
+        256,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+    let mut stream_b = ReliableStream::new(
+        hdlc_link(stream_b, 0x01, true),
+        256,
+        session_parameters,
+        None,
+        timers,
+    )
+    .unwrap();
+
+    let expected_message = message.clone();
+    let peer = thread::spawn(move || {
+        let mut received_message = Vec::new();
+        stream_b.read_to_end(&mut received_message).unwrap();
+        assert_eq!(received_message, expected_message);
+        stream_b.close().unwrap();
+    });
+    stream_a.write_all(&message).unwrap();
+    stream_a.close().unwrap();
+    peer.join().unwrap();
+}
@@ -0,0 +1,116 @@
+use crate::{
+    compression::{Compression, CompressionMode},
+    encryption::{Encryption, SALT_LENGTH_IN_BYTES},
+    error::{DecodeError, Result},
+    packets::sequence::SequenceNumberWidth,
+};
+
+const SESSION_MAGIC: [u8; 2] = *b"SW";
+const SESSION_VERSION: u8 = 1;
+
+/// Parameters announced by the sender in the first frame of every transfer.
+///
+/// The receiver only accepts the session if it understands every field, so both sides
+/// always agree on how the following payloads have to be decoded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SessionParameters {
+    pub compression: Compression,
+    pub compression_mode: CompressionMode,
+    pub encryption: Encryption,
+    pub sequence_number_width: SequenceNumberWidth,
+}
+
+impl Default for SessionParameters {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            compression_mode: CompressionMode::PerFrame,
+            encryption: Encryption::None,
+            sequence_number_width: SequenceNumberWidth::AlternatingBit,
+        }
+    }
+}
+
+impl SessionParameters {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            SESSION_MAGIC[0],
+            SESSION_MAGIC[1],
+            SESSION_VERSION,
+            self.compression.id(),
+            self.compression_mode.id(),
+            self.sequence_number_width.id(),
+        ];
+        match self.encryption {
+            Encryption::None => bytes.push(0),
+            Encryption::XChaCha20Poly1305 { salt } => {
+                bytes.push(1);
+                bytes.extend_from_slice(&salt);
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::decode(bytes).ok_or(DecodeError::InvalidSessionHeader.into())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (header, encryption) = bytes.split_first_chunk::<7>()?;
+        let [
+            m0,
+            m1,
+            version,
+            compression,
+            compression_mode,
+            sequence_number_width,
+            encryption_id,
+        ] = *header;
+        if [m0, m1] != SESSION_MAGIC || version != SESSION_VERSION {
+            return None;
+        }
+        let encryption = match (encryption_id, encryption) {
+            (0, []) => Encryption::None,
+            (1, salt) if salt.len() == SALT_LENGTH_IN_BYTES => Encryption::XChaCha20Poly1305 {
+                salt: salt.try_into().ok()?,
+            },
+            _ => return None,
+        };
+        Some(Self {
+            compression: Compression::from_id(compression)?,
+            compression_mode: CompressionMode::from_id(compression_mode)?,
+            encryption,
+            sequence_number_width: SequenceNumberWidth::from_id(sequence_number_width)?,
+        })
+    }
+}
+
+#[test]
+fn session_parameters_round_trip() {
+    let parameters = SessionParameters {
+        compression: Compression::Lz4,
+        compression_mode: CompressionMode::PerStream,
+        encryption: Encryption::random_xchacha20poly1305(),
+        sequence_number_width: SequenceNumberWidth::Bits16,
+    };
+    assert_eq!(
+        SessionParameters::from_bytes(&parameters.to_bytes()).ok(),
+        Some(parameters)
+    );
+    assert_eq!(
+        SessionParameters::from_bytes(&SessionParameters::default().to_bytes()).ok(),
+        Some(SessionParameters::default())
+    );
+    assert_eq!(
+        SessionParameters::from_bytes(&[b'S', b'W', 1, 9, 0, 1, 0]).ok(),
+        None
+    );
+    assert_eq!(
+        SessionParameters::from_bytes(&[b'S', b'W', 1, 0, 0, 1, 1]).ok(),
+        None
+    );
+    assert_eq!(
+        SessionParameters::from_bytes(&[b'S', b'W', 1, 0, 0, 7, 0]).ok(),
+        None
+    );
+}
@@ -0,0 +1,530 @@
+use std::{
+    io::{self, BufRead, Write},
+    sync::mpsc,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    endpoint::{FrameOutcome, TransmitterAction},
+    error::Result,
+    packets::{
+        GenericPacket, acknowledgement::GenericAcknowledgement, frame::Frame,
+        sequence::SequenceNumberWidth,
+    },
+};
+
+/// `LINKTYPE_USER0`, decoded by `tools/wireshark/stopandwait.lua`.
+pub const PCAP_LINK_TYPE: u32 = 147;
+const PCAP_RECORD_VERSION: u8 = 1;
+const PCAP_SNAPSHOT_LENGTH: u32 = 1 << 20;
+
+/// Part of the transfer that emitted an event.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceSource {
+    Transmitter,
+    Channel,
+    Receiver,
+}
+
+impl TraceSource {
+    pub const fn id(&self) -> u8 {
+        *self as u8 + 1
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceDirection {
+    Outgoing,
+    InTransit,
+    Incoming,
+    /// Timer events, which do not move a packet.
+    Local,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketType {
+    Frame,
+    Ack,
+    Nack,
+    FrameWithAck,
+    FrameWithNack,
+}
+
+impl PacketType {
+    pub const fn id(&self) -> u8 {
+        match self {
+            PacketType::Frame => 1,
+            PacketType::Ack => 2,
+            PacketType::Nack => 3,
+            PacketType::FrameWithAck => 4,
+            PacketType::FrameWithNack => 5,
+        }
+    }
+
+    pub(crate) fn of(
+        frame: Option<&Frame>,
+        acknowledgement: Option<&GenericAcknowledgement>,
+    ) -> Option<Self> {
+        match (frame, acknowledgement) {
+            (Some(_), None) => Some(PacketType::Frame),
+            (None, Some(GenericAcknowledgement::ACK(_))) => Some(PacketType::Ack),
+            (None, Some(GenericAcknowledgement::NACK(_))) => Some(PacketType::Nack),
+            (Some(_), Some(GenericAcknowledgement::ACK(_))) => Some(PacketType::FrameWithAck),
+            (Some(_), Some(GenericAcknowledgement::NACK(_))) => Some(PacketType::FrameWithNack),
+            (None, None) => None,
+        }
+    }
+}
+
+/// What happened to a packet, each action belonging to a single [`TraceSource`].
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum TraceAction {
+    SendFrame,
+    RetransmitFrame,
+    RetransmissionTimeout,
+    /// The acknowledgement releases the next frame.
+    AcknowledgementAdvance,
+    AcknowledgementRetransmit,
+    AcknowledgementStale,
+    AcknowledgementFinish,
+    Deliver,
+    Corrupt,
+    AcceptFrame,
+    DuplicateFrame,
+    OutOfOrderFrame,
+    RejectFrame,
+    /// Acknowledgement sent on its own because no frame was available to carry it.
+    SendAcknowledgement,
+    /// Extra copy of a packet that the channel already delivered.
+    Duplicate,
+    /// Packet delivered after packets that were sent later.
+    Reorder,
+    /// Packet that never left the channel.
+    Lose,
+    /// The acknowledgement releases the current frame but holds the next one back.
+    AcknowledgementPause,
+    /// Intact frame that the receiver has no room for.
+    RefuseFrame,
+    /// The acknowledgement tells that the receiver has no room for the current frame, which
+    /// stays unacknowledged.
+    AcknowledgementRefused,
+}
+
+impl TraceAction {
+    pub const fn id(&self) -> u8 {
+        *self as u8 + 1
+    }
+
+    pub const fn source(&self) -> TraceSource {
+        match self {
+            TraceAction::SendFrame
+            | TraceAction::RetransmitFrame
+            | TraceAction::RetransmissionTimeout
+            | TraceAction::AcknowledgementAdvance
+            | TraceAction::AcknowledgementRetransmit
+            | TraceAction::AcknowledgementStale
+            | TraceAction::AcknowledgementFinish
+            | TraceAction::AcknowledgementPause
+            | TraceAction::AcknowledgementRefused => TraceSource::Transmitter,
+            TraceAction::Deliver
+            | TraceAction::Corrupt
+            | TraceAction::Duplicate
+            | TraceAction::Reorder
+            | TraceAction::Lose => TraceSource::Channel,
+            TraceAction::AcceptFrame
+            | TraceAction::DuplicateFrame
+            | TraceAction::OutOfOrderFrame
+            | TraceAction::RejectFrame
+            | TraceAction::RefuseFrame
+            | TraceAction::SendAcknowledgement => TraceSource::Receiver,
+        }
+    }
+
+    pub const fn direction(&self) -> TraceDirection {
+        match self {
+            TraceAction::SendFrame
+            | TraceAction::RetransmitFrame
+            | TraceAction::SendAcknowledgement => TraceDirection::Outgoing,
+            TraceAction::Deliver
+            | TraceAction::Corrupt
+            | TraceAction::Duplicate
+            | TraceAction::Reorder
+            | TraceAction::Lose => TraceDirection::InTransit,
+            TraceAction::RetransmissionTimeout => TraceDirection::Local,
+            _ => TraceDirection::Incoming,
+        }
+    }
+}
+
+impl From<TransmitterAction> for TraceAction {
+    fn from(action: TransmitterAction) -> Self {
+        match action {
+            TransmitterAction::SendNextFrame => TraceAction::AcknowledgementAdvance,
+            TransmitterAction::RetransmitFrame => TraceAction::AcknowledgementRetransmit,
+            TransmitterAction::WaitForAcknowledgement => TraceAction::AcknowledgementStale,
+            TransmitterAction::HoldNextFrame => TraceAction::AcknowledgementPause,
+            TransmitterAction::WaitForReceiver => TraceAction::AcknowledgementRefused,
+            TransmitterAction::Finished => TraceAction::AcknowledgementFinish,
+        }
+    }
+}
+
+impl From<FrameOutcome> for TraceAction {
+    fn from(outcome: FrameOutcome) -> Self {
+        match outcome {
+            FrameOutcome::Accepted => TraceAction::AcceptFrame,
+            FrameOutcome::Duplicate => TraceAction::DuplicateFrame,
+            FrameOutcome::OutOfOrder => TraceAction::OutOfOrderFrame,
+            FrameOutcome::Rejected => TraceAction::RejectFrame,
+            FrameOutcome::Refused => TraceAction::RefuseFrame,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TraceEvent {
+    /// Microseconds since the Unix epoch.
+    pub timestamp_us: u64,
+    /// Endpoint name, or the direction of the channel such as `A->B`.
+    pub node: String,
+    pub source: TraceSource,
+    pub direction: TraceDirection,
+    pub action: TraceAction,
+    pub packet_type: Option<PacketType>,
+    pub sequence_number: Option<u32>,
+    pub acknowledgement_number: Option<u32>,
+    pub length_in_bytes: usize,
+    pub corrupted_bits: u32,
+    pub is_valid: bool,
+    // Only needed to export the bytes on the line to pcap
+    #[serde(skip)]
+    pub sequence_number_width_id: u8,
+    #[serde(skip)]
+    pub frame_bytes: Vec<u8>,
+    #[serde(skip)]
+    pub acknowledgement_bytes: Vec<u8>,
+}
+
+impl TraceEvent {
+    /// Length of the payload of the frame, without its trailer nor any acknowledgement riding
+    /// on it, 0 if the event carries no frame.
+    pub fn payload_length_in_bytes(&self) -> usize {
+        SequenceNumberWidth::from_id(self.sequence_number_width_id).map_or(0, |width| {
+            self.frame_bytes
+                .len()
+                .saturating_sub(Frame::overhead_in_bytes(width))
+        })
+    }
+
+    /// Pseudo header followed by the node name and the bytes on the line, all big endian:
+    ///
+    /// ```text
+    /// version u8 | source u8 | action u8 | packet type u8 | width id u8 | flags u8 |
+    /// corrupted bits u32 | sequence number u32 | acknowledgement number u32 |
+    /// frame length u32 | node length u8 | node | frame | acknowledgement
+    /// ```
+    ///
+    /// Flags: bit 0 valid, bit 1 sequence number present, bit 2 acknowledgement number present.
+    fn to_pcap_record(&self) -> Vec<u8> {
+        let node = &self.node.as_bytes()[..self.node.len().min(u8::MAX as usize)];
+        let mut record = Vec::with_capacity(
+            23 + node.len() + self.frame_bytes.len() + self.acknowledgement_bytes.len(),
+        );
+        record.push(PCAP_RECORD_VERSION);
+        record.push(self.source.id());
+        record.push(self.action.id());
+        record.push(self.packet_type.map_or(0, |packet_type| packet_type.id()));
+        record.push(self.sequence_number_width_id);
+        record.push(
+            self.is_valid as u8
+                | (self.sequence_number.is_some() as u8) << 1
+                | (self.acknowledgement_number.is_some() as u8) << 2,
+        );
+        record.extend_from_slice(&self.corrupted_bits.to_be_bytes());
+        record.extend_from_slice(&self.sequence_number.unwrap_or_default().to_be_bytes());
+        record.extend_from_slice(
+            &self
+                .acknowledgement_number
+                .unwrap_or_default()
+                .to_be_bytes(),
+        );
+        record.extend_from_slice(&(self.frame_bytes.len() as u32).to_be_bytes());
+        record.push(node.len() as u8);
+        record.extend_from_slice(node);
+        record.extend_from_slice(&self.frame_bytes);
+        record.extend_from_slice(&self.acknowledgement_bytes);
+        record
+    }
+}
+
+/// Collects [`TraceEvent`]s from every thread of a transfer. Clones share the same trace,
+/// a disabled tracer records nothing.
+#[derive(Clone)]
+pub struct Tracer {
+    start_time: SystemTime,
+    start_instant: Instant,
+    events: Option<mpsc::Sender<TraceEvent>>,
+}
+
+impl Tracer {
+    /// The receiver yields the events until every clone of the tracer has been dropped.
+    pub fn new() -> (Self, mpsc::Receiver<TraceEvent>) {
+        let (events, receiver) = mpsc::channel();
+        (
+            Self {
+                start_time: SystemTime::now(),
+                start_instant: Instant::now(),
+                events: Some(events),
+            },
+            receiver,
+        )
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            start_time: SystemTime::now(),
+            start_instant: Instant::now(),
+            events: None,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.events.is_some()
+    }
+
+    pub fn record(
+        &self,
+        node: &str,
+        action: TraceAction,
+        frame: Option<&Frame>,
+        acknowledgement: Option<&GenericAcknowledgement>,
+        is_valid: bool,
+    ) {
+        self.send(node, action, frame, acknowledgement, 0, is_valid);
+    }
+
+    /// Records `received` going through the channel, counting the bits flipped on the way.
+    pub fn record_transit(
+        &self,
+        node: &str,
+        transmitted: &GenericPacket,
+        received: &GenericPacket,
+    ) {
+        self.record_channel_event(node, None, transmitted, received);
+    }
+
+    /// Records `received` leaving the channel as a [`TraceAction::Duplicate`] or a
+    /// [`TraceAction::Reorder`], still counting the bits flipped on the way.
+    pub fn record_impairment(
+        &self,
+        node: &str,
+        action: TraceAction,
+        transmitted: &GenericPacket,
+        received: &GenericPacket,
+    ) {
+        self.record_channel_event(node, Some(action), transmitted, received);
+    }
+
+    /// Records `transmitted` as a [`TraceAction::Lose`].
+    pub fn record_loss(&self, node: &str, transmitted: &GenericPacket) {
+        self.record_channel_event(node, Some(TraceAction::Lose), transmitted, transmitted);
+    }
+
+    fn record_channel_event(
+        &self,
+        node: &str,
+        impairment: Option<TraceAction>,
+        transmitted: &GenericPacket,
+        received: &GenericPacket,
+    ) {
+        if !self.is_enabled() {
+            return;
+        }
+        let corrupted_bits: u32 = transmitted
+            .to_bytes()
+            .iter()
+            .zip(received.to_bytes())
+            .map(|(transmitted_byte, received_byte)| {
+                (transmitted_byte ^ received_byte).count_ones()
+            })
+            .sum();
+        let action = match (impairment, corrupted_bits) {
+            (Some(impairment), _) => impairment,
+            (None, 0) => TraceAction::Deliver,
+            (None, _) => TraceAction::Corrupt,
+        };
+        let (frame, acknowledgement) = packet_parts(received);
+        self.send(
+            node,
+            action,
+            frame,
+            acknowledgement,
+            corrupted_bits,
+            corrupted_bits == 0,
+        );
+    }
+
+    fn send(
+        &self,
+        node: &str,
+        action: TraceAction,
+        frame: Option<&Frame>,
+        acknowledgement: Option<&GenericAcknowledgement>,
+        corrupted_bits: u32,
+        is_valid: bool,
+    ) {
+        let Some(events) = &self.events else {
+            return;
+        };
+        let timestamp = self.start_time + self.start_instant.elapsed();
+        let frame_bytes = frame
+            .map(|frame| frame.content.to_vec())
+            .unwrap_or_default();
+        let acknowledgement_bytes = acknowledgement
+            .map(GenericAcknowledgement::to_bytes)
+            .unwrap_or_default();
+        // The tracer may outlive the collecting side, in which case the events are not needed
+        let _ = events.send(TraceEvent {
+            timestamp_us: timestamp
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_micros() as u64,
+            node: node.to_owned(),
+            source: action.source(),
+            direction: action.direction(),
+            action,
+            packet_type: PacketType::of(frame, acknowledgement),
+            sequence_number: frame
+                .and_then(|frame| frame.sequence_number().ok())
+                .map(|sequence_number| sequence_number.value()),
+            acknowledgement_number: acknowledgement
+                .map(|acknowledgement| acknowledgement.sequence_number().value()),
+            length_in_bytes: frame_bytes.len() + acknowledgement_bytes.len(),
+            corrupted_bits,
+            is_valid,
+            sequence_number_width_id: frame.map_or(0, |frame| frame.sequence_number_width.id()),
+            frame_bytes,
+            acknowledgement_bytes,
+        });
+    }
+}
+
+fn packet_parts(packet: &GenericPacket) -> (Option<&Frame>, Option<&GenericAcknowledgement>) {
+    match packet {
+        GenericPacket::Frame(frame) => (Some(frame), None),
+        GenericPacket::Acknowledgement(acknowledgement) => (None, Some(acknowledgement)),
+        GenericPacket::FrameWithAcknowledgement(frame, acknowledgement) => {
+            (Some(frame), Some(acknowledgement))
+        }
+    }
+}
+
+/// Writes one JSON object per line.
+pub fn write_json_lines(events: &[TraceEvent], mut writer: impl Write) -> Result<()> {
+    for event in events {
+        serde_json::to_writer(&mut writer, event).map_err(io::Error::from)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(writer.flush()?)
+}
+
+/// Reads events written by [`write_json_lines`], skipping blank lines.
+pub fn read_json_lines(reader: impl BufRead) -> Result<Vec<TraceEvent>> {
+    let mut events = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        events.push(serde_json::from_str(&line).map_err(io::Error::from)?);
+    }
+    Ok(events)
+}
+
+/// Writes a classic little-endian pcap file with microsecond timestamps and
+/// [`PCAP_LINK_TYPE`].
+pub fn write_pcap(events: &[TraceEvent], mut writer: impl Write) -> Result<()> {
+    writer.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+    writer.write_all(&2u16.to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&0i32.to_le_bytes())?;
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&PCAP_SNAPSHOT_LENGTH.to_le_bytes())?;
+    writer.write_all(&PCAP_LINK_TYPE.to_le_bytes())?;
+    for event in events {
+        let record = event.to_pcap_record();
+        let captured_length = record.len().min(PCAP_SNAPSHOT_LENGTH as usize);
+        writer.write_all(&((event.timestamp_us / 1_000_000) as u32).to_le_bytes())?;
+        writer.write_all(&((event.timestamp_us % 1_000_000) as u32).to_le_bytes())?;
+        writer.write_all(&(captured_length as u32).to_le_bytes())?;
+        writer.write_all(&(record.len() as u32).to_le_bytes())?;
+        writer.write_all(&record[..captured_length])?;
+    }
+    Ok(writer.flush()?)
+}
+
+#[test]
+fn events_are_exported_as_json_lines_and_pcap() {
+    use crate::packets::{
+        Packet,
+        acknowledgement::ack::ACK,
+        sequence::{SequenceNumber, SequenceNumberWidth},
+    };
+
+    let (tracer, events) = Tracer::new();
+    let frame = Frame::new(
+        b"payload",
+        SequenceNumber::new(5, SequenceNumberWidth::Bits16),
+    );
+    let acknowledgement = GenericAcknowledgement::ACK(ACK::new(SequenceNumber::new(
+        2,
+        SequenceNumberWidth::Bits16,
+    )));
+    let transmitted = GenericPacket::FrameWithAcknowledgement(frame.clone(), acknowledgement);
+    let corrupted_frame = frame.with_flipped_bits(&[0, 2]);
+    let received = GenericPacket::FrameWithAcknowledgement(corrupted_frame, acknowledgement);
+
+    tracer.record(
+        "A",
+        TraceAction::SendFrame,
+        Some(&frame),
+        Some(&acknowledgement),
+        true,
+    );
+    tracer.record_transit("A->B", &transmitted, &received);
+    Tracer::disabled().record("B", TraceAction::AcceptFrame, Some(&frame), None, true);
+    drop(tracer);
+    let events: Vec<TraceEvent> = events.iter().collect();
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].packet_type, Some(PacketType::FrameWithAck));
+    assert_eq!(events[0].sequence_number, Some(5));
+    assert_eq!(events[0].acknowledgement_number, Some(2));
+    assert_eq!(events[1].source, TraceSource::Channel);
+    assert_eq!(events[1].action, TraceAction::Corrupt);
+    assert_eq!(events[1].corrupted_bits, 2);
+    assert!(!events[1].is_valid);
+
+    let mut json_lines = Vec::new();
+    write_json_lines(&events, &mut json_lines).unwrap();
+    let lines: Vec<&str> = std::str::from_utf8(&json_lines).unwrap().lines().collect();
+    assert_eq!(lines.len(), 2);
+    let parsed = read_json_lines(json_lines.as_slice()).unwrap();
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(parsed[1].action, TraceAction::Corrupt);
+    assert_eq!(parsed[1].timestamp_us, events[1].timestamp_us);
+
+    let mut pcap = Vec::new();
+    write_pcap(&events, &mut pcap).unwrap();
+    assert_eq!(pcap[..4], 0xa1b2_c3d4u32.to_le_bytes());
+    assert_eq!(pcap[20..24], PCAP_LINK_TYPE.to_le_bytes());
+    let first_record_length = u32::from_le_bytes(pcap[32..36].try_into().unwrap()) as usize;
+    assert_eq!(first_record_length, 23 + 1 + transmitted.to_bytes().len());
+    assert_eq!(pcap[40 + 2], TraceAction::SendFrame.id());
+}
@@ -0,0 +1,109 @@
+-- Wireshark dissector for traces written by `stopandwait::trace::write_pcap`.
+--
+-- Copy into the personal Lua plugins folder (Help > About Wireshark > Folders) and open the
+-- .pcap file, records use the LINKTYPE_USER0 link type.
+
+local stopandwait = Proto("stopandwait", "Stop-and-wait trace event")
+
+local sources = { [1] = "Transmitter", [2] = "Channel", [3] = "Receiver" }
+local actions = {
+    [1] = "Send frame",
+    [2] = "Retransmit frame",
+    [3] = "Retransmission timeout",
+    [4] = "Acknowledgement advance",
+    [5] = "Acknowledgement retransmit",
+    [6] = "Acknowledgement stale",
+    [7] = "Acknowledgement finish",
+    [8] = "Deliver",
+    [9] = "Corrupt",
+    [10] = "Accept frame",
+    [11] = "Duplicate frame",
+    [12] = "Out-of-order frame",
+    [13] = "Reject frame",
+    [14] = "Send acknowledgement",
+    [15] = "Duplicate",
+    [16] = "Reorder",
+    [17] = "Lose",
+    [18] = "Acknowledgement pause",
+    [19] = "Refuse frame",
+    [20] = "Acknowledgement refused",
+}
+local packet_types = {
+    [0] = "None",
+    [1] = "Frame",
+    [2] = "ACK",
+    [3] = "NACK",
+    [4] = "Frame + ACK",
+    [5] = "Frame + NACK",
+}
+
+local fields = {
+    version = ProtoField.uint8("stopandwait.version", "Version"),
+    source = ProtoField.uint8("stopandwait.source", "Source", base.DEC, sources),
+    action = ProtoField.uint8("stopandwait.action", "Action", base.DEC, actions),
+    packet_type = ProtoField.uint8("stopandwait.packet_type", "Packet type", base.DEC, packet_types),
+    width = ProtoField.uint8("stopandwait.sequence_number_width", "Sequence number width"),
+    valid = ProtoField.bool("stopandwait.valid", "Valid", 8, nil, 0x01),
+    has_sequence_number = ProtoField.bool("stopandwait.has_sequence_number", "Has sequence number", 8, nil, 0x02),
+    has_acknowledgement = ProtoField.bool("stopandwait.has_acknowledgement", "Has acknowledgement", 8, nil, 0x04),
+    corrupted_bits = ProtoField.uint32("stopandwait.corrupted_bits", "Corrupted bits"),
+    sequence_number = ProtoField.uint32("stopandwait.sequence_number", "Sequence number"),
+    acknowledgement_number = ProtoField.uint32("stopandwait.acknowledgement_number", "Acknowledgement number"),
+    frame_length = ProtoField.uint32("stopandwait.frame_length", "Frame length"),
+    node = ProtoField.string("stopandwait.node", "Node"),
+    frame = ProtoField.bytes("stopandwait.frame", "Frame"),
+    acknowledgement = ProtoField.bytes("stopandwait.acknowledgement", "Acknowledgement"),
+}
+stopandwait.fields = fields
+
+function stopandwait.dissector(buffer, pinfo, tree)
+    if buffer:len() < 23 then
+        return 0
+    end
+    pinfo.cols.protocol = "STOPANDWAIT"
+
+    local subtree = tree:add(stopandwait, buffer(), "Stop-and-wait trace event")
+    subtree:add(fields.version, buffer(0, 1))
+    subtree:add(fields.source, buffer(1, 1))
+    subtree:add(fields.action, buffer(2, 1))
+    subtree:add(fields.packet_type, buffer(3, 1))
+    subtree:add(fields.width, buffer(4, 1))
+    local flags = buffer(5, 1)
+    subtree:add(fields.valid, flags)
+    subtree:add(fields.has_sequence_number, flags)
+    subtree:add(fields.has_acknowledgement, flags)
+    subtree:add(fields.corrupted_bits, buffer(6, 4))
+    if bit.band(flags:uint(), 0x02) ~= 0 then
+        subtree:add(fields.sequence_number, buffer(10, 4))
+    end
+    if bit.band(flags:uint(), 0x04) ~= 0 then
+        subtree:add(fields.acknowledgement_number, buffer(14, 4))
+    end
+    subtree:add(fields.frame_length, buffer(18, 4))
+
+    local node_length = buffer(22, 1):uint()
+    local node = buffer(23, node_length):string()
+    subtree:add(fields.node, buffer(23, node_length))
+
+    local offset = 23 + node_length
+    local frame_length = buffer(18, 4):uint()
+    if frame_length > 0 then
+        subtree:add(fields.frame, buffer(offset, frame_length))
+    end
+    offset = offset + frame_length
+    if offset < buffer:len() then
+        subtree:add(fields.acknowledgement, buffer(offset))
+    end
+
+    pinfo.cols.src = node
+    pinfo.cols.info = string.format(
+        "%s %s: %s",
+        node,
+        actions[buffer(2, 1):uint()] or "Unknown",
+        packet_types[buffer(3, 1):uint()] or "Unknown"
+    )
+    return buffer:len()
+end
+
+local user0 = wtap_encaps and wtap_encaps.USER0 or wtap.USER0
+DissectorTable.get("wtap_encap"):add(user0, stopandwait)